@@ -0,0 +1,250 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
+use boringtun::{
+    noise::{Tunn, TunnResult},
+    x25519::{PublicKey, StaticSecret},
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::{net::UdpSocket, runtime::Runtime, sync::Notify};
+use wireguard::{
+    buffer::{BufferPool, MAX_PACKET_SIZE},
+    device::ChannelDevice,
+    peer::Peer,
+    transport::Transport,
+    udp::{OuterSocket, RecvBatch, SendBatch},
+    worker::{Endpoints, Job, WorkerPool},
+};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const PACKET_SIZE: usize = 1400;
+const ITERATIONS: usize = 10_000;
+/// Peers sending at once in the workers benchmark, and packets each sends
+/// per iteration.
+const PEERS: usize = 8;
+const PEER_PACKETS: usize = 64;
+
+/// A peer with an established session, sending to a loopback socket.
+fn established_peer(runtime: &Runtime) -> (Peer, UdpSocket) {
+    let secret_a = StaticSecret::from([1u8; 32]);
+    let secret_b = StaticSecret::from([2u8; 32]);
+    let public_a = PublicKey::from(&secret_a);
+    let public_b = PublicKey::from(&secret_b);
+
+    let mut tunn_a = Tunn::new(secret_a, public_b, None, None, 1, None).unwrap();
+    let mut tunn_b = Tunn::new(secret_b, public_a, None, None, 2, None).unwrap();
+
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    let mut out = vec![0u8; MAX_PACKET_SIZE];
+    let init = match tunn_a.format_handshake_initiation(&mut buf, false) {
+        TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+        other => panic!("Unexpected handshake initiation: {other:?}"),
+    };
+    let response = match tunn_b.decapsulate(None, &init, &mut out) {
+        TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+        other => panic!("Unexpected handshake response: {other:?}"),
+    };
+    match tunn_a.decapsulate(None, &response, &mut out) {
+        TunnResult::WriteToNetwork(_) => {}
+        other => panic!("Unexpected keepalive: {other:?}"),
+    }
+
+    let (send_socket, sink) = runtime.block_on(async {
+        let send_socket = OuterSocket::listen(Some("127.0.0.1".parse().unwrap()), 0, None).unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        (send_socket, sink)
+    });
+    let sink_addr: SocketAddr = sink.local_addr().unwrap();
+
+    let mut peer = Peer::new().unwrap();
+    peer.set_public_key(&general_purpose::STANDARD.encode(public_b.as_bytes()))
+        .unwrap();
+    peer.set_endpoint(&sink_addr.to_string()).unwrap();
+    peer.set_send_socket(Arc::new(send_socket)).unwrap();
+    peer.set_tunn(tunn_a).unwrap();
+    (peer, sink)
+}
+
+/// A minimal IPv4 header followed by zeroes.
+fn ip_packet() -> Vec<u8> {
+    let mut packet = vec![0u8; PACKET_SIZE];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(PACKET_SIZE as u16).to_be_bytes());
+    packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+    packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+    packet
+}
+
+/// The tun to network path as the receive loop and a worker run it: take a
+/// pooled buffer, fill it, encapsulate into the worker send batch and send.
+async fn tun_to_network(pool: &Arc<BufferPool>, peer: &Peer, packet: &[u8], batch: &mut SendBatch) {
+    let mut buf = pool.get();
+    buf.as_full_mut()[..packet.len()].copy_from_slice(packet);
+    buf.set_len(packet.len());
+    peer.handle_tun_packet(&buf, batch).await.unwrap();
+    peer.flush(batch).await.unwrap();
+}
+
+fn packet_path(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (peer, _sink) = established_peer(&runtime);
+    let pool = BufferPool::new(16);
+    let packet = ip_packet();
+    let mut batch = SendBatch::new();
+
+    let allocations = runtime.block_on(async {
+        // Warm up
+        tun_to_network(&pool, &peer, &packet, &mut batch).await;
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        for _ in 0..ITERATIONS {
+            tun_to_network(&pool, &peer, &packet, &mut batch).await;
+        }
+        ALLOCATIONS.load(Ordering::Relaxed) - before
+    });
+    println!("tun_to_network: {allocations} allocations in {ITERATIONS} packets");
+
+    let mut group = c.benchmark_group("packet_path");
+    group.throughput(Throughput::Bytes(PACKET_SIZE as u64));
+    group.bench_function("tun_to_network", |b| {
+        b.iter(|| runtime.block_on(tun_to_network(&pool, &peer, &packet, &mut batch)))
+    });
+    group.finish();
+}
+
+/// The outer socket, counting the packets it sent so a benchmark knows when
+/// the workers are done.
+struct CountingSocket {
+    socket: OuterSocket,
+    sent: AtomicUsize,
+    done: Notify,
+}
+
+impl CountingSocket {
+    fn add(&self, packets: usize) {
+        self.sent.fetch_add(packets, Ordering::Relaxed);
+        self.done.notify_one();
+    }
+
+    /// Wait until `packets` have been sent since the last wait.
+    async fn wait(&self, packets: usize) {
+        while self.sent.load(Ordering::Relaxed) < packets {
+            self.done.notified().await;
+        }
+        self.sent.fetch_sub(packets, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl Transport for CountingSocket {
+    async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()> {
+        let packets = batch.len();
+        self.socket.send_batch(batch).await?;
+        self.add(packets);
+        Ok(())
+    }
+
+    fn try_send_batch(&self, batch: &mut SendBatch) -> io::Result<bool> {
+        let packets = batch.len();
+        let sent = self.socket.try_send_batch(batch)?;
+        if sent {
+            self.add(packets);
+        }
+        Ok(sent)
+    }
+
+    async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        self.socket.recv_batch(batch).await
+    }
+}
+
+/// The tun to network path of several peers at once, dispatched to a
+/// worker pool of 1 or 4 workers the way the receive loop dispatches it.
+fn workers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("workers");
+    let packets = PEERS * PEER_PACKETS;
+    group.throughput(Throughput::Bytes((PACKET_SIZE * packets) as u64));
+    for workers in [1, 4] {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(workers)
+            .enable_all()
+            .build()
+            .unwrap();
+        let (device, _handle) = ChannelDevice::new(MAX_PACKET_SIZE);
+        let (socket, pool, _handles) = runtime.block_on(async {
+            let socket = Arc::new(CountingSocket {
+                socket: OuterSocket::listen(Some("127.0.0.1".parse().unwrap()), 0, None).unwrap(),
+                sent: AtomicUsize::new(0),
+                done: Notify::new(),
+            });
+            // Each worker keeps its send batch, so none is made while timed
+            let (pool, handles) = WorkerPool::new(
+                workers,
+                socket.clone(),
+                Arc::new(device),
+                Arc::new(Endpoints::new()),
+            )
+            .unwrap();
+            (socket, pool, handles)
+        });
+        // Peers flush full batches through the same socket as the workers
+        let (peers, _sinks): (Vec<_>, Vec<_>) = (0..PEERS)
+            .map(|index| {
+                let (mut peer, sink) = established_peer(&runtime);
+                peer.set_worker_index(index).unwrap();
+                peer.set_send_socket(socket.clone()).unwrap();
+                (Arc::new(peer), sink)
+            })
+            .unzip();
+        let buffers = BufferPool::new(packets);
+        let packet = ip_packet();
+        group.bench_function(format!("{workers}"), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    for _ in 0..PEER_PACKETS {
+                        for peer in &peers {
+                            let mut buf = buffers.get();
+                            buf.as_full_mut()[..packet.len()].copy_from_slice(&packet);
+                            buf.set_len(packet.len());
+                            pool.dispatch(Job::Tun(peer.clone(), buf)).await.unwrap();
+                        }
+                    }
+                    socket.wait(packets).await;
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, packet_path, workers);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+pub const MAX_PACKET_SIZE: usize = 2048;
+
+/// A pool of packet sized buffers, so the data path does not allocate once
+/// it has warmed up.
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        let buffers = (0..capacity)
+            .map(|_| vec![0u8; MAX_PACKET_SIZE])
+            .collect::<Vec<_>>();
+        Arc::new(Self {
+            buffers: Mutex::new(buffers),
+            capacity,
+        })
+    }
+
+    /// Take a buffer out of the pool, allocating a new one only when the
+    /// pool is exhausted.
+    pub fn get(self: &Arc<Self>) -> PacketBuffer {
+        let data = self
+            .buffers
+            .lock()
+            .ok()
+            .and_then(|mut buffers| buffers.pop())
+            .unwrap_or_else(|| vec![0u8; MAX_PACKET_SIZE]);
+        PacketBuffer {
+            data,
+            len: MAX_PACKET_SIZE,
+            pool: self.clone(),
+        }
+    }
+
+    fn put(&self, data: Vec<u8>) {
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < self.capacity {
+                buffers.push(data);
+            }
+        }
+    }
+}
+
+/// A buffer borrowed from a [`BufferPool`], returned to it on drop. It
+/// dereferences to the first `len` bytes.
+pub struct PacketBuffer {
+    data: Vec<u8>,
+    len: usize,
+    pool: Arc<BufferPool>,
+}
+
+impl PacketBuffer {
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len.min(self.data.len());
+    }
+
+    /// The whole underlying buffer, regardless of the current length.
+    pub fn as_full_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Deref for PacketBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl DerefMut for PacketBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len]
+    }
+}
+
+impl Drop for PacketBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.data));
+    }
+}
@@ -0,0 +1,569 @@
+//! A local control API: JSON-RPC 2.0 over a unix socket, one request or
+//! response per line.
+//!
+//! | Method       | Params                | Result                       |
+//! |--------------|-----------------------|------------------------------|
+//! | `version`    |                       | `{"version": API_VERSION}`   |
+//! | `status`     |                       | see [`StatusInfo`]           |
+//! | `list_peers` |                       | every peer, see [`PeerInfo`] |
+//! | `handshake`  | `{"public_key": ...}` | `null`                       |
+//! | `events`     | `{"since": n}`        | see [`EventInfo`]            |
+//! | `add_peer`   | `{"config": ...}`     | `null`                       |
+//! | `update_peer`| `{"config": ...}`     | `null`                       |
+//! | `remove_peer`| `{"public_key": ...}` | `null`                       |
+//! | `reload`     |                       | `null`                       |
+//!
+//! A `config` holds the keys of a `[Peer]` section. Anyone who can connect to
+//! the socket controls the interface, it is created readable and writable by
+//! its owner only. Other platforms can pass lines to
+//! [`Control::handle_request`].
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+#[cfg(unix)]
+use std::{
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine};
+use boringtun::x25519::PublicKey;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{
+    limiter::HandshakeStats,
+    peer::{Peer, PeerEvent},
+    quota::unix_secs,
+    runtime::Runtime,
+    stun::StunStatus,
+    udp::SendBatch,
+    utils::decode_public_key,
+    wireguard::{parse_peer, WireGuard},
+};
+
+/// Bumped whenever a method changes incompatibly.
+pub const API_VERSION: u32 = 1;
+/// Peer events kept for the `events` method.
+const RECENT_EVENTS: usize = 256;
+/// Pause after a failed accept, so a broken listener does not spin.
+#[cfg(unix)]
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerInfo {
+    pub public_key: String,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<String>,
+    /// A [`crate::peer::PeerState`], e.g. `Established`.
+    pub state: String,
+    /// Handshakes that timed out in a row.
+    pub failed_attempts: u32,
+    pub last_handshake_secs: Option<u64>,
+    pub tx_bytes: usize,
+    pub rx_bytes: usize,
+    /// Bytes used of the data limit in the current period.
+    pub data_used: u64,
+    pub data_limit: Option<u64>,
+    /// Unix time the current period ends and the usage starts over.
+    pub data_period_end: Option<u64>,
+    /// Unix time the peer expires.
+    pub expires_at: Option<u64>,
+    /// Packets the rate limits dropped.
+    pub dropped_packets: u64,
+}
+
+/// The interface and every peer.
+#[derive(Clone, Debug, Serialize)]
+pub struct StatusInfo {
+    /// Cookie replies sent instead of answering a handshake.
+    pub cookie_replies: u64,
+    /// Handshake messages dropped, see [`HandshakeStats::rejected_handshakes`].
+    pub rejected_handshakes: u64,
+    pub stun: StunInfo,
+    pub peers: Vec<PeerInfo>,
+}
+
+/// What the STUN servers last reported.
+#[derive(Clone, Debug, Serialize)]
+pub struct StunInfo {
+    pub mapped_address: Option<SocketAddr>,
+    /// A [`crate::stun::NatType`], e.g. `EndpointIndependent`.
+    pub nat_type: String,
+}
+
+/// A peer state change, numbered so clients can ask for what they missed.
+#[derive(Clone, Debug, Serialize)]
+pub struct EventInfo {
+    pub sequence: u64,
+    pub unix_time: u64,
+    pub public_key: String,
+    pub state: String,
+    pub endpoint: Option<SocketAddr>,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self {
+            code: SERVER_ERROR,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// A handle on a running instance, see [`crate::WireGuard::control`].
+#[derive(Default)]
+pub struct Control {
+    runtime: RwLock<Option<Arc<Runtime>>>,
+    handshake_stats: Arc<HandshakeStats>,
+    stun_status: Arc<StunStatus>,
+    config_file: RwLock<Option<PathBuf>>,
+    /// The next sequence number and the recent events.
+    events: Mutex<(u64, VecDeque<EventInfo>)>,
+}
+
+impl Control {
+    pub(crate) fn new(handshake_stats: Arc<HandshakeStats>, stun_status: Arc<StunStatus>) -> Self {
+        Self {
+            handshake_stats,
+            stun_status,
+            ..Default::default()
+        }
+    }
+
+    /// Called by `run` once the peers are set up.
+    pub(crate) fn start(&self, runtime: Arc<Runtime>) {
+        *self.runtime.write().unwrap_or_else(|e| e.into_inner()) = Some(runtime);
+    }
+
+    pub(crate) fn stop(&self) {
+        *self.runtime.write().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    pub(crate) fn record(&self, event: &PeerEvent) {
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        let sequence = events.0;
+        events.0 += 1;
+        if events.1.len() == RECENT_EVENTS {
+            events.1.pop_front();
+        }
+        events.1.push_back(EventInfo {
+            sequence,
+            unix_time: unix_secs(SystemTime::now()),
+            public_key: encode_key(event.public_key.as_ref()),
+            state: format!("{:?}", event.status.state),
+            endpoint: event.status.endpoint,
+        });
+    }
+
+    pub(crate) fn running(&self) -> Result<Arc<Runtime>> {
+        self.runtime
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .ok_or_else(|| anyhow!("Not running"))
+    }
+
+    fn find(&self, public_key: &str) -> Result<Arc<Peer>> {
+        let public_key = decode_public_key(public_key)?;
+        self.running()?
+            .peers()
+            .get(public_key.as_bytes())
+            .cloned()
+            .ok_or_else(|| anyhow!("No such peer"))
+    }
+
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>> {
+        let mut peers = vec![];
+        for (peer, allowed_ips) in self.running()?.peers().with_allowed_ips() {
+            let status = peer.status();
+            let stats = peer.stats().await;
+            let usage = peer.usage();
+            peers.push(PeerInfo {
+                public_key: encode_key(peer.public_key.as_ref()),
+                endpoint: status.endpoint,
+                allowed_ips: allowed_ips
+                    .iter()
+                    .map(|(ip, prefix)| format!("{ip}/{prefix}"))
+                    .collect(),
+                state: format!("{:?}", status.state),
+                failed_attempts: status.failed_attempts,
+                last_handshake_secs: stats.last_handshake.map(|time| time.as_secs()),
+                tx_bytes: stats.tx_bytes,
+                rx_bytes: stats.rx_bytes,
+                data_used: usage.bytes,
+                data_limit: peer.data_limit.map(|data_limit| data_limit.bytes),
+                data_period_end: peer
+                    .data_limit
+                    .map(|data_limit| usage.period_start + data_limit.period.as_secs()),
+                expires_at: peer.expires_at.map(unix_secs),
+                dropped_packets: peer.dropped_packets(),
+            });
+        }
+        Ok(peers)
+    }
+
+    pub async fn status(&self) -> Result<StatusInfo> {
+        Ok(StatusInfo {
+            cookie_replies: self.handshake_stats.cookie_replies(),
+            rejected_handshakes: self.handshake_stats.rejected_handshakes(),
+            stun: StunInfo {
+                mapped_address: self.stun_status.mapped_address(),
+                nat_type: format!("{:?}", self.stun_status.nat_type()),
+            },
+            peers: self.peers().await?,
+        })
+    }
+
+    pub async fn add_peer(&self, peer: Peer) -> Result<()> {
+        self.running()?.change(&[], vec![peer]).await
+    }
+
+    /// Replace the peer with the public key of `peer`. Its session starts
+    /// over.
+    pub async fn update_peer(&self, peer: Peer) -> Result<()> {
+        let public_key = peer.public_key.ok_or(anyhow!("Missing peer public key"))?;
+        self.running()?.change(&[public_key], vec![peer]).await
+    }
+
+    /// Remove the peer of `public_key`, its routes and its session.
+    pub async fn remove_peer(&self, public_key: &str) -> Result<()> {
+        let public_key = decode_public_key(public_key)?;
+        self.running()?.change(&[public_key], vec![]).await
+    }
+
+    /// Where [`Control::reload`] reads the configuration from.
+    pub fn set_config_file(&self, config_file: PathBuf) {
+        *self.config_file.write().unwrap_or_else(|e| e.into_inner()) = Some(config_file);
+    }
+
+    /// Apply the peers of the configuration file: new ones are added,
+    /// missing ones removed and changed ones replaced. Peers left alone keep
+    /// their sessions. Everything else needs a restart.
+    pub async fn reload(&self) -> Result<()> {
+        let config_file = self
+            .config_file
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .ok_or(anyhow!("No configuration file to reload"))?;
+        let content = fs::read_to_string(&config_file)
+            .map_err(|e| anyhow!("Read {} failed: {e}", config_file.display()))?;
+        let configured = WireGuard::from_content(&content)?
+            .peers
+            .take()
+            .unwrap_or_default();
+
+        let runtime = self.running()?;
+        let running = runtime.peers();
+        let keys = configured
+            .iter()
+            .filter_map(|peer| peer.public_key)
+            .collect::<HashSet<_>>();
+        let mut removed = running
+            .peers()
+            .filter_map(|peer| peer.public_key)
+            .filter(|public_key| !keys.contains(public_key))
+            .collect::<Vec<_>>();
+        let mut added = vec![];
+        for peer in configured {
+            if running.is_unchanged(&peer) {
+                continue;
+            }
+            if let Some(public_key) = peer.public_key {
+                if running.get(public_key.as_bytes()).is_some() {
+                    removed.push(public_key);
+                }
+            }
+            added.push(peer);
+        }
+        runtime.change(&removed, added).await
+    }
+
+    /// Start a handshake with the peer of `public_key` right away.
+    pub async fn handshake(&self, public_key: &str) -> Result<()> {
+        let peer = self.find(public_key)?;
+        let mut batch = SendBatch::new();
+        peer.handshake(&mut batch).await?;
+        peer.flush(&mut batch).await
+    }
+
+    /// The recent events numbered `since` or later.
+    pub fn events(&self, since: u64) -> Vec<EventInfo> {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .1
+            .iter()
+            .filter(|event| event.sequence >= since)
+            .cloned()
+            .collect()
+    }
+
+    /// Answer one request line. None for notifications, which get no
+    /// response.
+    pub async fn handle_request(&self, line: &str) -> Option<String> {
+        let response = match serde_json::from_str::<Request>(line) {
+            Ok(request) => {
+                let result = self.call(&request.method, &request.params).await;
+                response(request.id?, result)
+            }
+            Err(e) => response(
+                Value::Null,
+                Err(RpcError {
+                    code: PARSE_ERROR,
+                    message: e.to_string(),
+                }),
+            ),
+        };
+        Some(response.to_string())
+    }
+
+    async fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "version" => Ok(json!({ "version": API_VERSION })),
+            "status" => Ok(json!(self.status().await?)),
+            "list_peers" => Ok(json!(self.peers().await?)),
+            "handshake" => {
+                self.handshake(public_key(params)?).await?;
+                Ok(Value::Null)
+            }
+            "events" => {
+                let since = match params.get("since") {
+                    None => 0,
+                    Some(since) => since
+                        .as_u64()
+                        .ok_or_else(|| invalid_params("since must be a number"))?,
+                };
+                Ok(json!(self.events(since)))
+            }
+            "add_peer" => {
+                self.add_peer(parse_peer(config(params)?)?).await?;
+                Ok(Value::Null)
+            }
+            "update_peer" => {
+                self.update_peer(parse_peer(config(params)?)?).await?;
+                Ok(Value::Null)
+            }
+            "remove_peer" => {
+                self.remove_peer(public_key(params)?).await?;
+                Ok(Value::Null)
+            }
+            "reload" => {
+                self.reload().await?;
+                Ok(Value::Null)
+            }
+            other => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("Unknown method: {other}"),
+            }),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Control {
+    /// Create the socket at `path`, replacing a stale one.
+    pub fn listen(path: &Path) -> Result<UnixListener> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(anyhow!("{} exists and is no socket", path.display()));
+            }
+            fs::remove_file(path)
+                .map_err(|e| anyhow!("Remove stale socket {} failed: {e}", path.display()))?;
+        }
+        // Bound in a directory only the owner can enter and moved into
+        // place once restricted, so it is never open to others meanwhile
+        let name = path
+            .file_name()
+            .ok_or(anyhow!("{} is no file path", path.display()))?;
+        let private = path.with_file_name(format!(
+            ".{}.{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&private);
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&private)
+            .map_err(|e| anyhow!("Create {} failed: {e}", private.display()))?;
+        let staged = private.join("control.sock");
+        let listener = UnixListener::bind(&staged)
+            .map_err(|e| anyhow!("Control bind {} failed: {e}", path.display()))
+            .and_then(|listener| {
+                fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))
+                    .map_err(|e| anyhow!("Restrict {} failed: {e}", path.display()))?;
+                fs::rename(&staged, path)
+                    .map_err(|e| anyhow!("Move socket to {} failed: {e}", path.display()))?;
+                Ok(listener)
+            });
+        let _ = fs::remove_dir_all(&private);
+        listener
+    }
+
+    /// Accept clients on `listener` until it fails.
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    println!("Control accept failed: {e}");
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let control = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = control.handle_client(stream).await {
+                    println!("Control client failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle_client(&self, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(mut response) = self.handle_request(&line).await {
+                response.push('\n');
+                writer.write_all(response.as_bytes()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Call `method` on the control socket at `path` and return its result, for
+/// command line clients.
+#[cfg(unix)]
+pub async fn call(path: &Path, method: &str, params: Value) -> Result<Value> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow!("Connect to {} failed: {e}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    writer
+        .write_all(format!("{request}\n").as_bytes())
+        .await
+        .map_err(|e| anyhow!("Control request failed: {e}"))?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await
+        .map_err(|e| anyhow!("Control response failed: {e}"))?
+        .ok_or(anyhow!("Control socket closed"))?;
+    let mut response: Value =
+        serde_json::from_str(&line).map_err(|e| anyhow!("Parse control response failed: {e}"))?;
+    if let Some(message) = response["error"]["message"].as_str() {
+        return Err(anyhow!("{method} failed: {message}"));
+    }
+    Ok(response["result"].take())
+}
+
+fn public_key(params: &Value) -> Result<&str, RpcError> {
+    params
+        .get("public_key")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid_params("public_key is required"))
+}
+
+fn config(params: &Value) -> Result<&str, RpcError> {
+    params
+        .get("config")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid_params("config is required"))
+}
+
+fn invalid_params(message: &str) -> RpcError {
+    RpcError {
+        code: INVALID_PARAMS,
+        message: message.to_string(),
+    }
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": e.code, "message": e.message },
+        }),
+    }
+}
+
+fn encode_key(public_key: Option<&PublicKey>) -> String {
+    public_key
+        .map(|key| general_purpose::STANDARD.encode(key.as_bytes()))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::{Control, RECENT_EVENTS};
+    use crate::peer::{PeerEvent, PeerState, PeerStatus};
+
+    #[test]
+    fn only_recent_events_are_kept() {
+        let control = Control::default();
+        let event = PeerEvent {
+            public_key: None,
+            status: PeerStatus {
+                state: PeerState::Handshaking,
+                endpoint: None,
+                failed_attempts: 0,
+                next_attempt: Some(Instant::now()),
+            },
+        };
+        for _ in 0..RECENT_EVENTS + 10 {
+            control.record(&event);
+        }
+
+        let events = control.events(0);
+        assert_eq!(events.len(), RECENT_EVENTS);
+        assert_eq!(events[0].sequence, 10);
+        assert_eq!(events[0].state, "Handshaking");
+        let since = control.events(RECENT_EVENTS as u64 + 8);
+        assert_eq!(
+            since.iter().map(|e| e.sequence).collect::<Vec<_>>(),
+            [264, 265]
+        );
+    }
+}
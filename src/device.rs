@@ -0,0 +1,136 @@
+use std::io;
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::tun::{TunBatch, TunReader, TUN_HEADROOM};
+
+const CHANNEL_DEPTH: usize = 1024;
+
+/// The virtual network interface on the inside of the tunnel. Packets read
+/// from it are encrypted to the peers, decrypted packets are written to it.
+#[async_trait]
+pub trait Device: Send + Sync {
+    fn name(&self) -> String;
+
+    fn mtu(&self) -> usize;
+
+    /// Index of a kernel interface, which gets routes to the allowed IPs of
+    /// the peers. Userspace devices have none.
+    fn if_index(&self) -> Option<u32> {
+        None
+    }
+
+    /// Read at least one IP packet into `reader`.
+    async fn recv(&self, reader: &mut TunReader) -> io::Result<()>;
+
+    /// Write every packet of `batch` and clear it. Each buffer starts with
+    /// [`TUN_HEADROOM`] bytes of headroom.
+    async fn send(&self, batch: &mut TunBatch) -> io::Result<()>;
+}
+
+/// A device backed by in-memory queues, with the other side held by a
+/// [`DeviceHandle`] standing in for the operating system.
+pub struct ChannelDevice {
+    mtu: usize,
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+/// The host side of a [`ChannelDevice`].
+pub struct DeviceHandle {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl ChannelDevice {
+    pub fn new(mtu: usize) -> (Self, DeviceHandle) {
+        let (device_tx, host_rx) = mpsc::channel(CHANNEL_DEPTH);
+        let (host_tx, device_rx) = mpsc::channel(CHANNEL_DEPTH);
+        let device = Self {
+            mtu,
+            tx: device_tx,
+            rx: Mutex::new(device_rx),
+        };
+        let handle = DeviceHandle {
+            tx: host_tx,
+            rx: host_rx,
+        };
+        (device, handle)
+    }
+}
+
+#[async_trait]
+impl Device for ChannelDevice {
+    fn name(&self) -> String {
+        "channel".to_string()
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    async fn recv(&self, reader: &mut TunReader) -> io::Result<()> {
+        reader.clear();
+        let packet = self
+            .rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        reader.push(&packet);
+        Ok(())
+    }
+
+    async fn send(&self, batch: &mut TunBatch) -> io::Result<()> {
+        for buf in batch.bufs_mut() {
+            // A full queue drops the packet, like a full tun queue
+            let _ = self.tx.try_send(buf[TUN_HEADROOM..].to_vec());
+        }
+        batch.clear();
+        Ok(())
+    }
+}
+
+impl DeviceHandle {
+    /// Hand `packet` to the tunnel, as if sent by the host.
+    pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(packet.to_vec())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// The next packet the tunnel delivered to the host.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChannelDevice, Device};
+    use crate::{
+        buffer::BufferPool,
+        tun::{TunBatch, TunReader},
+    };
+
+    #[tokio::test]
+    async fn channel_device_passes_packets_both_ways() {
+        let (device, mut handle) = ChannelDevice::new(1420);
+
+        handle.send(b"outgoing").await.unwrap();
+        let mut reader = TunReader::new(BufferPool::new(4), false);
+        device.recv(&mut reader).await.unwrap();
+        let packets = reader.drain().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(&packets[0][..], b"outgoing");
+
+        let mut batch = TunBatch::new();
+        batch.push(b"incoming");
+        device.send(&mut batch).await.unwrap();
+        assert!(batch.is_empty());
+        assert_eq!(handle.recv().await.unwrap(), b"incoming");
+    }
+}
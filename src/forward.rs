@@ -0,0 +1,403 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
+
+use crate::netstack::{self, Netstack};
+
+/// A UDP session without traffic for this long is closed.
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Pause after a failed accept, it tends to fail again right away.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForwardKind {
+    /// A local TCP port forwarded to a target in the tunnel.
+    TcpClient,
+    /// A TCP port of the tunnel address forwarded to a local target.
+    TcpServer,
+    /// A UDP port of the tunnel address forwarded to a local target.
+    UdpServer,
+}
+
+/// A port forwarding rule between a local socket and the tunnel, carried by
+/// the userspace network stack.
+pub struct Forward {
+    pub kind: ForwardKind,
+    pub bind_address: Option<SocketAddr>,
+    pub listen_port: Option<u16>,
+    pub target: Option<(String, u16)>,
+}
+
+pub enum ForwardListener {
+    Local(TcpListener),
+    Tcp(netstack::TcpListener),
+    Udp(netstack::UdpSocket),
+}
+
+impl Forward {
+    pub fn new(kind: ForwardKind) -> Result<Self> {
+        let forward = Self {
+            kind,
+            bind_address: None,
+            listen_port: None,
+            target: None,
+        };
+        Ok(forward)
+    }
+
+    pub fn set_bind_address(&mut self, bind_address: &str) -> Result<()> {
+        if self.kind != ForwardKind::TcpClient {
+            return Err(anyhow!("BindAddress is only valid for {self}"));
+        }
+        self.bind_address = Some(
+            bind_address
+                .parse()
+                .map_err(|e| anyhow!("Parse {self} bind address failed: {e}"))?,
+        );
+        Ok(())
+    }
+
+    pub fn set_listen_port(&mut self, listen_port: &str) -> Result<()> {
+        if self.kind == ForwardKind::TcpClient {
+            return Err(anyhow!("ListenPort is not valid for {self}"));
+        }
+        self.listen_port = Some(
+            listen_port
+                .parse()
+                .map_err(|e| anyhow!("Parse {self} listen port failed: {e}"))?,
+        );
+        Ok(())
+    }
+
+    /// `target` is `host:port`, with IPv6 hosts in brackets.
+    pub fn set_target(&mut self, target: &str) -> Result<()> {
+        let (host, port) = target
+            .rsplit_once(':')
+            .ok_or(anyhow!("{self} target must be host:port"))?;
+        let port = port
+            .parse()
+            .map_err(|e| anyhow!("Parse {self} target port failed: {e}"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.target = Some((host.to_string(), port));
+        Ok(())
+    }
+
+    /// Open the listening side of the rule.
+    pub async fn bind(&self, netstack: &Netstack) -> Result<ForwardListener> {
+        if self.target.is_none() {
+            return Err(anyhow!("{self} missing Target"));
+        }
+        match self.kind {
+            ForwardKind::TcpClient => {
+                let bind_address = self
+                    .bind_address
+                    .ok_or(anyhow!("{self} missing BindAddress"))?;
+                let listener = TcpListener::bind(bind_address)
+                    .await
+                    .map_err(|e| anyhow!("{self} bind {bind_address} failed: {e}"))?;
+                Ok(ForwardListener::Local(listener))
+            }
+            ForwardKind::TcpServer => {
+                let port = self
+                    .listen_port
+                    .ok_or(anyhow!("{self} missing ListenPort"))?;
+                let listener = netstack
+                    .listen(port)
+                    .map_err(|e| anyhow!("{self} listen on {port} failed: {e}"))?;
+                Ok(ForwardListener::Tcp(listener))
+            }
+            ForwardKind::UdpServer => {
+                let port = self
+                    .listen_port
+                    .ok_or(anyhow!("{self} missing ListenPort"))?;
+                let socket = netstack
+                    .bind_udp(Some(port))
+                    .map_err(|e| anyhow!("{self} bind {port} failed: {e}"))?;
+                Ok(ForwardListener::Udp(socket))
+            }
+        }
+    }
+
+    /// Forward everything arriving on `listener` until it fails.
+    pub async fn serve(self: Arc<Self>, listener: ForwardListener, netstack: Arc<Netstack>) {
+        match listener {
+            ForwardListener::Local(listener) => loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        println!("{self} accept failed: {e}");
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
+                let forward = self.clone();
+                let netstack = netstack.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = forward.forward_to_tunnel(stream, &netstack).await {
+                        println!("{forward} connection failed: {e}");
+                    }
+                });
+            },
+            ForwardListener::Tcp(mut listener) => loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        println!("{self} accept failed: {e}");
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
+                let forward = self.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = forward.forward_to_local(stream).await {
+                        println!("{forward} connection failed: {e}");
+                    }
+                });
+            },
+            ForwardListener::Udp(socket) => {
+                if let Err(e) = self.relay_udp(Arc::new(socket)).await {
+                    println!("{self} relay failed: {e}");
+                }
+            }
+        }
+    }
+
+    async fn forward_to_tunnel(&self, mut stream: TcpStream, netstack: &Netstack) -> Result<()> {
+        let target = self.tunnel_target(netstack).await?;
+        let mut remote = netstack
+            .connect(target)
+            .await
+            .map_err(|e| anyhow!("Connect {target} failed: {e}"))?;
+        tokio::io::copy_bidirectional(&mut stream, &mut remote).await?;
+        Ok(())
+    }
+
+    async fn forward_to_local(&self, mut stream: netstack::TcpStream) -> Result<()> {
+        let target = self.local_target().await?;
+        let mut local = TcpStream::connect(target)
+            .await
+            .map_err(|e| anyhow!("Connect {target} failed: {e}"))?;
+        tokio::io::copy_bidirectional(&mut stream, &mut local).await?;
+        Ok(())
+    }
+
+    /// Relay datagrams between tunnel peers and the local target, with a
+    /// local socket per peer address so replies find their way back.
+    async fn relay_udp(&self, socket: Arc<netstack::UdpSocket>) -> Result<()> {
+        let sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>> = Default::default();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await?;
+            let session = sessions
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(&from)
+                .cloned();
+            let session = match session {
+                Some(session) => session,
+                None => match self.open_udp_session(from, &socket, &sessions).await {
+                    Ok(session) => session,
+                    Err(e) => {
+                        println!("{self} session for {from} failed: {e}");
+                        continue;
+                    }
+                },
+            };
+            if let Err(e) = session.send(&buf[..len]).await {
+                println!("{self} send for {from} failed: {e}");
+            }
+        }
+    }
+
+    async fn open_udp_session(
+        &self,
+        from: SocketAddr,
+        socket: &Arc<netstack::UdpSocket>,
+        sessions: &Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>>,
+    ) -> Result<Arc<UdpSocket>> {
+        let target = self.local_target().await?;
+        let bind_address = match target {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+        let session = Arc::new(UdpSocket::bind(bind_address).await?);
+        session.connect(target).await?;
+        sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(from, session.clone());
+
+        let reply_session = session.clone();
+        let socket = socket.clone();
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            while let Ok(Ok(len)) =
+                tokio::time::timeout(UDP_SESSION_TIMEOUT, reply_session.recv(&mut buf)).await
+            {
+                if socket.send_to(&buf[..len], from).await.is_err() {
+                    break;
+                }
+            }
+            sessions
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&from);
+        });
+        Ok(session)
+    }
+
+    async fn tunnel_target(&self, netstack: &Netstack) -> io::Result<SocketAddr> {
+        let (host, port) = self.target()?;
+        match host.parse() {
+            Ok(ip) => Ok(SocketAddr::new(ip, port)),
+            Err(_) => netstack.lookup(host, port).await,
+        }
+    }
+
+    async fn local_target(&self) -> io::Result<SocketAddr> {
+        let (host, port) = self.target()?;
+        lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{host} not found")))
+    }
+
+    fn target(&self) -> io::Result<(&str, u16)> {
+        self.target
+            .as_ref()
+            .map(|(host, port)| (host.as_str(), *port))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Missing target"))
+    }
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ForwardKind::TcpClient => write!(f, "TCPClientTunnel"),
+            ForwardKind::TcpServer => write!(f, "TCPServerTunnel"),
+            ForwardKind::UdpServer => write!(f, "UDPServerTunnel"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream, UdpSocket},
+    };
+
+    use super::{Forward, ForwardKind, ForwardListener};
+    use crate::netstack::{tests::linked_pair, Netstack};
+
+    async fn start(forward: Forward, netstack: &Arc<Netstack>) {
+        let listener = forward.bind(netstack).await.unwrap();
+        tokio::spawn(Arc::new(forward).serve(listener, netstack.clone()));
+    }
+
+    async fn local_echo() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        addr.to_string()
+    }
+
+    #[tokio::test]
+    async fn tcp_client_tunnel_reaches_tunnel_target() {
+        let (a, b) = linked_pair();
+        let mut listener = b.listen(7).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let mut forward = Forward::new(ForwardKind::TcpClient).unwrap();
+        forward.set_bind_address("127.0.0.1:0").unwrap();
+        forward.set_target("10.0.0.2:7").unwrap();
+        let listener = forward.bind(&a).await.unwrap();
+        let addr = match &listener {
+            ForwardListener::Local(listener) => listener.local_addr().unwrap(),
+            _ => unreachable!(),
+        };
+        tokio::spawn(Arc::new(forward).serve(listener, a));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn tcp_server_tunnel_reaches_local_service() {
+        let (a, b) = linked_pair();
+        let mut forward = Forward::new(ForwardKind::TcpServer).unwrap();
+        forward.set_listen_port("8080").unwrap();
+        forward.set_target(&local_echo().await).unwrap();
+        start(forward, &a).await;
+
+        let mut stream = b.connect("10.0.0.1:8080".parse().unwrap()).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn udp_server_tunnel_reaches_local_service() {
+        let (a, b) = linked_pair();
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut forward = Forward::new(ForwardKind::UdpServer).unwrap();
+        forward.set_listen_port("5353").unwrap();
+        forward
+            .set_target(&echo.local_addr().unwrap().to_string())
+            .unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((len, from)) = echo.recv_from(&mut buf).await {
+                echo.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+        start(forward, &a).await;
+
+        let client = b.bind_udp(None).unwrap();
+        client
+            .send_to(b"ping", "10.0.0.1:5353".parse().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, "10.0.0.1:5353".parse().unwrap());
+    }
+
+    #[test]
+    fn keys_are_checked_against_the_kind() {
+        let mut forward = Forward::new(ForwardKind::TcpClient).unwrap();
+        assert!(forward.set_listen_port("80").is_err());
+        let mut forward = Forward::new(ForwardKind::TcpServer).unwrap();
+        assert!(forward.set_bind_address("127.0.0.1:80").is_err());
+        forward.set_target("[::1]:80").unwrap();
+        assert_eq!(forward.target, Some(("::1".to_string(), 80)));
+    }
+}
@@ -0,0 +1,202 @@
+//! The control API and changing peers while running.
+
+use std::{os::unix::fs::PermissionsExt, path::Path, time::Duration};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+};
+
+use super::{assert_delivered, count, running, udp_packet, Harness, Site, HANDSHAKE_INIT, TIMEOUT};
+use crate::{control::Control, peer::PeerState, wireguard::parse_peer};
+
+/// A client of the control API, one request at a time.
+struct ControlClient {
+    writer: OwnedWriteHalf,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+}
+
+impl ControlClient {
+    async fn connect(path: &Path) -> Self {
+        let (reader, writer) = UnixStream::connect(path).await.unwrap().into_split();
+        Self {
+            writer,
+            lines: BufReader::new(reader).lines(),
+        }
+    }
+
+    async fn call(&mut self, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        self.writer
+            .write_all(format!("{request}\n").as_bytes())
+            .await
+            .unwrap();
+        let line = self.lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn control_api_lists_peers_and_handshakes() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let mut control = None;
+    let a_node = harness.start_with(&a.config(&[&b], None), a.endpoint, |wg| {
+        control = Some(wg.control())
+    });
+    let mut b_node = harness.start(&b.config(&[&a], None), b.endpoint);
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+
+    let path = std::env::temp_dir().join(format!("wireguard-{}.sock", std::process::id()));
+    let listener = Control::listen(&path).unwrap();
+    tokio::spawn(control.unwrap().serve(listener));
+    let mut client = ControlClient::connect(&path).await;
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    assert_eq!(
+        client.call("version", json!(null)).await["result"]["version"],
+        1
+    );
+    let peers = client.call("list_peers", json!(null)).await;
+    let peer = &peers["result"][0];
+    assert_eq!(peer["public_key"], b.public_key);
+    assert_eq!(peer["state"], "Established");
+    assert_eq!(peer["allowed_ips"], json!(["10.0.0.2/32"]));
+    assert_eq!(peer["endpoint"], b.endpoint.to_string());
+    assert!(peer["tx_bytes"].as_u64().unwrap() > 0);
+    let status = client.call("status", json!(null)).await;
+    assert_eq!(status["result"]["rejected_handshakes"], 0);
+    assert_eq!(status["result"]["peers"][0]["public_key"], b.public_key);
+
+    // Events are recorded by a task of their own
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            let events = client.call("events", json!({ "since": 0 })).await;
+            let established = events["result"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|event| event["state"] == "Established");
+            if established {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let handshake = client
+        .call("handshake", json!({ "public_key": b.public_key }))
+        .await;
+    assert_eq!(handshake["result"], Value::Null);
+    let initiations = count(&mut tap, |from, datagram| {
+        *from == a.endpoint && datagram[0] == HANDSHAKE_INIT
+    });
+    assert_eq!(initiations, 2);
+
+    let unknown = client.call("nope", json!(null)).await;
+    assert_eq!(unknown["error"]["code"], -32601);
+    let _ = std::fs::remove_file(&path);
+}
+
+/// The `[Peer]` keys for reaching `site`.
+fn peer_config(site: &Site) -> String {
+    format!(
+        "PublicKey = {}\nAllowedIPs = {}/32\nEndpoint = {}\n",
+        site.public_key, site.address, site.endpoint
+    )
+}
+
+#[tokio::test]
+async fn peers_are_added_and_removed_while_running() {
+    let harness = Harness::new();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let (mut control, mut events) = (None, None);
+    let mut a_node = harness.start_with(&a.config(&[], None), a.endpoint, |wg| {
+        control = Some(wg.control());
+        events = Some(wg.subscribe());
+    });
+    let (control, mut events) = (control.unwrap(), events.unwrap());
+    let mut b_node = harness.start(&b.config(&[&a], None), b.endpoint);
+    running(&control).await;
+
+    control
+        .add_peer(parse_peer(&peer_config(&b)).unwrap())
+        .await
+        .unwrap();
+    assert!(control
+        .add_peer(parse_peer(&peer_config(&b)).unwrap())
+        .await
+        .is_err());
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+    assert_delivered((&b, &b_node), (&a, &mut a_node), b"pong").await;
+
+    control.remove_peer(&b.public_key).await.unwrap();
+    assert!(control.peers().await.unwrap().is_empty());
+    let mut removed = false;
+    while let Ok(event) = events.try_recv() {
+        removed |= event.status.state == PeerState::Removed;
+    }
+    assert!(removed);
+    // Neither direction gets through, b still has its session
+    let packet = udp_packet(a.socket(1000), b.socket(2000), b"gone");
+    a_node.send(&packet).await;
+    assert_eq!(b_node.recv(Duration::from_millis(500)).await, None);
+    let packet = udp_packet(b.socket(1000), a.socket(2000), b"gone");
+    b_node.send(&packet).await;
+    assert_eq!(a_node.recv(Duration::from_millis(500)).await, None);
+
+    // Added again, it starts over with a new session
+    control
+        .add_peer(parse_peer(&peer_config(&b)).unwrap())
+        .await
+        .unwrap();
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"again").await;
+}
+
+#[tokio::test]
+async fn reload_applies_the_peers_of_the_config_file() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let c = Site::new("10.0.0.3", "192.0.2.3:51820");
+    let path = std::env::temp_dir().join(format!("wireguard-{}.conf", std::process::id()));
+    std::fs::write(&path, a.config(&[&b], None)).unwrap();
+    let mut control = None;
+    let a_node = harness.start_with(&a.config(&[&b], None), a.endpoint, |wg| {
+        control = Some(wg.control())
+    });
+    let control = control.unwrap();
+    control.set_config_file(path.clone());
+    let mut b_node = harness.start(&b.config(&[&a], None), b.endpoint);
+    let mut c_node = harness.start(&c.config(&[&a], None), c.endpoint);
+    running(&control).await;
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+
+    // b is unchanged and keeps its session
+    std::fs::write(&path, a.config(&[&b, &c], None)).unwrap();
+    control.reload().await.unwrap();
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"still").await;
+    assert_delivered((&a, &a_node), (&c, &mut c_node), b"new").await;
+    let initiations = count(&mut tap, |from, datagram| {
+        *from == a.endpoint && datagram[0] == HANDSHAKE_INIT
+    });
+    assert_eq!(initiations, 2);
+
+    std::fs::write(&path, a.config(&[&c], None)).unwrap();
+    control.reload().await.unwrap();
+    let peers = control.peers().await.unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].public_key, c.public_key);
+    let _ = std::fs::remove_file(&path);
+}
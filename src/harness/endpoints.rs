@@ -0,0 +1,217 @@
+//! Finding peer endpoints: failover, NAT traversal through a rendezvous
+//! server and STUN.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose, Engine};
+
+use super::{assert_delivered, udp_packet, Harness, Nat, Site, DATA, TIMEOUT};
+use crate::{
+    rendezvous,
+    stun::{self, NatType},
+    transport::Transport,
+    udp::SendBatch,
+    WireGuard,
+};
+
+#[tokio::test]
+async fn unreachable_endpoint_fails_over_to_the_next() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let dead: SocketAddr = "198.51.100.1:51820".parse().unwrap();
+    let a_config = a
+        .config(&[&b], None)
+        .replace("Endpoint = ", &format!("Endpoint = {dead}, "));
+    let mut a_node = harness.start(&a_config, a.endpoint);
+    let mut b_node = harness.start(&b.config(&[&a], None), b.endpoint);
+
+    // Two race periods at most, well within REKEY_TIMEOUT
+    let packet = udp_packet(a.socket(1000), b.socket(2000), b"ping");
+    a_node.send(&packet).await;
+    assert_eq!(b_node.recv(Duration::from_secs(1)).await, Some(packet));
+
+    // a sticks with the endpoint that answered
+    assert_delivered((&b, &b_node), (&a, &mut a_node), b"pong").await;
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"again").await;
+    let mut data_to = vec![];
+    while let Ok((from, to, datagram)) = tap.try_recv() {
+        if from == a.endpoint && datagram[0] == DATA {
+            data_to.push(to);
+        }
+    }
+    assert!(data_to.len() >= 2, "{data_to:?}");
+    assert!(data_to.iter().all(|to| *to == b.endpoint), "{data_to:?}");
+}
+
+#[tokio::test]
+async fn peers_behind_nats_meet_through_rendezvous() {
+    let harness = Harness::new();
+    let server = harness.network.attach("192.0.2.100:51821".parse().unwrap());
+    tokio::spawn(rendezvous::serve(Arc::new(server)));
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+
+    // Neither knows where the other is, and each drops unsolicited
+    // datagrams
+    let config = |site: &Site, peer: &Site| {
+        site.config(&[peer], None)
+            .replace(&format!("Endpoint = {}\n", peer.endpoint), "")
+            .replacen("\n\n", "\nRendezvous = 192.0.2.100:51821\n\n", 1)
+    };
+    let behind_nat = |endpoint: SocketAddr| {
+        let network = harness.network.clone();
+        move |wg: &mut WireGuard| {
+            let nat = Nat::new(network.attach(endpoint));
+            wg.set_transport(Arc::new(nat)).unwrap()
+        }
+    };
+    let mut a_node = harness.start_with(&config(&a, &b), a.endpoint, behind_nat(a.endpoint));
+    let mut b_node = harness.start_with(&config(&b, &a), b.endpoint, behind_nat(b.endpoint));
+
+    // Without an endpoint, traffic is dropped until the introduction
+    let packet = udp_packet(a.socket(1000), b.socket(2000), b"ping");
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            a_node.send(&packet).await;
+            if b_node.recv(Duration::from_millis(200)).await == Some(packet.clone()) {
+                break;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_delivered((&b, &b_node), (&a, &mut a_node), b"pong").await;
+}
+
+#[tokio::test]
+async fn forged_introduction_maps_no_endpoint() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    // Whoever can send from the server's address, it stands in for it
+    let server: SocketAddr = "192.0.2.100:51821".parse().unwrap();
+    let forger = harness.network.attach(server);
+    let forged: SocketAddr = "192.0.2.66:51820".parse().unwrap();
+    let _stranger = harness.network.attach(forged);
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let config = a
+        .config(&[&b], None)
+        .replace(&format!("Endpoint = {}\n", b.endpoint), "")
+        .replacen("\n\n", "\nRendezvous = 192.0.2.100:51821\n\n", 1);
+    let mut control = None;
+    let _a_node = harness.start_with(&config, a.endpoint, |wg| control = Some(wg.control()));
+    tokio::time::timeout(TIMEOUT, async {
+        while tap.recv().await.unwrap().1 != server {}
+    })
+    .await
+    .unwrap();
+
+    // and claims b is at the stranger's address
+    let key = general_purpose::STANDARD.decode(&b.public_key).unwrap();
+    let message = rendezvous::endpoint(&key.try_into().unwrap(), forged);
+    let mut batch = SendBatch::new();
+    batch.slot()[..message.len()].copy_from_slice(&message);
+    batch.commit(message.len(), a.endpoint, None);
+    forger.send_batch(&mut batch).await.unwrap();
+
+    // a punches towards it, but maps nothing until b answers from there
+    tokio::time::timeout(TIMEOUT, async {
+        while tap.recv().await.unwrap().1 != forged {}
+    })
+    .await
+    .unwrap();
+    let runtime = control.unwrap().running().unwrap();
+    assert!(!runtime.endpoints.contains_key(&forged));
+}
+
+#[tokio::test]
+async fn stun_servers_report_the_public_address() {
+    let harness = Harness::new();
+    for server in ["192.0.2.200:3478", "192.0.2.201:3478"] {
+        let server = harness.network.attach(server.parse().unwrap());
+        tokio::spawn(stun::tests::serve(server, 0));
+    }
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let config = a.config(&[&b], None).replacen(
+        "\n\n",
+        "\nStunServer = 192.0.2.200:3478, 192.0.2.201:3478\n\n",
+        1,
+    );
+    let (mut status, mut control) = (None, None);
+    let a_node = harness.start_with(&config, a.endpoint, |wg| {
+        status = Some(wg.stun_status());
+        control = Some(wg.control());
+    });
+    let mut b_node = harness.start(&b.config(&[&a], None), b.endpoint);
+    let status = status.unwrap();
+
+    // The responses share the socket with the tunnel
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+    tokio::time::timeout(TIMEOUT, async {
+        while status.mapped_address().is_none() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(status.mapped_address(), Some(a.endpoint));
+    assert_eq!(status.nat_type(), NatType::EndpointIndependent);
+    // And so does the control API
+    let stun = control.unwrap().status().await.unwrap().stun;
+    assert_eq!(stun.mapped_address, Some(a.endpoint));
+    assert_eq!(stun.nat_type, "EndpointIndependent");
+}
+
+#[tokio::test]
+async fn stun_and_rendezvous_pass_obfuscation() {
+    let harness = Harness::new();
+    let server = harness.network.attach("192.0.2.100:51821".parse().unwrap());
+    tokio::spawn(rendezvous::serve(Arc::new(server)));
+    let stun_server = harness.network.attach("192.0.2.200:3478".parse().unwrap());
+    tokio::spawn(stun::tests::serve(stun_server, 0));
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+
+    // The servers know nothing of the obfuscation
+    let config = |site: &Site, peer: &Site| {
+        site.config(&[peer], None)
+            .replace(&format!("Endpoint = {}\n", peer.endpoint), "")
+            .replacen(
+                "\n\n",
+                "\nRendezvous = 192.0.2.100:51821\nStunServer = 192.0.2.200:3478\n\
+                 Jc = 2\nJmin = 20\nJmax = 40\nS1 = 17\nS2 = 29\n\
+                 H1 = 91847\nH2 = 5530\nH3 = 771\nH4 = 1209\n\n",
+                1,
+            )
+    };
+    let mut status = None;
+    let mut a_node = harness.start_with(&config(&a, &b), a.endpoint, |wg| {
+        status = Some(wg.stun_status())
+    });
+    let mut b_node = harness.start(&config(&b, &a), b.endpoint);
+    let status = status.unwrap();
+
+    let packet = udp_packet(a.socket(1000), b.socket(2000), b"ping");
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            a_node.send(&packet).await;
+            if b_node.recv(Duration::from_millis(200)).await == Some(packet.clone()) {
+                break;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_delivered((&b, &b_node), (&a, &mut a_node), b"pong").await;
+    tokio::time::timeout(TIMEOUT, async {
+        while status.mapped_address().is_none() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(status.mapped_address(), Some(a.endpoint));
+}
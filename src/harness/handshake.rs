@@ -0,0 +1,163 @@
+//! Handshakes: retries, cookies under load, rekeying and expired sessions.
+
+use std::time::Duration;
+
+use super::{
+    assert_delivered, count, pair, udp_packet, Harness, Site, COOKIE_REPLY, HANDSHAKE_INIT,
+};
+use crate::{
+    peer::{REJECT_AFTER_TIME, REKEY_AFTER_TIME},
+    transport::Transport,
+    udp::SendBatch,
+};
+
+#[tokio::test]
+async fn handshake_is_retried_until_the_peer_is_reachable() {
+    let harness = Harness::new();
+    let (a, b, a_node, mut b_node) = pair(&harness, None);
+    harness.network.set_down(b.endpoint, true);
+
+    let packet = udp_packet(a.socket(1000), b.socket(2000), b"queued");
+    a_node.send(&packet).await;
+    assert_eq!(b_node.recv(Duration::from_secs(1)).await, None);
+
+    // The initiation is sent again after REKEY_TIMEOUT and the queued
+    // packet follows the handshake
+    harness.network.set_down(b.endpoint, false);
+    assert_eq!(b_node.recv(Duration::from_secs(10)).await, Some(packet));
+}
+
+#[tokio::test]
+async fn restarted_peer_handshakes_again() {
+    let harness = Harness::new();
+    let (a, b, mut a_node, b_node) = pair(&harness, None);
+    drop(b_node);
+
+    // Same keys, but none of the session state
+    let mut b_node = harness.start(&b.config(&[&a], None), b.endpoint);
+    assert_delivered((&b, &b_node), (&a, &mut a_node), b"again").await;
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"and back").await;
+}
+
+#[tokio::test]
+async fn handshake_completes_with_a_cookie_under_load() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let a_node = harness.start(&a.config(&[&b], None), a.endpoint);
+    let mut stats = None;
+    // With a limit of zero, b is always under load
+    let mut b_node = harness.start_with(&b.config(&[&a], None), b.endpoint, |wg| {
+        wg.set_handshake_rate_limit(0).unwrap();
+        stats = Some(wg.handshake_stats());
+    });
+    let stats = stats.unwrap();
+
+    // The first initiation is answered with a cookie, the one retried
+    // after REKEY_TIMEOUT carries it and is accepted
+    let packet = udp_packet(a.socket(1000), b.socket(2000), b"ping");
+    a_node.send(&packet).await;
+    assert_eq!(b_node.recv(Duration::from_secs(10)).await, Some(packet));
+    assert_eq!(stats.cookie_replies(), 1);
+    assert_eq!(
+        count(&mut tap, |from, datagram| {
+            *from == b.endpoint && datagram[0] == COOKIE_REPLY
+        }),
+        1
+    );
+}
+
+#[tokio::test]
+async fn unknown_initiators_get_cookies_under_load() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let stranger = Site::new("10.0.0.9", "192.0.2.9:51820");
+    let (mut stats, mut control) = (None, None);
+    let _a_node = harness.start_with(&a.config(&[], None), a.endpoint, |wg| {
+        wg.set_handshake_rate_limit(0).unwrap();
+        stats = Some(wg.handshake_stats());
+        control = Some(wg.control());
+    });
+    let stats = stats.unwrap();
+    let stranger_node = harness.start(&stranger.config(&[&a], None), stranger.endpoint);
+
+    let packet = udp_packet(stranger.socket(1000), a.socket(2000), b"let me in");
+    stranger_node.send(&packet).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let cookies = count(&mut tap, |from, datagram| {
+        *from == a.endpoint && datagram[0] == COOKIE_REPLY
+    });
+    assert_eq!(cookies, 1);
+    assert_eq!(stats.cookie_replies(), 1);
+    assert_eq!(stats.rejected_handshakes(), 0);
+    // The control API reports the same counters
+    let status = control.unwrap().status().await.unwrap();
+    assert_eq!(status.cookie_replies, 1);
+    assert_eq!(status.rejected_handshakes, 0);
+}
+
+#[tokio::test]
+async fn replayed_initiation_maps_no_endpoint() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let mut control = None;
+    let mut a_node = harness.start_with(&a.config(&[&b], None), a.endpoint, |wg| {
+        control = Some(wg.control())
+    });
+    let b_node = harness.start(&b.config(&[&a], None), b.endpoint);
+    assert_delivered((&b, &b_node), (&a, &mut a_node), b"ping").await;
+    let initiation = std::iter::from_fn(|| tap.try_recv().ok())
+        .find(|(from, _, datagram)| *from == b.endpoint && datagram[0] == HANDSHAKE_INIT)
+        .map(|(_, _, datagram)| datagram)
+        .unwrap();
+
+    // The initiation names b, but only b's session can tell it is old
+    let stranger = harness.network.attach("192.0.2.9:51820".parse().unwrap());
+    let mut batch = SendBatch::new();
+    batch.slot()[..initiation.len()].copy_from_slice(&initiation);
+    batch.commit(initiation.len(), a.endpoint, None);
+    stranger.send_batch(&mut batch).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let answered =
+        std::iter::from_fn(|| tap.try_recv().ok()).any(|(_, to, _)| to == stranger.local_addr());
+    assert!(!answered);
+    let runtime = control.unwrap().running().unwrap();
+    assert!(!runtime.endpoints.contains_key(&stranger.local_addr()));
+    assert!(runtime.endpoints.contains_key(&b.endpoint));
+}
+
+#[tokio::test]
+async fn session_is_rekeyed_after_rekey_after_time() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let (a, b, a_node, mut b_node) = pair(&harness, None);
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"first").await;
+
+    // The old session carries the packet that starts the rekey
+    tokio::time::sleep(REKEY_AFTER_TIME + Duration::from_millis(1500)).await;
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"second").await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"third").await;
+    let initiations = count(&mut tap, |from, datagram| {
+        *from == a.endpoint && datagram[0] == HANDSHAKE_INIT
+    });
+    assert_eq!(initiations, 2);
+}
+
+#[tokio::test]
+async fn expired_session_handshakes_again() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let (a, b, a_node, mut b_node) = pair(&harness, None);
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"first").await;
+
+    tokio::time::sleep(REJECT_AFTER_TIME + Duration::from_millis(1500)).await;
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"after expiry").await;
+    let initiations = count(&mut tap, |_, datagram| datagram[0] == HANDSHAKE_INIT);
+    assert_eq!(initiations, 2);
+}
@@ -0,0 +1,139 @@
+//! Data limits, expiry and send rates of peers.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde_json::json;
+
+use super::{assert_delivered, count, running, udp_packet, Harness, Node, Site};
+use crate::{control::Control, quota::unix_secs};
+
+/// Start `a` with `b` as its peer, with `interface` and `peer` added to
+/// the sections, and `b` with `a`. Returns the control of `a`.
+async fn start_limited(
+    harness: &Harness,
+    (a, b): (&Site, &Site),
+    interface: &str,
+    peer: &str,
+) -> (Node, Node, Arc<Control>) {
+    let own = a.config(&[], None);
+    let config = format!(
+        "{own}{interface}{}{peer}\n",
+        &a.config(&[b], None)[own.len()..]
+    );
+    let mut control = None;
+    let a_node = harness.start_with(&config, a.endpoint, |wg| control = Some(wg.control()));
+    let b_node = harness.start(&b.config(&[a], None), b.endpoint);
+    let control = control.unwrap();
+    running(&control).await;
+    (a_node, b_node, control)
+}
+
+#[tokio::test]
+async fn data_limit_suspends_the_peer() {
+    let harness = Harness::new();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let (mut a_node, mut b_node, control) =
+        start_limited(&harness, (&a, &b), "", "DataLimit = 200/1d").await;
+
+    // 32 and 128 bytes, both directions count
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+    assert_delivered((&b, &b_node), (&a, &mut a_node), &[0; 100]).await;
+    let peers = control.peers().await.unwrap();
+    assert_eq!(peers[0].data_used, 160);
+    assert_eq!(peers[0].data_limit, Some(200));
+
+    // This one takes it over the limit and is dropped
+    let packet = udp_packet(a.socket(1000), b.socket(2000), &[0; 100]);
+    a_node.send(&packet).await;
+    assert_eq!(b_node.recv(Duration::from_millis(500)).await, None);
+    let peers = control.peers().await.unwrap();
+    assert_eq!(peers[0].state, "Suspended");
+    assert_eq!(peers[0].data_used, 288);
+    // The session is closed, b's packets are dropped
+    let packet = udp_packet(b.socket(1000), a.socket(2000), b"pong");
+    b_node.send(&packet).await;
+    assert_eq!(a_node.recv(Duration::from_millis(500)).await, None);
+    assert!(control.handshake(&b.public_key).await.is_err());
+}
+
+#[tokio::test]
+async fn expired_peer_gets_no_traffic() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let (mut a_node, mut b_node, control) =
+        start_limited(&harness, (&a, &b), "", "ExpiresAt = 2020-01-01").await;
+
+    let packet = udp_packet(a.socket(1000), b.socket(2000), b"ping");
+    a_node.send(&packet).await;
+    assert_eq!(b_node.recv(Duration::from_millis(500)).await, None);
+    let packet = udp_packet(b.socket(1000), a.socket(2000), b"pong");
+    b_node.send(&packet).await;
+    assert_eq!(a_node.recv(Duration::from_millis(500)).await, None);
+    // Not even b's initiation is answered
+    assert_eq!(count(&mut tap, |from, _| *from == a.endpoint), 0);
+    let peers = control.peers().await.unwrap();
+    assert_eq!(peers[0].state, "Suspended");
+    assert_eq!(peers[0].expires_at, Some(1577836800));
+}
+
+#[tokio::test]
+async fn data_usage_is_restored_from_the_state_file() {
+    let harness = Harness::new();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let path = std::env::temp_dir().join(format!("wireguard-{}.json", std::process::id()));
+    let period_start = unix_secs(SystemTime::now());
+    std::fs::write(
+        &path,
+        json!({ &b.public_key: { "period_start": period_start, "bytes": 150 } }).to_string(),
+    )
+    .unwrap();
+    let (a_node, mut b_node, control) = start_limited(
+        &harness,
+        (&a, &b),
+        &format!("StateFile = {}\n", path.display()),
+        "DataLimit = 200/1d",
+    )
+    .await;
+    let peers = control.peers().await.unwrap();
+    assert_eq!(peers[0].data_used, 150);
+    assert_eq!(peers[0].data_period_end, Some(period_start + 86400));
+
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+    let packet = udp_packet(a.socket(1000), b.socket(2000), b"ping");
+    a_node.send(&packet).await;
+    assert_eq!(b_node.recv(Duration::from_millis(500)).await, None);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn send_rate_queues_then_drops() {
+    let harness = Harness::new();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let (a_node, mut b_node, control) =
+        start_limited(&harness, (&a, &b), "", "SendRate = 1K").await;
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+
+    // A second of the rate passes, a second waits, the rest is dropped
+    for i in 0..20 {
+        let packet = udp_packet(a.socket(1000), b.socket(2000), &[i; 100]);
+        a_node.send(&packet).await;
+    }
+    let mut received = vec![];
+    while let Some(packet) = b_node.recv(Duration::from_millis(1500)).await {
+        received.push((packet[28], Instant::now()));
+    }
+    let dropped = control.peers().await.unwrap()[0].dropped_packets;
+    assert!(dropped > 0);
+    assert_eq!(received.len() as u64 + dropped, 20);
+    assert!(received.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    let (first, last) = (received[0].1, received[received.len() - 1].1);
+    assert!(last - first >= Duration::from_millis(500));
+}
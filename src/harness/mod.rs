@@ -0,0 +1,274 @@
+//! Runs [`WireGuard`] instances in process, linked by an in-memory network
+//! and with in-memory devices, so tunnels can be tested without root.
+
+mod control;
+mod endpoints;
+mod handshake;
+mod limits;
+mod relay;
+mod transports;
+mod tunnel;
+
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
+use boringtun::x25519::{PublicKey, StaticSecret};
+use rand::RngCore;
+use tokio::{runtime::Runtime, sync::mpsc};
+
+use crate::{
+    control::Control,
+    device::{ChannelDevice, DeviceHandle},
+    transport::{ChannelNetwork, ChannelTransport, Transport},
+    udp::{RecvBatch, SendBatch},
+    WireGuard,
+};
+
+const HARNESS_MTU: usize = 1420;
+const HARNESS_WORKERS: usize = 2;
+
+/// A fresh `(private, public)` key pair, base64 encoded.
+pub(crate) fn keypair() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let secret = StaticSecret::from(bytes);
+    let public = PublicKey::from(&secret);
+    (
+        general_purpose::STANDARD.encode(secret.to_bytes()),
+        general_purpose::STANDARD.encode(public.as_bytes()),
+    )
+}
+
+#[derive(Default)]
+pub(crate) struct Harness {
+    pub network: ChannelNetwork,
+}
+
+/// A running instance. Dropping it stops the instance.
+pub(crate) struct Node {
+    handle: DeviceHandle,
+    runtime: Option<Runtime>,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start an instance from `config`, reachable at `endpoint`.
+    pub fn start(&self, config: &str, endpoint: SocketAddr) -> Node {
+        self.start_with(config, endpoint, |_| {})
+    }
+
+    /// Like [`Harness::start`], with `configure` applied before it runs.
+    pub fn start_with(
+        &self,
+        config: &str,
+        endpoint: SocketAddr,
+        configure: impl FnOnce(&mut WireGuard),
+    ) -> Node {
+        let mut wg = WireGuard::from_content(config).unwrap();
+        let (device, handle) = ChannelDevice::new(HARNESS_MTU);
+        wg.set_device(Arc::new(device)).unwrap();
+        wg.set_transport(Arc::new(self.network.attach(endpoint)))
+            .unwrap();
+        wg.set_workers(HARNESS_WORKERS).unwrap();
+        configure(&mut wg);
+
+        // Its own runtime, so every task of the instance stops with it
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(HARNESS_WORKERS)
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.spawn(async move {
+            if let Err(e) = wg.run().await {
+                println!("Harness instance failed: {e}");
+            }
+        });
+        Node {
+            handle,
+            runtime: Some(runtime),
+        }
+    }
+}
+
+impl Node {
+    /// Write `packet` to the device, as if sent by the host.
+    pub async fn send(&self, packet: &[u8]) {
+        self.handle.send(packet).await.unwrap();
+    }
+
+    /// The next packet delivered to the device within `timeout`.
+    pub async fn recv(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+        tokio::time::timeout(timeout, self.handle.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// A NAT in front of a transport. Datagrams only come in from addresses the
+/// inside sent to before, as with a port restricted cone NAT.
+pub(crate) struct Nat {
+    inner: ChannelTransport,
+    contacted: Mutex<HashSet<SocketAddr>>,
+}
+
+impl Nat {
+    pub fn new(inner: ChannelTransport) -> Self {
+        Self {
+            inner,
+            contacted: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for Nat {
+    async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()> {
+        self.contacted
+            .lock()
+            .unwrap()
+            .extend(batch.packets().map(|(_, endpoint, _)| endpoint));
+        self.inner.send_batch(batch).await
+    }
+
+    async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        self.inner.recv_batch(batch).await?;
+        let contacted = self.contacted.lock().unwrap();
+        batch.retain_mut(|_, from| contacted.contains(from));
+        Ok(())
+    }
+}
+
+/// A minimal IPv4/UDP packet from `src` to `dst` carrying `payload`.
+pub(crate) fn udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let (SocketAddr::V4(src), SocketAddr::V4(dst)) = (src, dst) else {
+        panic!("IPv4 addresses expected");
+    };
+    let total = 20 + 8 + payload.len();
+    let mut packet = vec![0u8; total];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 17;
+    packet[12..16].copy_from_slice(&src.ip().octets());
+    packet[16..20].copy_from_slice(&dst.ip().octets());
+    let checksum = !packet[..20]
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .fold(0u32, |sum, word| {
+            let sum = sum + word;
+            (sum & 0xffff) + (sum >> 16)
+        }) as u16;
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet[20..22].copy_from_slice(&src.port().to_be_bytes());
+    packet[22..24].copy_from_slice(&dst.port().to_be_bytes());
+    packet[24..26].copy_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    packet[28..].copy_from_slice(payload);
+    packet
+}
+
+const HANDSHAKE_INIT: u8 = 1;
+const COOKIE_REPLY: u8 = 3;
+const DATA: u8 = 4;
+/// A data message with an empty payload is a keepalive.
+const KEEPALIVE_LEN: usize = 32;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One instance of a test: its keys, tunnel address and endpoint.
+struct Site {
+    private_key: String,
+    public_key: String,
+    address: &'static str,
+    endpoint: SocketAddr,
+}
+
+impl Site {
+    fn new(address: &'static str, endpoint: &str) -> Self {
+        let (private_key, public_key) = keypair();
+        Self {
+            private_key,
+            public_key,
+            address,
+            endpoint: endpoint.parse().unwrap(),
+        }
+    }
+
+    fn config(&self, peers: &[&Site], keepalive: Option<u16>) -> String {
+        let mut config = format!(
+            "[Interface]\nPrivateKey = {}\nAddress = {}/24\n",
+            self.private_key, self.address
+        );
+        for peer in peers {
+            config += &format!(
+                "\n[Peer]\nPublicKey = {}\nAllowedIPs = {}/32\nEndpoint = {}\n",
+                peer.public_key, peer.address, peer.endpoint
+            );
+            if let Some(keepalive) = keepalive {
+                config += &format!("PersistentKeepalive = {keepalive}\n");
+            }
+        }
+        config
+    }
+
+    fn socket(&self, port: u16) -> SocketAddr {
+        SocketAddr::new(self.address.parse().unwrap(), port)
+    }
+}
+
+fn pair(harness: &Harness, keepalive: Option<u16>) -> (Site, Site, Node, Node) {
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let a_node = harness.start(&a.config(&[&b], keepalive), a.endpoint);
+    let b_node = harness.start(&b.config(&[&a], keepalive), b.endpoint);
+    (a, b, a_node, b_node)
+}
+
+/// Send a packet from `from` to `to` and check it arrives unchanged.
+async fn assert_delivered(from: (&Site, &Node), to: (&Site, &mut Node), payload: &[u8]) {
+    let packet = udp_packet(from.0.socket(1000), to.0.socket(2000), payload);
+    from.1.send(&packet).await;
+    assert_eq!(to.1.recv(TIMEOUT).await, Some(packet));
+}
+
+/// Datagrams seen by `tap` so far that match `filter`.
+fn count(
+    tap: &mut mpsc::UnboundedReceiver<(SocketAddr, SocketAddr, Vec<u8>)>,
+    filter: impl Fn(&SocketAddr, &[u8]) -> bool,
+) -> usize {
+    let mut count = 0;
+    while let Ok((from, _, datagram)) = tap.try_recv() {
+        if filter(&from, &datagram) {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Wait until the instance behind `control` runs.
+async fn running(control: &Control) {
+    tokio::time::timeout(TIMEOUT, async {
+        while control.peers().await.is_err() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
@@ -0,0 +1,56 @@
+//! A hub relaying packets between its peers.
+
+use std::time::Duration;
+
+use super::{assert_delivered, udp_packet, Harness, Node, Site};
+
+/// A hub relaying with `relay`, with two spokes that route the whole
+/// subnet through it. Each spoke's `[Peer]` section on the hub gets the
+/// matching extra lines.
+fn hub(harness: &Harness, relay: &str, spokes: [&str; 2]) -> [(Site, Node); 3] {
+    let hub = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let a = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let b = Site::new("10.0.0.3", "192.0.2.3:51820");
+    let mut hub_config =
+        hub.config(&[&a, &b], None)
+            .replacen("\n\n", &format!("\nRelay = {relay}\n\n"), 1);
+    for (spoke, extra) in [&a, &b].into_iter().zip(spokes) {
+        let endpoint = format!("Endpoint = {}\n", spoke.endpoint);
+        hub_config = hub_config.replace(&endpoint, &format!("{endpoint}{extra}\n"));
+    }
+    let spoke_config = |spoke: &Site| {
+        spoke
+            .config(&[&hub], None)
+            .replace("10.0.0.1/32", "10.0.0.0/24")
+    };
+    let hub_node = harness.start(&hub_config, hub.endpoint);
+    let a_node = harness.start(&spoke_config(&a), a.endpoint);
+    let b_node = harness.start(&spoke_config(&b), b.endpoint);
+    [(hub, hub_node), (a, a_node), (b, b_node)]
+}
+
+#[tokio::test]
+async fn hub_relays_between_spokes() {
+    let harness = Harness::new();
+    let [(_, mut hub_node), (a, a_node), (b, mut b_node)] = hub(&harness, "all", ["", ""]);
+
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"via hub").await;
+    assert_eq!(hub_node.recv(Duration::from_millis(200)).await, None);
+}
+
+#[tokio::test]
+async fn hub_keeps_relay_groups_apart() {
+    let harness = Harness::new();
+    let [(hub, mut hub_node), (a, a_node), (b, mut b_node)] = hub(
+        &harness,
+        "groups",
+        ["RelayGroups = office", "RelayGroups = guests"],
+    );
+
+    // The spoke still reaches the hub itself
+    assert_delivered((&a, &a_node), (&hub, &mut hub_node), b"to hub").await;
+    let packet = udp_packet(a.socket(1000), b.socket(2000), b"blocked");
+    a_node.send(&packet).await;
+    assert_eq!(b_node.recv(Duration::from_millis(500)).await, None);
+    assert_eq!(hub_node.recv(Duration::from_millis(200)).await, None);
+}
@@ -0,0 +1,105 @@
+//! Tunnels over the transports other than plain UDP.
+
+use super::{assert_delivered, count, Harness, Site};
+use crate::proxy::tests::{stand_in, PASSWORD, USERNAME};
+
+#[tokio::test]
+async fn obfuscated_tunnel_hides_message_types() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let obfuscation = "Jc = 2\nJmin = 20\nJmax = 40\nS1 = 17\nS2 = 29\n\
+                       H1 = 91847\nH2 = 5530\nH3 = 771\nH4 = 1209\n";
+    let obfuscate = |config: String| config.replacen("\n\n", &format!("\n{obfuscation}\n"), 1);
+    let a_node = harness.start(&obfuscate(a.config(&[&b], None)), a.endpoint);
+    let mut b_node = harness.start(&obfuscate(b.config(&[&a], None)), b.endpoint);
+
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+    let plain = count(&mut tap, |_, datagram| {
+        (1..=4).contains(&datagram[0]) && datagram[1..4] == [0, 0, 0]
+    });
+    assert_eq!(plain, 0);
+}
+
+#[tokio::test]
+async fn peers_connect_over_tcp() {
+    let harness = Harness::new();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let server = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let client = Site::new("10.0.0.2", "192.0.2.2:51820");
+
+    // The server knows no endpoint, it answers whoever connects
+    let server_config = server
+        .config(&[&client], None)
+        .replace(&format!("Endpoint = {}\n", client.endpoint), "")
+        .replacen(
+            "\n\n",
+            &format!("\nListenAddress = 127.0.0.1\nTcpListenPort = {port}\n\n"),
+            1,
+        );
+    let client_config = client.config(&[&server], None).replace(
+        &format!("Endpoint = {}", server.endpoint),
+        &format!("Endpoint = 127.0.0.1:{port}\nTransport = tcp"),
+    );
+    let mut server_node = harness.start(&server_config, server.endpoint);
+    let mut client_node = harness.start(&client_config, client.endpoint);
+
+    let mut tap = harness.network.tap();
+    assert_delivered(
+        (&client, &client_node),
+        (&server, &mut server_node),
+        b"ping",
+    )
+    .await;
+    assert_delivered(
+        (&server, &server_node),
+        (&client, &mut client_node),
+        b"pong",
+    )
+    .await;
+    assert_eq!(count(&mut tap, |_, _| true), 0);
+}
+
+#[tokio::test]
+async fn peers_connect_through_a_socks5_proxy() {
+    let harness = Harness::new();
+    let server = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let client = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let relay = harness.network.attach("192.0.2.9:1080".parse().unwrap());
+    let proxy = stand_in(relay, client.endpoint, true).await;
+
+    // The server only ever sees the proxy's relay
+    let server_config = server
+        .config(&[&client], None)
+        .replace(&format!("Endpoint = {}\n", client.endpoint), "");
+    let client_config = client.config(&[&server], None).replacen(
+        "\n\n",
+        &format!("\nProxy = socks5://{USERNAME}:{PASSWORD}@{proxy}\n\n"),
+        1,
+    );
+    let mut server_node = harness.start(&server_config, server.endpoint);
+    let mut client_node = harness.start(&client_config, client.endpoint);
+
+    let mut tap = harness.network.tap();
+    assert_delivered(
+        (&client, &client_node),
+        (&server, &mut server_node),
+        b"ping",
+    )
+    .await;
+    assert_delivered(
+        (&server, &server_node),
+        (&client, &mut client_node),
+        b"pong",
+    )
+    .await;
+    while let Ok((from, to, _)) = tap.try_recv() {
+        assert!(from != client.endpoint || to != server.endpoint);
+        assert!(from != server.endpoint || to != client.endpoint);
+    }
+}
@@ -0,0 +1,96 @@
+//! Packets crossing a tunnel between two instances.
+
+use std::time::Duration;
+
+use super::{
+    assert_delivered, count, pair, udp_packet, Harness, Site, DATA, HANDSHAKE_INIT, KEEPALIVE_LEN,
+};
+use crate::peer::PeerState;
+
+#[tokio::test]
+async fn packets_cross_after_handshake() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let (a, b, mut a_node, mut b_node) = pair(&harness, None);
+
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+    assert_delivered((&b, &b_node), (&a, &mut a_node), b"pong").await;
+    assert_eq!(
+        count(&mut tap, |_, datagram| datagram[0] == HANDSHAKE_INIT),
+        1
+    );
+}
+
+#[tokio::test]
+async fn packets_are_routed_by_allowed_ips() {
+    let harness = Harness::new();
+    let hub = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let a = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let b = Site::new("10.0.0.3", "192.0.2.3:51820");
+    let hub_node = harness.start(&hub.config(&[&a, &b], None), hub.endpoint);
+    let mut a_node = harness.start(&a.config(&[&hub], None), a.endpoint);
+    let mut b_node = harness.start(&b.config(&[&hub], None), b.endpoint);
+
+    assert_delivered((&hub, &hub_node), (&b, &mut b_node), b"to b").await;
+    assert_delivered((&hub, &hub_node), (&a, &mut a_node), b"to a").await;
+    assert_eq!(b_node.recv(Duration::from_millis(200)).await, None);
+
+    // Nothing routes to an address outside every peer's allowed IPs
+    let stray = udp_packet(hub.socket(1000), "10.0.0.9:2000".parse().unwrap(), b"x");
+    hub_node.send(&stray).await;
+    assert_eq!(a_node.recv(Duration::from_millis(500)).await, None);
+}
+
+#[tokio::test]
+async fn persistent_keepalive_is_sent_while_idle() {
+    let harness = Harness::new();
+    let (a, b, a_node, mut b_node) = pair(&harness, Some(1));
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+
+    let mut tap = harness.network.tap();
+    tokio::time::sleep(Duration::from_millis(3500)).await;
+    let keepalives = count(&mut tap, |from, datagram| {
+        *from == a.endpoint && datagram[0] == DATA && datagram.len() == KEEPALIVE_LEN
+    });
+    assert!(keepalives >= 2, "{keepalives} keepalives");
+}
+
+#[tokio::test]
+async fn state_changes_are_reported() {
+    let harness = Harness::new();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let mut events = None;
+    let a_node = harness.start_with(&a.config(&[&b], None), a.endpoint, |wg| {
+        events = Some(wg.subscribe());
+    });
+    let mut events = events.unwrap();
+    let mut b_node = harness.start(&b.config(&[&a], None), b.endpoint);
+
+    assert_delivered((&a, &a_node), (&b, &mut b_node), b"ping").await;
+    let mut states = vec![];
+    while let Ok(event) = events.try_recv() {
+        assert_eq!(event.status.endpoint, Some(b.endpoint));
+        states.push(event.status.state);
+    }
+    assert_eq!(states, [PeerState::Handshaking, PeerState::Established]);
+}
+
+#[tokio::test]
+async fn peer_without_endpoint_is_not_contacted() {
+    let harness = Harness::new();
+    let mut tap = harness.network.tap();
+    let a = Site::new("10.0.0.1", "192.0.2.1:51820");
+    let b = Site::new("10.0.0.2", "192.0.2.2:51820");
+    let a_config = a
+        .config(&[&b], Some(1))
+        .replace(&format!("Endpoint = {}\n", b.endpoint), "");
+    let a_node = harness.start(&a_config, a.endpoint);
+    let _b_node = harness.start(&b.config(&[&a], None), b.endpoint);
+
+    a_node
+        .send(&udp_packet(a.socket(1000), b.socket(2000), b"ping"))
+        .await;
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(count(&mut tap, |from, _| *from == a.endpoint), 0);
+}
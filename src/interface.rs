@@ -1,15 +1,39 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, path::PathBuf};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use boringtun::x25519::StaticSecret;
 
-use crate::utils::{decode_private_key, parse_address, parse_dns};
+use crate::{
+    obfuscation::Obfuscation,
+    proxy::Proxy,
+    relay::RelayPolicy,
+    shaper::parse_rate,
+    utils::{decode_private_key, parse_address, parse_dns},
+};
 
-pub(crate) struct Interface {
+pub struct Interface {
     pub private_key: Option<StaticSecret>,
     pub address: Option<(IpAddr, u8)>,
     pub dns: Option<Vec<IpAddr>>,
     pub listen_port: Option<u16>,
+    pub listen_address: Option<IpAddr>,
+    pub bind_interface: Option<String>,
+    pub offload: bool,
+    pub resolve_interval: Option<u64>,
+    pub obfuscation: Obfuscation,
+    pub tcp_listen_port: Option<u16>,
+    pub proxy: Option<Proxy>,
+    pub relay: RelayPolicy,
+    /// A rendezvous server, `host:port`.
+    pub rendezvous: Option<String>,
+    /// STUN servers, `host:port`.
+    pub stun_servers: Vec<String>,
+    /// Where the data usage of peers is kept across restarts.
+    pub state_file: Option<PathBuf>,
+    /// Bytes per second to all peers together.
+    pub send_rate: Option<u64>,
+    /// Bytes per second from all peers together.
+    pub receive_rate: Option<u64>,
 }
 
 impl Interface {
@@ -19,6 +43,19 @@ impl Interface {
             address: None,
             dns: None,
             listen_port: None,
+            listen_address: None,
+            bind_interface: None,
+            offload: false,
+            resolve_interval: None,
+            obfuscation: Obfuscation::new(),
+            tcp_listen_port: None,
+            proxy: None,
+            relay: RelayPolicy::Off,
+            rendezvous: None,
+            stun_servers: vec![],
+            state_file: None,
+            send_rate: None,
+            receive_rate: None,
         };
         Ok(interface)
     }
@@ -36,7 +73,7 @@ impl Interface {
     pub fn set_dns(&mut self, dns: &[&str]) -> Result<()> {
         self.dns = Some(
             dns.iter()
-                .map(|item| parse_dns(&item))
+                .map(|item| parse_dns(item))
                 .collect::<Result<Vec<IpAddr>, anyhow::Error>>()?,
         );
         Ok(())
@@ -46,4 +83,97 @@ impl Interface {
         self.listen_port = Some(listen_port);
         Ok(())
     }
+
+    pub fn set_listen_address(&mut self, listen_address: &str) -> Result<()> {
+        self.listen_address = Some(
+            listen_address
+                .parse()
+                .map_err(|e| anyhow!("Parse listen address failed: {e}"))?,
+        );
+        Ok(())
+    }
+
+    pub fn set_bind_interface(&mut self, bind_interface: &str) -> Result<()> {
+        self.bind_interface = Some(bind_interface.to_string());
+        Ok(())
+    }
+
+    pub fn set_offload(&mut self, offload: bool) -> Result<()> {
+        self.offload = offload;
+        Ok(())
+    }
+
+    pub fn set_resolve_interval(&mut self, resolve_interval: u64) -> Result<()> {
+        self.resolve_interval = Some(resolve_interval);
+        Ok(())
+    }
+
+    /// Also accept peers over TCP on this port.
+    pub fn set_tcp_listen_port(&mut self, tcp_listen_port: u16) -> Result<()> {
+        self.tcp_listen_port = Some(tcp_listen_port);
+        Ok(())
+    }
+
+    /// Send the outer datagrams through a SOCKS5 proxy, see [`Proxy::parse`].
+    pub fn set_proxy(&mut self, proxy: &str) -> Result<()> {
+        self.proxy = Some(Proxy::parse(proxy)?);
+        Ok(())
+    }
+
+    /// Forward packets between peers in process, see [`RelayPolicy::parse`].
+    pub fn set_relay(&mut self, relay: &str) -> Result<()> {
+        self.relay = RelayPolicy::parse(relay)?;
+        Ok(())
+    }
+
+    /// Meet the peers through a rendezvous server, see [`crate::rendezvous`].
+    pub fn set_rendezvous(&mut self, rendezvous: &str) -> Result<()> {
+        if rendezvous
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse::<u16>().ok())
+            .is_none()
+        {
+            return Err(anyhow!("Rendezvous server needs a port: {rendezvous}"));
+        }
+        self.rendezvous = Some(rendezvous.to_string());
+        Ok(())
+    }
+
+    /// Learn the public address and NAT type from STUN servers, see
+    /// [`crate::stun`]. Two or more servers are needed for the NAT type.
+    pub fn set_stun_servers(&mut self, stun_servers: &[&str]) -> Result<()> {
+        for server in stun_servers {
+            if server
+                .rsplit_once(':')
+                .and_then(|(_, port)| port.parse::<u16>().ok())
+                .is_none()
+            {
+                return Err(anyhow!("STUN server needs a port: {server}"));
+            }
+        }
+        self.stun_servers = stun_servers.iter().map(|s| s.to_string()).collect();
+        Ok(())
+    }
+
+    /// Set one of the obfuscation keys, see [`Obfuscation::set`].
+    pub fn set_obfuscation(&mut self, key: &str, value: &str) -> Result<()> {
+        self.obfuscation.set(key, value)
+    }
+
+    pub fn set_state_file(&mut self, state_file: &str) -> Result<()> {
+        self.state_file = Some(PathBuf::from(state_file));
+        Ok(())
+    }
+
+    /// See [`parse_rate`].
+    pub fn set_send_rate(&mut self, send_rate: &str) -> Result<()> {
+        self.send_rate = Some(parse_rate(send_rate)?);
+        Ok(())
+    }
+
+    /// See [`parse_rate`].
+    pub fn set_receive_rate(&mut self, receive_rate: &str) -> Result<()> {
+        self.receive_rate = Some(parse_rate(receive_rate)?);
+        Ok(())
+    }
 }
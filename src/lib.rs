@@ -0,0 +1,31 @@
+pub mod buffer;
+pub mod control;
+pub mod device;
+pub mod forward;
+#[cfg(test)]
+mod harness;
+pub mod interface;
+pub mod limiter;
+pub mod netstack;
+pub mod obfuscation;
+pub mod pcap;
+pub mod peer;
+pub mod proxy;
+pub mod quota;
+pub mod relay;
+pub mod rendezvous;
+pub mod resolver;
+mod runtime;
+pub mod shaper;
+pub mod socks5;
+pub mod stun;
+pub mod tcp;
+pub mod transport;
+pub mod tun;
+pub mod udp;
+mod utils;
+pub mod wireguard;
+pub mod worker;
+
+pub use wireguard::WireGuard;
+pub use worker::default_workers;
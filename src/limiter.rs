@@ -0,0 +1,270 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use boringtun::{
+    noise::{handshake::parse_handshake_anon, rate_limiter::RateLimiter, Packet, TunnResult},
+    x25519::{PublicKey, StaticSecret},
+};
+
+use crate::udp::SendBatch;
+
+/// Handshakes per second the interface accepts before it asks initiators to
+/// prove their address with a cookie.
+pub const HANDSHAKE_RATE_LIMIT: u64 = 100;
+
+const HANDSHAKE_INIT: u8 = 1;
+pub(crate) const HANDSHAKE_RESPONSE: u8 = 2;
+
+/// Counters of the handshake flood protection.
+#[derive(Default)]
+pub struct HandshakeStats {
+    cookie_replies: AtomicU64,
+    rejected_handshakes: AtomicU64,
+}
+
+impl HandshakeStats {
+    /// Cookie replies sent instead of answering a handshake.
+    pub fn cookie_replies(&self) -> u64 {
+        self.cookie_replies.load(Ordering::Relaxed)
+    }
+
+    /// Handshake messages dropped, because of a bad MAC, because they came
+    /// under load without a cookie, or because no peer matched them.
+    pub fn rejected_handshakes(&self) -> u64 {
+        self.rejected_handshakes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn count_cookie_reply(&self) {
+        self.cookie_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_rejected_handshake(&self) {
+        self.rejected_handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The rate limiter of an interface. The receive task counts every
+/// handshake message here exactly once, before any peer sees it, so a flood
+/// of handshakes puts the whole interface under load, not just one peer.
+pub(crate) struct HandshakeLimiter {
+    rate_limiter: Arc<RateLimiter>,
+    session_limiter: Arc<RateLimiter>,
+    stats: Arc<HandshakeStats>,
+    private_key: StaticSecret,
+    public_key: PublicKey,
+}
+
+impl HandshakeLimiter {
+    pub fn new(private_key: &StaticSecret, limit: u64, stats: Arc<HandshakeStats>) -> Self {
+        let public_key = PublicKey::from(private_key);
+        Self {
+            rate_limiter: Arc::new(RateLimiter::new(&public_key, limit)),
+            session_limiter: Arc::new(RateLimiter::new(&public_key, u64::MAX)),
+            stats,
+            private_key: private_key.clone(),
+            public_key,
+        }
+    }
+
+    /// The limiter for the sessions of the peers. It never gets under load,
+    /// what reaches a session was counted by this one already, so the
+    /// session only checks the MACs again.
+    pub fn session_limiter(&self) -> Arc<RateLimiter> {
+        self.session_limiter.clone()
+    }
+
+    /// Start a new counting period, at most once a second.
+    pub fn reset(&self) {
+        self.rate_limiter.reset_count();
+    }
+
+    /// Count a datagram from an endpoint that belongs to a peer, if it is a
+    /// handshake. Under load one without a valid cookie gets a cookie reply,
+    /// queued to `batch`. Returns whether the datagram goes on to the peer.
+    pub fn check(
+        &self,
+        src: &[u8],
+        endpoint: SocketAddr,
+        local: Option<IpAddr>,
+        batch: &mut SendBatch,
+    ) -> bool {
+        !is_handshake(src) || self.verify(src, endpoint, local, batch).is_some()
+    }
+
+    /// Handle a datagram from an endpoint that belongs to no peer. Under
+    /// load a handshake gets a cookie reply, queued to `batch`. Otherwise an
+    /// initiation names the peer it claims to be from, which `lookup` turns
+    /// into the peer to hand it to. Any other handshake is dropped.
+    ///
+    /// Naming the peer takes an X25519 with the interface key on the
+    /// receive task, which the peer's session repeats. The rate limit
+    /// bounds how many of them a flood can cause.
+    pub fn handle_unknown<T>(
+        &self,
+        src: &[u8],
+        endpoint: SocketAddr,
+        local: Option<IpAddr>,
+        batch: &mut SendBatch,
+        lookup: impl FnOnce(&PublicKey) -> Option<T>,
+    ) -> Option<T> {
+        if !is_handshake(src) {
+            return None;
+        }
+        let found = match self.verify(src, endpoint, local, batch)? {
+            // Only the peer's own session can tell whether it is genuine
+            Packet::HandshakeInit(init) => {
+                parse_handshake_anon(&self.private_key, &self.public_key, &init)
+                    .ok()
+                    .and_then(|half| lookup(&PublicKey::from(half.peer_static_public)))
+            }
+            _ => None,
+        };
+        if found.is_none() {
+            self.stats.count_rejected_handshake();
+        }
+        found
+    }
+
+    /// Count the handshake `src` and check its MACs, and its cookie under
+    /// load. Returns it if it passed, otherwise it was answered with a
+    /// cookie reply or counted as rejected.
+    fn verify<'a>(
+        &self,
+        src: &'a [u8],
+        endpoint: SocketAddr,
+        local: Option<IpAddr>,
+        batch: &mut SendBatch,
+    ) -> Option<Packet<'a>> {
+        match self
+            .rate_limiter
+            .verify_packet(Some(endpoint.ip()), src, batch.slot())
+        {
+            Ok(packet) => Some(packet),
+            Err(TunnResult::WriteToNetwork(cookie)) => {
+                let len = cookie.len();
+                batch.commit(len, endpoint, local);
+                self.stats.count_cookie_reply();
+                None
+            }
+            Err(_) => {
+                self.stats.count_rejected_handshake();
+                None
+            }
+        }
+    }
+}
+
+fn is_handshake(src: &[u8]) -> bool {
+    matches!(
+        src.first(),
+        Some(&HANDSHAKE_INIT) | Some(&HANDSHAKE_RESPONSE)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use boringtun::{
+        noise::{Tunn, TunnResult},
+        x25519::{PublicKey, StaticSecret},
+    };
+
+    use super::{HandshakeLimiter, HandshakeStats, HANDSHAKE_RESPONSE};
+    use crate::udp::SendBatch;
+
+    const COOKIE_REPLY: u8 = 3;
+
+    fn secret(byte: u8) -> StaticSecret {
+        StaticSecret::from([byte; 32])
+    }
+
+    fn initiation(responder: &PublicKey) -> Vec<u8> {
+        let mut tunn = Tunn::new(secret(2), *responder, None, None, 1, None).unwrap();
+        let mut buf = vec![0u8; 256];
+        match tunn.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            other => panic!("Unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_handshakes_get_cookies_only_under_load() {
+        let public_key = PublicKey::from(&secret(1));
+        let init = initiation(&public_key);
+        let endpoint = "192.0.2.1:51820".parse().unwrap();
+        let unknown = |_: &PublicKey| None::<()>;
+
+        let stats = Arc::new(HandshakeStats::default());
+        let limiter = HandshakeLimiter::new(&secret(1), 1, stats.clone());
+        let mut batch = SendBatch::new();
+        limiter.handle_unknown(&init, endpoint, None, &mut batch, unknown);
+        assert!(batch.is_empty());
+        assert_eq!(stats.rejected_handshakes(), 1);
+
+        limiter.handle_unknown(&init, endpoint, None, &mut batch, unknown);
+        let packets = batch.packets().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].0[0], COOKIE_REPLY);
+        assert_eq!(packets[0].1, endpoint);
+        assert_eq!(stats.cookie_replies(), 1);
+
+        // A message for another interface fails the MAC check
+        let other = initiation(&PublicKey::from(&secret(3)));
+        limiter.handle_unknown(&other, endpoint, None, &mut batch, unknown);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(stats.rejected_handshakes(), 2);
+    }
+
+    #[test]
+    fn sessions_do_not_count_handshakes_again() {
+        let init = initiation(&PublicKey::from(&secret(1)));
+        let endpoint = "192.0.2.1:51820".parse().unwrap();
+        let stats = Arc::new(HandshakeStats::default());
+        let limiter = HandshakeLimiter::new(&secret(1), 1, stats.clone());
+        let mut batch = SendBatch::new();
+        assert!(limiter.check(&init, endpoint, None, &mut batch));
+
+        // The session answers, though the limit of one is reached
+        let mut session = Tunn::new(
+            secret(1),
+            PublicKey::from(&secret(2)),
+            None,
+            None,
+            1,
+            Some(limiter.session_limiter()),
+        )
+        .unwrap();
+        let mut buf = vec![0u8; 256];
+        match session.decapsulate(Some(endpoint.ip()), &init, &mut buf) {
+            TunnResult::WriteToNetwork(packet) => assert_eq!(packet[0], HANDSHAKE_RESPONSE),
+            other => panic!("Unexpected result: {other:?}"),
+        }
+
+        // The next handshake is over it
+        assert!(!limiter.check(&init, endpoint, None, &mut batch));
+        assert_eq!(batch.packets().next().unwrap().0[0], COOKIE_REPLY);
+        assert_eq!(stats.cookie_replies(), 1);
+    }
+
+    #[test]
+    fn unknown_initiations_name_their_peer() {
+        let init = initiation(&PublicKey::from(&secret(1)));
+        let endpoint = "192.0.2.1:51820".parse().unwrap();
+        let stats = Arc::new(HandshakeStats::default());
+        let limiter = HandshakeLimiter::new(&secret(1), 10, stats.clone());
+        let mut batch = SendBatch::new();
+
+        let initiator = PublicKey::from(&secret(2));
+        let found = limiter.handle_unknown(&init, endpoint, None, &mut batch, |key| {
+            (*key == initiator).then_some("initiator")
+        });
+        assert_eq!(found, Some("initiator"));
+        assert_eq!(stats.rejected_handshakes(), 0);
+    }
+}
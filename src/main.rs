@@ -1,31 +1,160 @@
-use std::fs;
+use std::{fs, net::SocketAddr, path::PathBuf, sync::Arc};
 
 use anyhow::{anyhow, Result};
-use wireguard::WireGuard;
-
-mod interface;
-mod peer;
-mod utils;
-mod wireguard;
-
+use base64::{engine::general_purpose, Engine};
+use serde_json::Value;
 use structopt::StructOpt;
+use tokio::sync::broadcast;
+use wireguard::{default_workers, pcap::PcapReplay, rendezvous, udp::OuterSocket, WireGuard};
 
 #[derive(StructOpt)]
 #[structopt(name = "wireguard", about = "A user-space implementation of WireGuard")]
 struct Opt {
+    /// Configuration file, needed unless a subcommand is given
     #[structopt(short = "c", long = "config")]
-    config_file: String,
+    config_file: Option<String>,
+
+    /// Number of packet processing workers, defaults to the number of CPUs
+    #[structopt(short = "w", long = "workers")]
+    workers: Option<usize>,
+
+    /// Replay the IP packets of a pcap file instead of using a tun device
+    #[structopt(long = "replay")]
+    replay: Option<String>,
+
+    /// Serve the JSON control API on this unix socket
+    #[structopt(long = "control", parse(from_os_str))]
+    control: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main(worker_threads = 1)]
-async fn main() -> Result<()> {
+#[derive(StructOpt)]
+enum Command {
+    /// Run a rendezvous server, which introduces peers behind NATs
+    Rendezvous {
+        /// Address to listen on
+        #[structopt(short = "l", long = "listen", default_value = "0.0.0.0:51821")]
+        listen: SocketAddr,
+    },
+    /// Show the interface and its peers, through the `--control` socket
+    Status,
+}
+
+fn main() -> Result<()> {
     let opt = Opt::from_args();
-    let content = fs::read_to_string(opt.config_file)
-        .map_err(|e| anyhow!("Read config file content failed: {e}"))?;
-    let mut wg =
-        WireGuard::from_content(&content).map_err(|e| anyhow!("Create wireguard failed: {e}"))?;
-    wg.run()
-        .await
-        .map_err(|e| anyhow!("WireGuard run failed: {e}"))?;
+    let workers = opt.workers.unwrap_or_else(default_workers).max(1);
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .enable_all()
+        .build()
+        .map_err(|e| anyhow!("Create tokio runtime failed: {e}"))?;
+
+    runtime.block_on(async {
+        if let Some(Command::Rendezvous { listen }) = opt.command {
+            let socket = OuterSocket::listen(Some(listen.ip()), listen.port(), None)
+                .map_err(|e| anyhow!("Rendezvous bind {listen} failed: {e}"))?;
+            println!("Rendezvous server listening on {listen}");
+            rendezvous::serve(Arc::new(socket))
+                .await
+                .map_err(|e| anyhow!("Rendezvous server failed: {e}"))?;
+            return Ok(());
+        }
+        if let Some(Command::Status) = opt.command {
+            let path = opt
+                .control
+                .ok_or(anyhow!("Missing control socket, pass it with --control"))?;
+            return status(&path).await;
+        }
+        let config_file = opt
+            .config_file
+            .ok_or(anyhow!("Missing config file, pass it with -c"))?;
+        let content = fs::read_to_string(&config_file)
+            .map_err(|e| anyhow!("Read config file content failed: {e}"))?;
+        let mut wg = WireGuard::from_content(&content)
+            .map_err(|e| anyhow!("Create wireguard failed: {e}"))?;
+        wg.set_workers(workers)?;
+        wg.control().set_config_file(PathBuf::from(config_file));
+        if let Some(replay) = opt.replay {
+            let device = PcapReplay::open(&replay)
+                .map_err(|e| anyhow!("Open pcap file {replay} failed: {e}"))?;
+            wg.set_device(Arc::new(device))?;
+        }
+        let mut events = wg.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let public_key = event
+                    .public_key
+                    .map(|key| general_purpose::STANDARD.encode(key.as_bytes()))
+                    .unwrap_or_default();
+                println!("Peer {public_key} is {:?}", event.status.state);
+            }
+        });
+        #[cfg(unix)]
+        if let Some(path) = &opt.control {
+            let listener = wireguard::control::Control::listen(path)?;
+            println!("Control API listening on {}", path.display());
+            tokio::spawn(wg.control().serve(listener));
+        }
+        #[cfg(not(unix))]
+        if opt.control.is_some() {
+            return Err(anyhow!("The control API needs unix sockets"));
+        }
+        let result = wg.run().await;
+        if let Some(path) = &opt.control {
+            let _ = fs::remove_file(path);
+        }
+        result.map_err(|e| anyhow!("WireGuard run failed: {e}"))?;
+        Ok(())
+    })
+}
+
+#[cfg(unix)]
+async fn status(path: &std::path::Path) -> Result<()> {
+    let status = wireguard::control::call(path, "status", Value::Null).await?;
+    println!("interface");
+    println!("  cookie replies: {}", status["cookie_replies"]);
+    println!("  rejected handshakes: {}", status["rejected_handshakes"]);
+    if let Some(address) = status["stun"]["mapped_address"].as_str() {
+        println!("  public address: {address}");
+        println!(
+            "  nat type: {}",
+            status["stun"]["nat_type"].as_str().unwrap_or_default()
+        );
+    }
+    for peer in status["peers"].as_array().into_iter().flatten() {
+        println!();
+        println!("peer: {}", peer["public_key"].as_str().unwrap_or_default());
+        if let Some(endpoint) = peer["endpoint"].as_str() {
+            println!("  endpoint: {endpoint}");
+        }
+        let allowed_ips = peer["allowed_ips"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>();
+        println!("  allowed ips: {}", allowed_ips.join(", "));
+        println!("  state: {}", peer["state"].as_str().unwrap_or_default());
+        if let Some(secs) = peer["last_handshake_secs"].as_u64() {
+            println!("  latest handshake: {secs} seconds ago");
+        }
+        println!(
+            "  transfer: {} B received, {} B sent",
+            peer["rx_bytes"], peer["tx_bytes"]
+        );
+    }
     Ok(())
 }
+
+#[cfg(not(unix))]
+async fn status(_path: &std::path::Path) -> Result<()> {
+    Err(anyhow!("The control API needs unix sockets"))
+}
@@ -0,0 +1,765 @@
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, DeviceCapabilities, Medium},
+    socket::{dns, tcp, udp},
+    time::Instant,
+    wire::{DnsQueryType, HardwareAddress, IpAddress, IpCidr, IpEndpoint},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, Notify},
+};
+
+use crate::{
+    device::Device,
+    tun::{TunBatch, TunReader, TUN_HEADROOM},
+};
+
+/// MTU of the stack, leaving room for the WireGuard overhead on a 1500 byte
+/// outer link.
+pub const NETSTACK_MTU: usize = 1420;
+
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_PACKETS: usize = 64;
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+const DNS_QUERIES: usize = 16;
+/// Pending SYNs a listener can take before the next accept.
+const LISTEN_BACKLOG: usize = 8;
+/// Decrypted packets waiting for the stack beyond this are dropped.
+const RX_QUEUE_LIMIT: usize = 1024;
+const OUTBOUND_QUEUE_DEPTH: usize = 1024;
+const MAX_POLL_DELAY: Duration = Duration::from_millis(100);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const TCP_TIMEOUT: Duration = Duration::from_secs(60);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(25);
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// A userspace TCP/IP stack standing in for the tun device, so the tunnel
+/// can be used without privileges. Decrypted packets are fed in with
+/// [`Self::send`], packets to encrypt come out of [`Self::recv`], and
+/// [`Self::run`] drives the stack.
+pub struct Netstack {
+    shared: Arc<Shared>,
+    outbound_tx: mpsc::Sender<Vec<u8>>,
+    outbound_rx: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+struct Shared {
+    stack: Mutex<Stack>,
+    notify: Notify,
+}
+
+struct Stack {
+    iface: Interface,
+    device: QueueDevice,
+    sockets: SocketSet<'static>,
+    dns: Option<SocketHandle>,
+    closing: Vec<SocketHandle>,
+    next_port: u16,
+}
+
+impl Netstack {
+    pub fn new(address: IpAddr, prefix: u8, dns: &[IpAddr]) -> Result<Self> {
+        let address = match address {
+            IpAddr::V4(address) => address,
+            IpAddr::V6(_) => return Err(anyhow!("Netstack only supports an IPv4 address")),
+        };
+
+        let mut device = QueueDevice::default();
+        let mut iface = Interface::new(
+            Config::new(HardwareAddress::Ip),
+            &mut device,
+            Instant::now(),
+        );
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(IpAddress::Ipv4(address), prefix));
+        });
+        // Everything not on the interface subnet goes through the tunnel too
+        iface
+            .routes_mut()
+            .add_default_ipv4_route(address)
+            .map_err(|e| anyhow!("Add netstack route failed: {e}"))?;
+
+        let mut sockets = SocketSet::new(vec![]);
+        let servers = dns
+            .iter()
+            .filter_map(|server| match server {
+                IpAddr::V4(server) => Some(IpAddress::Ipv4(*server)),
+                IpAddr::V6(_) => None,
+            })
+            .take(4)
+            .collect::<Vec<_>>();
+        let dns = (!servers.is_empty()).then(|| {
+            let queries = (0..DNS_QUERIES).map(|_| None).collect::<Vec<_>>();
+            sockets.add(dns::Socket::new(&servers, queries))
+        });
+
+        let (outbound_tx, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE_DEPTH);
+        let port_range = EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start();
+        Ok(Self {
+            shared: Arc::new(Shared {
+                stack: Mutex::new(Stack {
+                    iface,
+                    device,
+                    sockets,
+                    dns,
+                    closing: vec![],
+                    next_port: EPHEMERAL_PORTS.start() + rand::random_range(0..=port_range),
+                }),
+                notify: Notify::new(),
+            }),
+            outbound_tx,
+            outbound_rx: tokio::sync::Mutex::new(outbound_rx),
+        })
+    }
+
+    /// Drive the stack: process received packets, timers and socket I/O.
+    pub async fn run(&self) {
+        loop {
+            let (packets, delay) = {
+                let mut stack = self.shared.lock();
+                let now = Instant::now();
+                stack.poll(now);
+                let packets = stack.device.tx.drain(..).collect::<Vec<_>>();
+                let Stack { iface, sockets, .. } = &mut *stack;
+                (packets, iface.poll_delay(now, sockets))
+            };
+            for packet in packets {
+                if self.outbound_tx.send(packet).await.is_err() {
+                    return;
+                }
+            }
+
+            let delay = delay
+                .map(|delay| Duration::from_micros(delay.total_micros()))
+                .unwrap_or(MAX_POLL_DELAY)
+                .min(MAX_POLL_DELAY);
+            tokio::select! {
+                _ = self.shared.notify.notified() => {}
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+
+    /// Hand a decrypted packet to the stack.
+    pub fn send(&self, packet: &[u8]) {
+        {
+            let mut stack = self.shared.lock();
+            if stack.device.rx.len() >= RX_QUEUE_LIMIT {
+                return;
+            }
+            stack.device.rx.push_back(packet.to_vec());
+        }
+        self.shared.notify.notify_one();
+    }
+
+    /// The next packet sent by the stack, to be encrypted.
+    pub async fn recv(&self) -> Option<Vec<u8>> {
+        self.outbound_rx.lock().await.recv().await
+    }
+
+    /// Open a TCP connection to `remote` through the tunnel.
+    pub async fn connect(&self, remote: SocketAddr) -> io::Result<TcpStream> {
+        let handle = {
+            let mut stack = self.shared.lock();
+            let port = stack.ephemeral_port();
+            let mut socket = new_tcp_socket();
+            let Stack { iface, sockets, .. } = &mut *stack;
+            socket
+                .connect(iface.context(), to_endpoint(remote)?, port)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            sockets.add(socket)
+        };
+        self.shared.notify.notify_one();
+
+        let stream = TcpStream {
+            shared: self.shared.clone(),
+            handle,
+        };
+        tokio::time::timeout(CONNECT_TIMEOUT, stream.established())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        Ok(stream)
+    }
+
+    /// Accept TCP connections to `port` of the stack address.
+    pub fn listen(&self, port: u16) -> io::Result<TcpListener> {
+        let mut stack = self.shared.lock();
+        let handles = (0..LISTEN_BACKLOG)
+            .map(|_| stack.listen_socket(port))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(TcpListener {
+            shared: self.shared.clone(),
+            port,
+            handles,
+        })
+    }
+
+    /// A UDP socket on the stack, on `port` or an ephemeral port.
+    pub fn bind_udp(&self, port: Option<u16>) -> io::Result<UdpSocket> {
+        let mut stack = self.shared.lock();
+        let port = match port {
+            Some(port) => port,
+            None => stack.ephemeral_port(),
+        };
+        let mut socket = udp::Socket::new(
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; UDP_PACKETS],
+                vec![0; UDP_BUFFER_SIZE],
+            ),
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; UDP_PACKETS],
+                vec![0; UDP_BUFFER_SIZE],
+            ),
+        );
+        socket
+            .bind(port)
+            .map_err(|e| io::Error::new(io::ErrorKind::AddrInUse, e.to_string()))?;
+        let handle = stack.sockets.add(socket);
+        Ok(UdpSocket {
+            shared: self.shared.clone(),
+            handle,
+            port,
+        })
+    }
+
+    pub fn has_dns(&self) -> bool {
+        self.shared.lock().dns.is_some()
+    }
+
+    /// Look `name` up with the interface DNS servers, through the tunnel.
+    pub async fn resolve(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        let (dns, query) = {
+            let mut stack = self.shared.lock();
+            let dns = stack
+                .dns
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No DNS server"))?;
+            let Stack { iface, sockets, .. } = &mut *stack;
+            let query = sockets
+                .get_mut::<dns::Socket>(dns)
+                .start_query(iface.context(), name, DnsQueryType::A)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            (dns, query)
+        };
+        self.shared.notify.notify_one();
+
+        let mut pending = PendingQuery {
+            shared: &self.shared,
+            dns,
+            query: Some(query),
+        };
+        poll_fn(|cx| pending.poll(cx)).await
+    }
+
+    /// Resolve `name` to an IPv4 address, through the tunnel when it has DNS
+    /// servers and with the system resolver otherwise.
+    pub async fn lookup(&self, name: &str, port: u16) -> io::Result<SocketAddr> {
+        let addrs = if self.has_dns() {
+            self.resolve(name).await?
+        } else {
+            tokio::net::lookup_host((name, port))
+                .await?
+                .map(|addr| addr.ip())
+                .collect()
+        };
+        addrs
+            .into_iter()
+            .find(|addr| addr.is_ipv4())
+            .map(|addr| SocketAddr::new(addr, port))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{name} not found")))
+    }
+}
+
+#[async_trait]
+impl Device for Netstack {
+    fn name(&self) -> String {
+        "netstack".to_string()
+    }
+
+    fn mtu(&self) -> usize {
+        NETSTACK_MTU
+    }
+
+    async fn recv(&self, reader: &mut TunReader) -> io::Result<()> {
+        reader.clear();
+        let packet = Netstack::recv(self)
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        reader.push(&packet);
+        Ok(())
+    }
+
+    async fn send(&self, batch: &mut TunBatch) -> io::Result<()> {
+        for buf in batch.bufs_mut() {
+            Netstack::send(self, &buf[TUN_HEADROOM..]);
+        }
+        batch.clear();
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Stack> {
+        self.stack.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Stack {
+    fn poll(&mut self, now: Instant) {
+        let Stack {
+            iface,
+            device,
+            sockets,
+            closing,
+            ..
+        } = self;
+        iface.poll(now, device, sockets);
+
+        // Sockets of dropped streams are removed once they finished closing
+        closing.retain(|handle| {
+            let state = sockets.get::<tcp::Socket>(*handle).state();
+            if matches!(state, tcp::State::Closed | tcp::State::TimeWait) {
+                sockets.remove(*handle);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn ephemeral_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = if port == *EPHEMERAL_PORTS.end() {
+            *EPHEMERAL_PORTS.start()
+        } else {
+            port + 1
+        };
+        port
+    }
+
+    fn listen_socket(&mut self, port: u16) -> io::Result<SocketHandle> {
+        let mut socket = new_tcp_socket();
+        socket
+            .listen(port)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(self.sockets.add(socket))
+    }
+}
+
+fn new_tcp_socket() -> tcp::Socket<'static> {
+    let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    );
+    socket.set_timeout(Some(TCP_TIMEOUT.into()));
+    socket.set_keep_alive(Some(TCP_KEEPALIVE.into()));
+    socket
+}
+
+/// A TCP connection on the stack.
+pub struct TcpStream {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared
+            .lock()
+            .sockets
+            .get::<tcp::Socket>(self.handle)
+            .local_endpoint()
+            .map(to_socket_addr)
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.shared
+            .lock()
+            .sockets
+            .get::<tcp::Socket>(self.handle)
+            .remote_endpoint()
+            .map(to_socket_addr)
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    async fn established(&self) -> io::Result<()> {
+        poll_fn(|cx| {
+            let mut stack = self.shared.lock();
+            let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+            match socket.state() {
+                tcp::State::SynSent | tcp::State::SynReceived => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                tcp::State::Closed => Poll::Ready(Err(io::ErrorKind::ConnectionRefused.into())),
+                _ => Poll::Ready(Ok(())),
+            }
+        })
+        .await
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut stack = self.shared.lock();
+        let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+        if socket.can_recv() {
+            let len = socket
+                .recv_slice(buf.initialize_unfilled())
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            buf.advance(len);
+            drop(stack);
+            // The receive window opened up
+            self.shared.notify.notify_one();
+            return Poll::Ready(Ok(()));
+        }
+        if !socket.may_recv() {
+            return Poll::Ready(Ok(()));
+        }
+        socket.register_recv_waker(cx.waker());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut stack = self.shared.lock();
+        let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+        if socket.can_send() {
+            let len = socket
+                .send_slice(data)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            drop(stack);
+            self.shared.notify.notify_one();
+            return Poll::Ready(Ok(len));
+        }
+        if !socket.may_send() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        socket.register_send_waker(cx.waker());
+        Poll::Pending
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared
+            .lock()
+            .sockets
+            .get_mut::<tcp::Socket>(self.handle)
+            .close();
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut stack = self.shared.lock();
+        stack.sockets.get_mut::<tcp::Socket>(self.handle).close();
+        stack.closing.push(self.handle);
+        drop(stack);
+        self.shared.notify.notify_one();
+    }
+}
+
+/// Accepts TCP connections on a port of the stack address.
+pub struct TcpListener {
+    shared: Arc<Shared>,
+    port: u16,
+    handles: Vec<SocketHandle>,
+}
+
+impl TcpListener {
+    pub async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (index, remote) = poll_fn(|cx| {
+            let mut stack = self.shared.lock();
+            for (index, handle) in self.handles.iter().enumerate() {
+                let socket = stack.sockets.get_mut::<tcp::Socket>(*handle);
+                if socket.is_active() && socket.state() != tcp::State::SynReceived {
+                    let remote = socket.remote_endpoint().map(to_socket_addr);
+                    return Poll::Ready((index, remote));
+                }
+                socket.register_recv_waker(cx.waker());
+            }
+            Poll::Pending
+        })
+        .await;
+
+        // Keep the backlog full
+        let handle = self.shared.lock().listen_socket(self.port)?;
+        let stream = TcpStream {
+            shared: self.shared.clone(),
+            handle: std::mem::replace(&mut self.handles[index], handle),
+        };
+        let remote = remote.ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        Ok((stream, remote))
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut stack = self.shared.lock();
+        for handle in self.handles.drain(..) {
+            stack.sockets.remove(handle);
+        }
+    }
+}
+
+/// A UDP socket on the stack.
+pub struct UdpSocket {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+    port: u16,
+}
+
+impl UdpSocket {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn send_to(&self, data: &[u8], remote: SocketAddr) -> io::Result<()> {
+        poll_fn(|cx| {
+            let mut stack = self.shared.lock();
+            let socket = stack.sockets.get_mut::<udp::Socket>(self.handle);
+            if socket.can_send() {
+                socket
+                    .send_slice(data, to_endpoint(remote)?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+                drop(stack);
+                self.shared.notify.notify_one();
+                return Poll::Ready(Ok(()));
+            }
+            socket.register_send_waker(cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        poll_fn(|cx| {
+            let mut stack = self.shared.lock();
+            let socket = stack.sockets.get_mut::<udp::Socket>(self.handle);
+            if socket.can_recv() {
+                let (len, meta) = socket
+                    .recv_slice(buf)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                return Poll::Ready(Ok((len, to_socket_addr(meta.endpoint))));
+            }
+            socket.register_recv_waker(cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.shared.lock().sockets.remove(self.handle);
+    }
+}
+
+/// A DNS query, cancelled if dropped before it completes.
+struct PendingQuery<'a> {
+    shared: &'a Shared,
+    dns: SocketHandle,
+    query: Option<dns::QueryHandle>,
+}
+
+impl PendingQuery<'_> {
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Vec<IpAddr>>> {
+        let query = match self.query {
+            Some(query) => query,
+            None => return Poll::Ready(Err(io::ErrorKind::NotFound.into())),
+        };
+        let mut stack = self.shared.lock();
+        let socket = stack.sockets.get_mut::<dns::Socket>(self.dns);
+        match socket.get_query_result(query) {
+            Ok(addrs) => {
+                self.query = None;
+                Poll::Ready(Ok(addrs.iter().map(|addr| IpAddr::from(*addr)).collect()))
+            }
+            Err(dns::GetQueryResultError::Pending) => {
+                socket.register_query_waker(query, cx.waker());
+                Poll::Pending
+            }
+            Err(dns::GetQueryResultError::Failed) => {
+                self.query = None;
+                Poll::Ready(Err(io::ErrorKind::NotFound.into()))
+            }
+        }
+    }
+}
+
+impl Drop for PendingQuery<'_> {
+    fn drop(&mut self) {
+        if let Some(query) = self.query.take() {
+            self.shared
+                .lock()
+                .sockets
+                .get_mut::<dns::Socket>(self.dns)
+                .cancel_query(query);
+        }
+    }
+}
+
+fn to_endpoint(addr: SocketAddr) -> io::Result<IpEndpoint> {
+    match addr {
+        SocketAddr::V4(addr) => Ok(addr.into()),
+        SocketAddr::V6(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Netstack only supports IPv4",
+        )),
+    }
+}
+
+fn to_socket_addr(endpoint: IpEndpoint) -> SocketAddr {
+    SocketAddr::new(endpoint.addr.into(), endpoint.port)
+}
+
+/// The stack's side of the tunnel: packets queued in both directions.
+#[derive(Default)]
+struct QueueDevice {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+impl phy::Device for QueueDevice {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = NETSTACK_MTU;
+        capabilities
+    }
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::Netstack;
+
+    /// Two stacks on 10.0.0.1 and 10.0.0.2, wired back to back.
+    pub(crate) fn linked_pair() -> (Arc<Netstack>, Arc<Netstack>) {
+        let a = Arc::new(Netstack::new("10.0.0.1".parse().unwrap(), 24, &[]).unwrap());
+        let b = Arc::new(Netstack::new("10.0.0.2".parse().unwrap(), 24, &[]).unwrap());
+        for (from, to) in [(a.clone(), b.clone()), (b.clone(), a.clone())] {
+            let run = from.clone();
+            tokio::spawn(async move { run.run().await });
+            tokio::spawn(async move {
+                while let Some(packet) = from.recv().await {
+                    to.send(&packet);
+                }
+            });
+        }
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn tcp_connects_between_stacks() {
+        let (a, b) = linked_pair();
+        let mut listener = b.listen(80).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let mut stream = a.connect("10.0.0.2:80".parse().unwrap()).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), "10.0.0.2:80".parse().unwrap());
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn tcp_connect_to_closed_port_is_refused() {
+        let (a, _b) = linked_pair();
+        let result = a.connect("10.0.0.2:81".parse().unwrap()).await;
+        assert_eq!(
+            result.err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::ConnectionRefused)
+        );
+    }
+
+    #[tokio::test]
+    async fn udp_between_stacks() {
+        let (a, b) = linked_pair();
+        let server = b.bind_udp(Some(53)).unwrap();
+        let client = a.bind_udp(None).unwrap();
+
+        client
+            .send_to(b"ping", "10.0.0.2:53".parse().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, SocketAddr::from(([10, 0, 0, 1], client.port())));
+    }
+}
@@ -0,0 +1,378 @@
+use std::{io, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rand::{Rng, RngCore};
+use tokio::sync::Mutex;
+
+use crate::{
+    buffer::PacketBuffer,
+    rendezvous, stun,
+    transport::Transport,
+    udp::{RecvBatch, SendBatch},
+};
+
+const HANDSHAKE_INIT: u32 = 1;
+const HANDSHAKE_RESPONSE: u32 = 2;
+const COOKIE_REPLY: u32 = 3;
+const DATA: u32 = 4;
+
+const HANDSHAKE_INIT_LEN: usize = 148;
+const HANDSHAKE_RESPONSE_LEN: usize = 92;
+const COOKIE_REPLY_LEN: usize = 64;
+const DATA_MIN_LEN: usize = 32;
+
+/// Largest junk packet and largest padded handshake, so both fit the
+/// minimum IPv6 MTU.
+const MAX_DATAGRAM: usize = 1280;
+const MAX_JUNK_COUNT: usize = 128;
+
+/// Disguises WireGuard on the wire, the way AmneziaWG does: junk datagrams
+/// before each handshake initiation, random padding in front of handshake
+/// messages and other message type values. Both sides need the same
+/// settings. The defaults leave traffic unchanged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Obfuscation {
+    /// `Jc`, junk datagrams sent before each handshake initiation.
+    pub junk_count: usize,
+    /// `Jmin` and `Jmax`, the size range of junk datagrams.
+    pub junk_min: usize,
+    pub junk_max: usize,
+    /// `S1` and `S2`, random bytes in front of handshake initiations and
+    /// responses.
+    pub init_padding: usize,
+    pub response_padding: usize,
+    /// `H1` to `H4`, the type values of initiations, responses, cookie
+    /// replies and data messages.
+    pub headers: [u32; 4],
+}
+
+impl Default for Obfuscation {
+    fn default() -> Self {
+        Self {
+            junk_count: 0,
+            junk_min: 0,
+            junk_max: 0,
+            init_padding: 0,
+            response_padding: 0,
+            headers: [HANDSHAKE_INIT, HANDSHAKE_RESPONSE, COOKIE_REPLY, DATA],
+        }
+    }
+}
+
+impl Obfuscation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set one of the `[Interface]` keys `Jc`, `Jmin`, `Jmax`, `S1`, `S2`
+    /// and `H1` to `H4`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let parse = || {
+            value
+                .parse::<usize>()
+                .map_err(|e| anyhow!("Parse {key} failed: {e}"))
+        };
+        match key {
+            "Jc" => self.junk_count = parse()?,
+            "Jmin" => self.junk_min = parse()?,
+            "Jmax" => self.junk_max = parse()?,
+            "S1" => self.init_padding = parse()?,
+            "S2" => self.response_padding = parse()?,
+            "H1" | "H2" | "H3" | "H4" => {
+                let index = (key.as_bytes()[1] - b'1') as usize;
+                self.headers[index] = value
+                    .parse::<u32>()
+                    .map_err(|e| anyhow!("Parse {key} failed: {e}"))?;
+            }
+            other => return Err(anyhow!("Unexpected obfuscation key: {other}")),
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Reject settings under which message types could not be told apart.
+    pub fn validate(&self) -> Result<()> {
+        if self.junk_count > MAX_JUNK_COUNT {
+            return Err(anyhow!("Jc must be at most {MAX_JUNK_COUNT}"));
+        }
+        if self.junk_count > 0 && (self.junk_min > self.junk_max || self.junk_max > MAX_DATAGRAM) {
+            return Err(anyhow!(
+                "Jmin must not exceed Jmax, and Jmax must be at most {MAX_DATAGRAM}"
+            ));
+        }
+        if HANDSHAKE_INIT_LEN + self.init_padding > MAX_DATAGRAM {
+            return Err(anyhow!(
+                "S1 must be at most {}",
+                MAX_DATAGRAM - HANDSHAKE_INIT_LEN
+            ));
+        }
+        if HANDSHAKE_RESPONSE_LEN + self.response_padding > MAX_DATAGRAM {
+            return Err(anyhow!(
+                "S2 must be at most {}",
+                MAX_DATAGRAM - HANDSHAKE_RESPONSE_LEN
+            ));
+        }
+        if HANDSHAKE_INIT_LEN + self.init_padding == HANDSHAKE_RESPONSE_LEN + self.response_padding
+        {
+            return Err(anyhow!(
+                "S1 + {} must not equal S2, initiations and responses would have the same size",
+                HANDSHAKE_INIT_LEN - HANDSHAKE_RESPONSE_LEN
+            ));
+        }
+        for (index, header) in self.headers.iter().enumerate() {
+            if self.headers[..index].contains(header) {
+                return Err(anyhow!("H1, H2, H3 and H4 must all differ"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the disguised form of `packet` to `dst`, returning its length.
+    fn encode(&self, packet: &[u8], dst: &mut [u8]) -> usize {
+        let padding = match message_type(packet).filter(|_| !is_passthrough(packet)) {
+            Some(HANDSHAKE_INIT) => self.init_padding,
+            Some(HANDSHAKE_RESPONSE) => self.response_padding,
+            _ => 0,
+        };
+        rand::rng().fill_bytes(&mut dst[..padding]);
+        dst[padding..padding + packet.len()].copy_from_slice(packet);
+        self.replace_header(&mut dst[padding..padding + packet.len()]);
+        padding + packet.len()
+    }
+
+    /// Swap the message type for its configured value.
+    fn replace_header(&self, packet: &mut [u8]) {
+        if is_passthrough(packet) {
+            return;
+        }
+        if let Some(header) = message_type(packet)
+            .filter(|header| (HANDSHAKE_INIT..=DATA).contains(header))
+            .map(|header| self.headers[header as usize - 1])
+        {
+            packet[..4].copy_from_slice(&header.to_le_bytes());
+        }
+    }
+
+    /// Turn a received datagram back into a WireGuard message. Returns false
+    /// for junk and anything else that isn't one.
+    fn decode(&self, buf: &mut PacketBuffer) -> bool {
+        let header_at = |buf: &[u8], offset: usize| message_type(buf.get(offset..).unwrap_or(&[]));
+        let len = buf.len();
+        let (padding, header) = if len == HANDSHAKE_INIT_LEN + self.init_padding
+            && header_at(buf, self.init_padding) == Some(self.headers[0])
+        {
+            (self.init_padding, HANDSHAKE_INIT)
+        } else if len == HANDSHAKE_RESPONSE_LEN + self.response_padding
+            && header_at(buf, self.response_padding) == Some(self.headers[1])
+        {
+            (self.response_padding, HANDSHAKE_RESPONSE)
+        } else if len == COOKIE_REPLY_LEN && header_at(buf, 0) == Some(self.headers[2]) {
+            (0, COOKIE_REPLY)
+        } else if len >= DATA_MIN_LEN && header_at(buf, 0) == Some(self.headers[3]) {
+            (0, DATA)
+        } else {
+            return false;
+        };
+        buf.copy_within(padding.., 0);
+        buf.set_len(len - padding);
+        buf[..4].copy_from_slice(&header.to_le_bytes());
+        true
+    }
+
+    /// A junk datagram of random size and content, written to `dst`.
+    fn junk(&self, dst: &mut [u8]) -> usize {
+        let mut rng = rand::rng();
+        let len = rng.random_range(self.junk_min..=self.junk_max);
+        rng.fill_bytes(&mut dst[..len]);
+        len
+    }
+}
+
+/// STUN and rendezvous messages share the socket with WireGuard and pass
+/// unchanged, so their servers understand them.
+fn is_passthrough(packet: &[u8]) -> bool {
+    stun::is_message(packet) || rendezvous::is_message(packet)
+}
+
+fn message_type(packet: &[u8]) -> Option<u32> {
+    packet
+        .get(..4)
+        .map(|header| u32::from_le_bytes(header.try_into().unwrap()))
+}
+
+/// Applies an [`Obfuscation`] to everything sent and received through
+/// another transport.
+pub struct ObfuscatedTransport {
+    inner: Arc<dyn Transport>,
+    obfuscation: Obfuscation,
+    /// Batches with handshakes are rewritten into this one.
+    out: Mutex<SendBatch>,
+}
+
+impl ObfuscatedTransport {
+    pub fn new(inner: Arc<dyn Transport>, obfuscation: Obfuscation) -> Result<Self> {
+        obfuscation.validate()?;
+        Ok(Self {
+            inner,
+            obfuscation,
+            out: Mutex::new(SendBatch::new()),
+        })
+    }
+
+    /// Send the packets of `batch` through `out`, junk in front of each
+    /// initiation.
+    async fn encode_batch(&self, batch: &SendBatch, out: &mut SendBatch) -> io::Result<()> {
+        for (packet, endpoint, source) in batch.packets() {
+            if message_type(packet) == Some(HANDSHAKE_INIT) {
+                for _ in 0..self.obfuscation.junk_count {
+                    if out.is_full() {
+                        self.inner.send_batch(out).await?;
+                    }
+                    let len = self.obfuscation.junk(out.slot());
+                    out.commit(len, endpoint, source);
+                }
+            }
+            if out.is_full() {
+                self.inner.send_batch(out).await?;
+            }
+            let len = self.obfuscation.encode(packet, out.slot());
+            out.commit(len, endpoint, source);
+        }
+        self.inner.send_batch(out).await
+    }
+}
+
+#[async_trait]
+impl Transport for ObfuscatedTransport {
+    async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()> {
+        let handshake = |packet: &[u8]| {
+            matches!(
+                message_type(packet),
+                Some(HANDSHAKE_INIT) | Some(HANDSHAKE_RESPONSE)
+            )
+        };
+        // Data and cookies only change their type, in place
+        if !batch.packets().any(|(packet, _, _)| handshake(packet)) {
+            for packet in batch.packets_mut() {
+                self.obfuscation.replace_header(packet);
+            }
+            return self.inner.send_batch(batch).await;
+        }
+
+        let mut out = self.out.lock().await;
+        let result = self.encode_batch(batch, &mut out).await;
+        batch.clear();
+        out.clear();
+        result
+    }
+
+    async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        self.inner.recv_batch(batch).await?;
+        batch.retain_mut(|buf, _| is_passthrough(buf) || self.obfuscation.decode(buf));
+        Ok(())
+    }
+
+    async fn rebind(&self) -> io::Result<()> {
+        self.inner.rebind().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use super::{ObfuscatedTransport, Obfuscation, HANDSHAKE_INIT_LEN};
+    use crate::{
+        buffer::BufferPool,
+        transport::{ChannelTransport, Transport},
+        udp::{RecvBatch, SendBatch},
+    };
+
+    fn obfuscation() -> Obfuscation {
+        let mut obfuscation = Obfuscation::new();
+        for (key, value) in [
+            ("Jc", "3"),
+            ("Jmin", "40"),
+            ("Jmax", "70"),
+            ("S1", "15"),
+            ("S2", "68"),
+            ("H1", "1733240241"),
+            ("H2", "95614391"),
+            ("H3", "3361402712"),
+            ("H4", "2129468340"),
+        ] {
+            obfuscation.set(key, value).unwrap();
+        }
+        obfuscation
+    }
+
+    #[test]
+    fn conflicting_settings_are_rejected() {
+        assert!(obfuscation().validate().is_ok());
+        assert!(!Obfuscation::new().is_enabled());
+
+        let mut same_size = obfuscation();
+        same_size.response_padding = same_size.init_padding + 56;
+        assert!(same_size.validate().is_err());
+
+        let mut same_header = obfuscation();
+        same_header.headers[3] = same_header.headers[0];
+        assert!(same_header.validate().is_err());
+
+        let mut junk = obfuscation();
+        junk.junk_min = junk.junk_max + 1;
+        assert!(junk.validate().is_err());
+
+        assert!(Obfuscation::new().set("H5", "1").is_err());
+    }
+
+    #[tokio::test]
+    async fn messages_are_disguised_and_restored() {
+        let a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let (a_end, b_end) = ChannelTransport::pair(a, b);
+        let a_end = ObfuscatedTransport::new(Arc::new(a_end), obfuscation()).unwrap();
+
+        let mut init = vec![0u8; HANDSHAKE_INIT_LEN];
+        init[0] = 1;
+        let mut data = vec![7u8; 48];
+        data[..4].copy_from_slice(&[4, 0, 0, 0]);
+        let mut send = SendBatch::new();
+        for packet in [&init, &data] {
+            send.slot()[..packet.len()].copy_from_slice(packet);
+            send.commit(packet.len(), b, None);
+        }
+        a_end.send_batch(&mut send).await.unwrap();
+
+        // Three junk datagrams, the padded initiation and the data message
+        let mut raw = RecvBatch::new(BufferPool::new(8));
+        b_end.recv_batch(&mut raw).await.unwrap();
+        let raw = raw.drain().map(|(buf, _, _)| buf).collect::<Vec<_>>();
+        assert_eq!(raw.len(), 5);
+        assert!(raw[..3].iter().all(|junk| (40..=70).contains(&junk.len())));
+        assert_eq!(raw[3].len(), HANDSHAKE_INIT_LEN + 15);
+        assert_eq!(&raw[3][15..19], &1733240241u32.to_le_bytes());
+        assert_eq!(&raw[4][..4], &2129468340u32.to_le_bytes());
+
+        // The other side, with the same settings, sees plain WireGuard
+        let (c_end, d_end) = ChannelTransport::pair(a, b);
+        let c_end = ObfuscatedTransport::new(Arc::new(c_end), obfuscation()).unwrap();
+        let d_end = ObfuscatedTransport::new(Arc::new(d_end), obfuscation()).unwrap();
+        for packet in [&init, &data] {
+            send.slot()[..packet.len()].copy_from_slice(packet);
+            send.commit(packet.len(), b, None);
+        }
+        c_end.send_batch(&mut send).await.unwrap();
+        let mut recv = RecvBatch::new(BufferPool::new(8));
+        d_end.recv_batch(&mut recv).await.unwrap();
+        let packets = recv
+            .drain()
+            .map(|(buf, _, _)| buf.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(packets, vec![init, data]);
+    }
+}
@@ -0,0 +1,192 @@
+use std::{collections::VecDeque, fs, io, path::Path};
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use crate::{
+    device::Device,
+    tun::{TunBatch, TunReader},
+};
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const REPLAY_MTU: usize = 1500;
+
+/// A device that reads the IP packets of a pcap capture, as fast as they
+/// are taken, and discards everything written to it. Once the capture is
+/// exhausted it stays silent.
+pub struct PcapReplay {
+    packets: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl PcapReplay {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        Ok(Self {
+            packets: Mutex::new(parse(data)?.into()),
+        })
+    }
+}
+
+#[async_trait]
+impl Device for PcapReplay {
+    fn name(&self) -> String {
+        "pcap".to_string()
+    }
+
+    fn mtu(&self) -> usize {
+        REPLAY_MTU
+    }
+
+    async fn recv(&self, reader: &mut TunReader) -> io::Result<()> {
+        reader.clear();
+        match self.packets.lock().await.pop_front() {
+            Some(packet) => {
+                reader.push(&packet);
+                Ok(())
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn send(&self, batch: &mut TunBatch) -> io::Result<()> {
+        batch.clear();
+        Ok(())
+    }
+}
+
+/// The IP packets of a pcap capture, in order. Frames that don't carry IP
+/// are skipped.
+fn parse(data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let header = data
+        .get(..HEADER_LEN)
+        .ok_or_else(|| invalid("Pcap header too short"))?;
+    let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let little_endian = match magic {
+        MAGIC_MICROS | MAGIC_NANOS => true,
+        _ if magic.swap_bytes() == MAGIC_MICROS || magic.swap_bytes() == MAGIC_NANOS => false,
+        _ => return Err(invalid("Not a pcap file")),
+    };
+    let read_u32 = |bytes: &[u8]| {
+        let bytes = bytes.try_into().unwrap();
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    };
+    let linktype = read_u32(&header[20..24]) & 0x0fff_ffff;
+
+    let mut packets = vec![];
+    let mut rest = &data[HEADER_LEN..];
+    while !rest.is_empty() {
+        let record = rest
+            .get(..RECORD_HEADER_LEN)
+            .ok_or_else(|| invalid("Pcap record header truncated"))?;
+        let len = read_u32(&record[8..12]) as usize;
+        let frame = rest
+            .get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)
+            .ok_or_else(|| invalid("Pcap record truncated"))?;
+        rest = &rest[RECORD_HEADER_LEN + len..];
+        if let Some(packet) = ip_payload(linktype, frame)? {
+            packets.push(packet.to_vec());
+        }
+    }
+    Ok(packets)
+}
+
+fn ip_payload(linktype: u32, frame: &[u8]) -> io::Result<Option<&[u8]>> {
+    let packet = match linktype {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(frame),
+        LINKTYPE_NULL => frame.get(4..),
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = read_u16(frame, offset);
+            while ethertype == Some(ETHERTYPE_VLAN) {
+                offset += 4;
+                ethertype = read_u16(frame, offset);
+            }
+            match ethertype {
+                Some(ETHERTYPE_IPV4 | ETHERTYPE_IPV6) => frame.get(offset + 2..),
+                _ => None,
+            }
+        }
+        LINKTYPE_LINUX_SLL => match read_u16(frame, 14) {
+            Some(ETHERTYPE_IPV4 | ETHERTYPE_IPV6) => frame.get(16..),
+            _ => None,
+        },
+        other => return Err(invalid(&format!("Unsupported pcap link type: {other}"))),
+    };
+    Ok(packet.filter(|packet| matches!(packet.first().map(|b| b >> 4), Some(4 | 6))))
+}
+
+fn read_u16(frame: &[u8], offset: usize) -> Option<u16> {
+    frame
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, LINKTYPE_ETHERNET, LINKTYPE_RAW, MAGIC_MICROS};
+
+    fn capture(linktype: u32, frames: &[&[u8]], big_endian: bool) -> Vec<u8> {
+        let u32_bytes = |value: u32| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let mut data = u32_bytes(MAGIC_MICROS).to_vec();
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&u32_bytes(65535));
+        data.extend_from_slice(&u32_bytes(linktype));
+        for frame in frames {
+            data.extend_from_slice(&[0; 8]);
+            data.extend_from_slice(&u32_bytes(frame.len() as u32));
+            data.extend_from_slice(&u32_bytes(frame.len() as u32));
+            data.extend_from_slice(frame);
+        }
+        data
+    }
+
+    #[test]
+    fn ip_packets_are_extracted_from_captures() {
+        let packet = [0x45, 0, 0, 20];
+        let raw = capture(LINKTYPE_RAW, &[&packet], true);
+        assert_eq!(parse(&raw).unwrap(), vec![packet.to_vec()]);
+
+        let mut ip_frame = vec![0u8; 12];
+        ip_frame.extend_from_slice(&[0x08, 0x00]);
+        ip_frame.extend_from_slice(&packet);
+        let mut arp_frame = vec![0u8; 12];
+        arp_frame.extend_from_slice(&[0x08, 0x06, 0, 1]);
+        let ethernet = capture(LINKTYPE_ETHERNET, &[&arp_frame, &ip_frame], false);
+        assert_eq!(parse(&ethernet).unwrap(), vec![packet.to_vec()]);
+
+        assert!(parse(&ethernet[..ethernet.len() - 1]).is_err());
+    }
+}
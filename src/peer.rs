@@ -1,27 +1,156 @@
 use std::{
-    net::{IpAddr, SocketAddr, ToSocketAddrs},
-    sync::Arc,
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex as StdMutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{anyhow, Ok, Result};
-use async_recursion::async_recursion;
 use boringtun::{
-    noise::{errors::WireGuardError, Tunn, TunnResult},
-    x25519::PublicKey,
+    noise::{errors::WireGuardError, rate_limiter::RateLimiter, Tunn, TunnResult},
+    x25519::{PublicKey, StaticSecret},
 };
-use tokio::{net::UdpSocket, sync::Mutex};
+use rand::Rng;
+use tokio::sync::{broadcast, Mutex};
 
-use crate::utils::{decode_public_key, parse_allowed_ips};
+use crate::{
+    buffer::PacketBuffer,
+    device::Device,
+    limiter::HandshakeStats,
+    quota::{parse_time, unix_secs, DataLimit, Usage},
+    relay::{Hop, Relay},
+    shaper::{parse_rate, Admit, Next, Shaper},
+    transport::{self, Transport},
+    tun::TunBatch,
+    udp::SendBatch,
+    utils::{decode_public_key, parse_allowed_ips},
+};
+
+const HANDSHAKE_INIT: u8 = 1;
+const HANDSHAKE_RESPONSE: u8 = 2;
+const COOKIE_REPLY: u8 = 3;
+const DATA: u8 = 4;
+/// Time an initiation waits for a response before it is also sent to the
+/// next endpoint. Races are checked on a timer of this period, so the next
+/// endpoint follows after one to two periods.
+pub const ENDPOINT_RACE_DELAY: Duration = Duration::from_millis(250);
+/// Session age at which its initiator starts a new handshake, and at which
+/// it is no longer used. boringtun keeps the same times on a clock of its
+/// own, the peer checks them as well so tests can shorten them.
+#[cfg(not(test))]
+pub(crate) const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+#[cfg(not(test))]
+pub(crate) const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+#[cfg(test)]
+pub(crate) const REKEY_AFTER_TIME: Duration = Duration::from_secs(6);
+#[cfg(test)]
+pub(crate) const REJECT_AFTER_TIME: Duration = Duration::from_secs(9);
+/// Wait after the first failed handshake, doubled after each further one.
+const RETRY_BACKOFF_BASE: Duration = Duration::from_secs(5);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+/// Where a peer stands in reaching the other side.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerState {
+    /// No handshake was tried yet.
+    Idle,
+    /// A handshake is under way, with no session yet.
+    Handshaking,
+    /// A handshake completed.
+    Established,
+    /// The session timed out. A new one is started by traffic, or right away
+    /// with persistent keepalive.
+    Expired,
+    /// Handshakes went unanswered. They are retried after a backoff, until
+    /// the attempt limit is reached.
+    Unreachable,
+    /// The peer was removed while running. Its packets are dropped.
+    Removed,
+    /// The peer is over its data limit or expired. Its session was closed
+    /// and its packets are dropped.
+    Suspended,
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerStatus {
+    pub state: PeerState,
+    pub endpoint: Option<SocketAddr>,
+    /// Handshakes that timed out in a row.
+    pub failed_attempts: u32,
+    /// When the next handshake is due. None when nothing is scheduled.
+    pub next_attempt: Option<Instant>,
+}
 
-pub(crate) struct Peer {
+/// Traffic of the current session keys.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PeerStats {
+    /// Time since the last completed handshake.
+    pub last_handshake: Option<Duration>,
+    pub tx_bytes: usize,
+    pub rx_bytes: usize,
+}
+
+/// Sent whenever the state of a peer changes.
+#[derive(Clone, Debug)]
+pub struct PeerEvent {
+    pub public_key: Option<PublicKey>,
+    pub status: PeerStatus,
+}
+
+struct Connection {
+    state: PeerState,
+    failed_attempts: u32,
+    next_attempt: Option<Instant>,
+}
+
+pub struct Peer {
     pub public_key: Option<PublicKey>,
     pub allowed_ips: Option<Vec<(IpAddr, u8)>>,
     pub persistent_keepalive: Option<u16>,
-    pub endpoint: Option<SocketAddr>,
+    /// Handshakes that may time out in a row before retries stop. Unlimited
+    /// when None.
+    pub handshake_attempts: Option<u32>,
+    /// Reach the endpoints over TCP instead of UDP.
+    pub over_tcp: bool,
+    /// Groups whose other members this peer may reach through the relay.
+    pub relay_groups: Vec<String>,
+    pub data_limit: Option<DataLimit>,
+    pub expires_at: Option<SystemTime>,
+    /// Bytes per second to the peer.
+    pub send_rate: Option<u64>,
+    /// Bytes per second from the peer.
+    pub receive_rate: Option<u64>,
 
-    send_socket: Option<Arc<UdpSocket>>,
-    send_tun: Option<Arc<tun_rs::AsyncDevice>>,
+    /// Endpoints as configured, addresses or `host:port`, in order.
+    configured_endpoints: Vec<String>,
+    resolved: RwLock<HashMap<String, Vec<SocketAddr>>>,
+    candidates: RwLock<Vec<SocketAddr>>,
+    endpoint: RwLock<Option<SocketAddr>>,
+    race: StdMutex<Option<EndpointRace>>,
+    source: RwLock<Option<IpAddr>>,
+    failed_handshakes: AtomicU32,
+    /// Whether the current session answered our initiation, only then the
+    /// peer starts the next one.
+    initiator: AtomicBool,
+    removed: AtomicBool,
+    period_start: AtomicU64,
+    used_bytes: AtomicU64,
+    suspended: AtomicBool,
+    connection: StdMutex<Connection>,
+    events: Option<broadcast::Sender<PeerEvent>>,
+    send_socket: Option<Arc<dyn Transport>>,
+    send_device: Option<Arc<dyn Device>>,
+    handshake_stats: Option<Arc<HandshakeStats>>,
+    relay: Option<Arc<Relay>>,
     tunn: Option<Mutex<Tunn>>,
+    send_shaper: Option<Shaper>,
+    receive_shaper: Option<Shaper>,
+    /// What closing the session takes.
+    session_key: Option<(StaticSecret, Arc<RateLimiter>)>,
+    worker_index: usize,
 }
 
 impl Peer {
@@ -30,10 +159,40 @@ impl Peer {
             public_key: None,
             allowed_ips: None,
             persistent_keepalive: None,
-            endpoint: None,
+            handshake_attempts: None,
+            over_tcp: false,
+            relay_groups: vec![],
+            data_limit: None,
+            expires_at: None,
+            send_rate: None,
+            receive_rate: None,
+            configured_endpoints: vec![],
+            resolved: RwLock::new(HashMap::new()),
+            candidates: RwLock::new(vec![]),
+            endpoint: RwLock::new(None),
+            race: StdMutex::new(None),
+            source: RwLock::new(None),
+            failed_handshakes: AtomicU32::new(0),
+            initiator: AtomicBool::new(false),
+            removed: AtomicBool::new(false),
+            period_start: AtomicU64::new(0),
+            used_bytes: AtomicU64::new(0),
+            suspended: AtomicBool::new(false),
+            connection: StdMutex::new(Connection {
+                state: PeerState::Idle,
+                failed_attempts: 0,
+                next_attempt: None,
+            }),
+            events: None,
             send_socket: None,
-            send_tun: None,
+            send_device: None,
+            handshake_stats: None,
+            relay: None,
             tunn: None,
+            send_shaper: None,
+            receive_shaper: None,
+            session_key: None,
+            worker_index: 0,
         };
         Ok(peer)
     }
@@ -53,18 +212,240 @@ impl Peer {
         Ok(())
     }
 
+    pub fn set_handshake_attempts(&mut self, handshake_attempts: u32) -> Result<()> {
+        if handshake_attempts == 0 {
+            return Err(anyhow!("Handshake attempts must be greater than 0"));
+        }
+        self.handshake_attempts = Some(handshake_attempts);
+        Ok(())
+    }
+
+    /// `udp`, the default, or `tcp`.
+    pub fn set_transport(&mut self, transport: &str) -> Result<()> {
+        self.over_tcp = match transport.to_ascii_lowercase().as_str() {
+            "udp" => false,
+            "tcp" => true,
+            other => return Err(anyhow!("Unexpected transport: {other}")),
+        };
+        Ok(())
+    }
+
+    pub fn set_relay_groups(&mut self, relay_groups: &[&str]) -> Result<()> {
+        self.relay_groups = relay_groups.iter().map(|group| group.to_string()).collect();
+        Ok(())
+    }
+
+    /// Bytes per period, see [`DataLimit::parse`].
+    pub fn set_data_limit(&mut self, data_limit: &str) -> Result<()> {
+        self.data_limit = Some(DataLimit::parse(data_limit)?);
+        Ok(())
+    }
+
+    /// See [`parse_time`].
+    pub fn set_expires_at(&mut self, expires_at: &str) -> Result<()> {
+        self.expires_at = Some(parse_time(expires_at)?);
+        Ok(())
+    }
+
+    /// See [`parse_rate`].
+    pub fn set_send_rate(&mut self, send_rate: &str) -> Result<()> {
+        self.send_rate = Some(parse_rate(send_rate)?);
+        Ok(())
+    }
+
+    /// See [`parse_rate`].
+    pub fn set_receive_rate(&mut self, receive_rate: &str) -> Result<()> {
+        self.receive_rate = Some(parse_rate(receive_rate)?);
+        Ok(())
+    }
+
+    /// Replace the endpoints with `endpoint`.
     pub fn set_endpoint(&mut self, endpoint: &str) -> Result<()> {
-        self.endpoint = Some(endpoint_socket_addr(endpoint)?);
+        self.configured_endpoints.clear();
+        self.add_endpoint(endpoint)
+    }
+
+    /// An address is used as is, a hostname is kept and resolved when the
+    /// device runs, and again whenever it may have changed. The first
+    /// endpoint is tried first, the others when handshakes go unanswered.
+    pub fn add_endpoint(&mut self, endpoint: &str) -> Result<()> {
+        if endpoint.parse::<SocketAddr>().is_err() {
+            let (host, port) = endpoint.rsplit_once(':').ok_or(anyhow!(
+                "Parse socket address failed: missing port in {endpoint}"
+            ))?;
+            if host.is_empty() {
+                return Err(anyhow!(
+                    "Parse socket address failed: missing host in {endpoint}"
+                ));
+            }
+            port.parse::<u16>()
+                .map_err(|e| anyhow!("Parse socket address failed: {}", e))?;
+        }
+        self.configured_endpoints.push(endpoint.to_string());
+        self.update_candidates();
+        Ok(())
+    }
+
+    /// Endpoints as configured, addresses or `host:port`.
+    pub fn configured_endpoints(&self) -> &[String] {
+        &self.configured_endpoints
+    }
+
+    /// The configured endpoints that are hostnames.
+    pub fn endpoint_hosts(&self) -> Vec<String> {
+        self.configured_endpoints
+            .iter()
+            .filter(|endpoint| endpoint.parse::<SocketAddr>().is_err())
+            .cloned()
+            .collect()
+    }
+
+    /// The endpoint packets are sent to.
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        *self.endpoint.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Every address the peer may be reached at, in the order they are
+    /// tried.
+    pub fn endpoints(&self) -> Vec<SocketAddr> {
+        self.candidates
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replace the endpoint, returning the previous one.
+    pub fn update_endpoint(&self, endpoint: SocketAddr) -> Option<SocketAddr> {
+        self.set_source(None);
+        self.endpoint
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .replace(endpoint)
+    }
+
+    /// Record the addresses `host` resolved to and recompute the endpoints.
+    /// Returns the endpoints before and after.
+    pub fn set_resolved(
+        &self,
+        host: &str,
+        addrs: Vec<SocketAddr>,
+    ) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+        self.resolved
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(host.to_string(), interleave_families(addrs));
+        let previous = self.endpoints();
+        self.update_candidates();
+        (previous, self.endpoints())
+    }
+
+    /// Stay on the current endpoint while it is still a candidate, otherwise
+    /// move to the first one.
+    fn update_candidates(&self) {
+        let candidates = {
+            let resolved = self.resolved.read().unwrap_or_else(|e| e.into_inner());
+            let mut candidates = vec![];
+            for endpoint in &self.configured_endpoints {
+                let addrs = match endpoint.parse::<SocketAddr>() {
+                    Result::Ok(addr) => vec![addr],
+                    Err(_) => resolved.get(endpoint).cloned().unwrap_or_default(),
+                };
+                for addr in addrs {
+                    if !candidates.contains(&addr) {
+                        candidates.push(addr);
+                    }
+                }
+            }
+            candidates
+        };
+        if self
+            .endpoint()
+            .is_none_or(|current| !candidates.contains(&current))
+        {
+            self.set_source(None);
+            *self.endpoint.write().unwrap_or_else(|e| e.into_inner()) = candidates.first().copied();
+        }
+        *self.candidates.write().unwrap_or_else(|e| e.into_inner()) = candidates;
+    }
+
+    /// The local address the peer's packets last arrived on, which replies
+    /// are sent from.
+    pub fn source(&self) -> Option<IpAddr> {
+        *self.source.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_source(&self, source: Option<IpAddr>) {
+        *self.source.write().unwrap_or_else(|e| e.into_inner()) = source;
+    }
+
+    /// Handshake initiations sent since the last confirmed session.
+    pub fn failed_handshakes(&self) -> u32 {
+        self.failed_handshakes.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_failed_handshakes(&self) {
+        self.failed_handshakes.store(0, Ordering::Relaxed);
+    }
+
+    pub fn status(&self) -> PeerStatus {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        PeerStatus {
+            state: connection.state,
+            endpoint: self.endpoint(),
+            failed_attempts: connection.failed_attempts,
+            next_attempt: connection.next_attempt,
+        }
+    }
+
+    /// Report state changes to `events`.
+    pub fn set_events(&mut self, events: broadcast::Sender<PeerEvent>) -> Result<()> {
+        self.events = Some(events);
         Ok(())
     }
 
-    pub fn set_send_socket(&mut self, send_socket: Arc<UdpSocket>) -> Result<()> {
+    /// Forward packets for other peers through `relay` instead of the device.
+    pub(crate) fn set_relay(&mut self, relay: Arc<Relay>) -> Result<()> {
+        self.relay = Some(relay);
+        Ok(())
+    }
+
+    /// Tear the peer down, after it was removed while running. Packets still
+    /// on their way are dropped, the session goes with the last reference.
+    pub fn close(&self) {
+        self.removed.store(true, Ordering::Relaxed);
+        self.update_connection(|connection| {
+            connection.state = PeerState::Removed;
+            connection.next_attempt = None;
+        });
+    }
+
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Relaxed)
+    }
+
+    /// Retry handshakes right away and count failures from zero, once the
+    /// network or the endpoints changed.
+    pub fn reset_backoff(&self) {
+        self.update_connection(|connection| {
+            connection.failed_attempts = 0;
+            if connection.state == PeerState::Unreachable {
+                connection.next_attempt = Some(Instant::now());
+            }
+        });
+    }
+
+    pub fn set_send_socket(&mut self, send_socket: Arc<dyn Transport>) -> Result<()> {
         self.send_socket = Some(send_socket);
         Ok(())
     }
 
-    pub fn set_send_tun(&mut self, send_tun: Arc<tun_rs::AsyncDevice>) -> Result<()> {
-        self.send_tun = Some(send_tun);
+    pub fn set_send_device(&mut self, send_device: Arc<dyn Device>) -> Result<()> {
+        self.send_device = Some(send_device);
+        Ok(())
+    }
+
+    pub fn set_handshake_stats(&mut self, handshake_stats: Arc<HandshakeStats>) -> Result<()> {
+        self.handshake_stats = Some(handshake_stats);
         Ok(())
     }
 
@@ -73,74 +454,332 @@ impl Peer {
         Ok(())
     }
 
-    pub async fn handle_socket_packet(&self, src: &mut [u8]) -> Result<()> {
-        let mut dst = vec![0u8; 2048];
+    pub(crate) fn set_shapers(
+        &mut self,
+        send_shaper: Option<Shaper>,
+        receive_shaper: Option<Shaper>,
+    ) -> Result<()> {
+        self.send_shaper = send_shaper;
+        self.receive_shaper = receive_shaper;
+        Ok(())
+    }
+
+    /// Packets the rate limits dropped.
+    pub fn dropped_packets(&self) -> u64 {
+        [&self.send_shaper, &self.receive_shaper]
+            .into_iter()
+            .flatten()
+            .map(|shaper| shaper.dropped())
+            .sum()
+    }
+
+    /// The private key and rate limiter the tunn was created with.
+    pub(crate) fn set_session_key(
+        &mut self,
+        private_key: StaticSecret,
+        rate_limiter: Arc<RateLimiter>,
+    ) -> Result<()> {
+        self.session_key = Some((private_key, rate_limiter));
+        Ok(())
+    }
+
+    /// Traffic counted against the data limit in the current period.
+    pub fn usage(&self) -> Usage {
+        Usage {
+            period_start: self.period_start.load(Ordering::Relaxed),
+            bytes: self.used_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Carry on with `usage`, saved before a restart or by the peer this one
+    /// replaces.
+    pub(crate) fn restore_usage(&self, usage: Usage) {
+        self.period_start
+            .store(usage.period_start, Ordering::Relaxed);
+        self.used_bytes.store(usage.bytes, Ordering::Relaxed);
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Relaxed)
+    }
+
+    /// Suspend the peer once it is expired or over its data limit, and let
+    /// it back in when a new period starts. Called once a second.
+    pub async fn check_quota(&self) {
+        if self.data_limit.is_none() && self.expires_at.is_none() {
+            return;
+        }
+        let now = SystemTime::now();
+        let mut over = false;
+        if let Some(data_limit) = &self.data_limit {
+            let usage = self.usage();
+            let current = usage.at(data_limit.period, unix_secs(now));
+            if current != usage {
+                self.restore_usage(current);
+            }
+            over = current.bytes >= data_limit.bytes;
+        }
+        let expired = self.expires_at.is_some_and(|expires_at| expires_at <= now);
+        if over || expired {
+            self.suspend().await;
+        } else if self.suspended.swap(false, Ordering::Relaxed) {
+            self.update_connection(|connection| {
+                connection.state = PeerState::Idle;
+                connection.failed_attempts = 0;
+                connection.next_attempt = None;
+            });
+        }
+    }
+
+    /// Count `len` bytes of traffic, and suspend the peer once they take it
+    /// to its data limit.
+    async fn count_traffic(&self, len: usize) {
+        if let Some(data_limit) = &self.data_limit {
+            let used = self.used_bytes.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+            if used >= data_limit.bytes {
+                self.suspend().await;
+            }
+        }
+    }
+
+    async fn suspend(&self) {
+        if self.suspended.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        self.update_connection(|connection| {
+            connection.state = PeerState::Suspended;
+            connection.next_attempt = None;
+        });
+        if let Some(tunn) = &self.tunn {
+            self.close_sessions(&mut *tunn.lock().await);
+        }
+    }
+
+    fn close_sessions(&self, tunn: &mut Tunn) {
+        if let Some((private_key, rate_limiter)) = &self.session_key {
+            // Setting the same key again drops the sessions
+            let result = tunn.set_static_private(
+                private_key.clone(),
+                PublicKey::from(private_key),
+                Some(rate_limiter.clone()),
+            );
+            if let Err(e) = result {
+                println!("Close session failed: {e:?}");
+            }
+        }
+    }
+
+    pub fn set_worker_index(&mut self, worker_index: usize) -> Result<()> {
+        self.worker_index = worker_index;
+        Ok(())
+    }
+
+    pub fn worker_index(&self) -> usize {
+        self.worker_index
+    }
+
+    /// Replies go back to `from`, which becomes the endpoint once the packet
+    /// proves to be from the peer. Returns whether it did.
+    pub async fn handle_socket_packet(
+        &self,
+        src: &[u8],
+        from: SocketAddr,
+        local: Option<IpAddr>,
+        batch: &mut SendBatch,
+        tun_batch: &mut TunBatch,
+    ) -> Result<bool> {
+        if self.is_removed() || self.is_suspended() {
+            return Ok(false);
+        }
         if let Some(tunn) = &self.tunn {
-            let result = tunn.lock().await.decapsulate(None, src, &mut dst);
+            self.make_room(batch).await?;
+            // Handshakes passed the interface's rate limit on the receive
+            // task, the session limiter only checks their MACs
+            let result = tunn
+                .lock()
+                .await
+                .decapsulate(Some(from.ip()), src, batch.slot());
+            let authenticated = match &result {
+                TunnResult::Err(_) => false,
+                TunnResult::WriteToNetwork(packet) => packet.first() != Some(&COOKIE_REPLY),
+                _ => true,
+            } && matches!(
+                src.first(),
+                Some(&HANDSHAKE_INIT) | Some(&HANDSHAKE_RESPONSE) | Some(&DATA)
+            );
+            if authenticated {
+                self.confirm_endpoint(from);
+                match src.first() {
+                    Some(&HANDSHAKE_INIT) => self.initiator.store(false, Ordering::Relaxed),
+                    Some(&HANDSHAKE_RESPONSE) => self.initiator.store(true, Ordering::Relaxed),
+                    _ => {}
+                }
+                if src.first() != Some(&HANDSHAKE_INIT) {
+                    self.established();
+                }
+            }
             match result {
                 TunnResult::WriteToNetwork(packet) => {
-                    let send_socket = self
-                        .send_socket
-                        .clone()
-                        .ok_or(anyhow!("Missing send socket"))?;
-                    let endpoint = self.endpoint.ok_or(anyhow!("Missing endpoint"))?;
-
-                    send_socket.send_to(packet, &endpoint).await.map_err(|e| {
-                        anyhow!("Send to network failed in handle socket packet: {e}")
-                    })?;
-
-                    while let TunnResult::WriteToNetwork(packet) =
-                        tunn.lock().await.decapsulate(None, &[], &mut dst)
-                    {
-                        send_socket.send_to(packet, &endpoint).await.map_err(|e| {
-                            anyhow!("Send to network in loop failed: {e}")
-                        })?;
+                    if packet.first() == Some(&COOKIE_REPLY) {
+                        self.count_stat(HandshakeStats::count_cookie_reply);
                     }
+                    let len = packet.len();
+                    batch.commit(len, from, local);
+                    self.send_queued_packets(tunn, from, local, batch).await?;
                 }
                 TunnResult::WriteToTunnelV4(packet, _) => {
-                    self.send_tun
-                        .clone()
-                        .ok_or(anyhow!("Tun device not found"))?
-                        .send(&packet)
-                        .await
-                        .map_err(|e| anyhow!("Send to tun dev failed: {e}"))?;
+                    let admitted = match &self.receive_shaper {
+                        Some(shaper) => shaper.admit(packet, Instant::now()) == Admit::Pass,
+                        None => true,
+                    };
+                    if admitted {
+                        if let Some((to, packet)) = self.deliver(packet, tun_batch).await? {
+                            to.handle_tun_packet(&packet, batch).await?;
+                        }
+                    }
                 }
                 TunnResult::Done | TunnResult::WriteToTunnelV6(_, _) => {
                     // Ignored
                 }
+                TunnResult::Err(WireGuardError::UnderLoad | WireGuardError::InvalidMac) => {
+                    self.count_stat(HandshakeStats::count_rejected_handshake);
+                    return Ok(false);
+                }
                 other => {
                     Err(anyhow!("Unexpect wireguard result: {:?}", other))?;
                 }
             }
+            // A valid handshake response or data packet proves a session
+            if matches!(src.first(), Some(&HANDSHAKE_RESPONSE) | Some(&DATA)) {
+                self.reset_failed_handshakes();
+            }
+            if local.is_some() && Some(from) == self.endpoint() && local != self.source() {
+                self.set_source(local);
+            }
+            return Ok(authenticated);
         }
-        Ok(())
+        Ok(false)
+    }
+
+    pub async fn handle_tun_packet(&self, src: &[u8], batch: &mut SendBatch) -> Result<()> {
+        if !self.takes_tun_packets() {
+            return Ok(());
+        }
+        if let Some(shaper) = &self.send_shaper {
+            if shaper.admit(src, Instant::now()) != Admit::Pass {
+                return Ok(());
+            }
+        }
+        self.send_packet(src, batch).await
+    }
+
+    /// Pass on the packets the shapers queued, as far as the rates allow
+    /// now. Returns when more may pass, None with nothing queued.
+    pub(crate) async fn drain_shaped(
+        &self,
+        batch: &mut SendBatch,
+        tun_batch: &mut TunBatch,
+    ) -> Result<Option<Duration>> {
+        let mut wait = None;
+        if let Some(shaper) = &self.send_shaper {
+            if !self.takes_tun_packets() {
+                shaper.clear();
+            }
+            loop {
+                match shaper.next(Instant::now()) {
+                    Next::Packet(packet) => self.send_packet(&packet, batch).await?,
+                    Next::Wait(until) => {
+                        wait = Some(until);
+                        break;
+                    }
+                    Next::Empty => break,
+                }
+            }
+        }
+        if let Some(shaper) = &self.receive_shaper {
+            if self.is_removed() || self.is_suspended() {
+                shaper.clear();
+            }
+            loop {
+                match shaper.next(Instant::now()) {
+                    Next::Packet(packet) => {
+                        if let Some((to, packet)) = self.deliver(&packet, tun_batch).await? {
+                            to.handle_tun_packet(&packet, batch).await?;
+                        }
+                    }
+                    Next::Wait(until) => {
+                        wait = Some(wait.map_or(until, |wait: Duration| wait.min(until)));
+                        break;
+                    }
+                    Next::Empty => break,
+                }
+            }
+        }
+        Ok(wait)
     }
 
-    pub async fn handle_tun_packet(&self, src: &mut [u8]) -> Result<()> {
-        let mut dst = vec![0u8; 2048];
+    /// Without an endpoint or while backing off, traffic starts no handshake
+    /// and is dropped.
+    fn takes_tun_packets(&self) -> bool {
+        self.endpoint().is_some()
+            && !self.is_removed()
+            && !self.is_suspended()
+            && self.status().state != PeerState::Unreachable
+    }
+
+    /// Write `packet` from the peer to the device. Returns the peer to hand it
+    /// to instead, when the relay picks one.
+    async fn deliver(
+        &self,
+        packet: &[u8],
+        tun_batch: &mut TunBatch,
+    ) -> Result<Option<(Arc<Peer>, PacketBuffer)>> {
+        self.count_traffic(packet.len()).await;
+        let hop = match &self.relay {
+            Some(relay) => relay.next_hop(self, packet),
+            None => Hop::Device,
+        };
+        match hop {
+            Hop::Device => {
+                if tun_batch.is_full() {
+                    self.flush_tun(tun_batch).await?;
+                }
+                tun_batch.push(packet);
+                Ok(None)
+            }
+            Hop::Peer(to, packet) => Ok(Some((to, packet))),
+            Hop::Drop => Ok(None),
+        }
+    }
+
+    async fn send_packet(&self, src: &[u8], batch: &mut SendBatch) -> Result<()> {
+        // The packet that reaches the limit is the first one dropped
+        self.count_traffic(src.len()).await;
+        if self.is_suspended() {
+            return Ok(());
+        }
         if let Some(tunn) = &self.tunn {
-            let result = tunn.lock().await.encapsulate(src, &mut dst);
+            self.make_room(batch).await?;
+            let mut session = tunn.lock().await;
+            let renewal = self.renew_session(&mut session, batch.slot());
+            if let Some(len) = self.handle_routine_task_result(renewal)? {
+                self.commit(Some(len), batch)?;
+                if batch.is_full() {
+                    drop(session);
+                    self.flush(batch).await?;
+                    session = tunn.lock().await;
+                }
+            }
+            let result = session.encapsulate(src, batch.slot());
+            drop(session);
             match result {
                 TunnResult::WriteToNetwork(packet) => {
-                    let send_socket = self
-                        .send_socket
-                        .clone()
-                        .ok_or(anyhow!("Udp socket not found"))?;
-                    let endpoint = self.endpoint.ok_or(anyhow!("Missing endpoint"))?;
-
-                    send_socket
-                        .send_to(packet, &endpoint)
-                        .await
-                        .map_err(|e| anyhow!("Send to network failed: {e}"))?;
-
-                    while let TunnResult::WriteToNetwork(packet) =
-                        tunn.lock().await.decapsulate(None, &[], &mut dst)
-                    {
-                        send_socket
-                            .send_to(packet, &endpoint)
-                            .await
-                            .map_err(|e| anyhow!("Send to network in loop failed: {:?}", e))?;
-                    }
+                    self.count_handshake(packet);
+                    let len = packet.len();
+                    let (endpoint, source) = self.destination()?;
+                    batch.commit(len, endpoint, source);
+                    self.send_queued_packets(tunn, endpoint, source, batch)
+                        .await?;
                 }
                 TunnResult::Done => {
                     // Ignored
@@ -153,63 +792,500 @@ impl Peer {
         Ok(())
     }
 
-    pub async fn handle_routine_task(&self) -> Result<()> {
-        let mut dst = vec![0u8; 2048];
-        if let Some(tunn) = &self.tunn {
-            let result = tunn.lock().await.update_timers(&mut dst);
-            self.handle_routine_task_result(tunn, result).await?;
+    /// Start a new handshake once the session is [`REKEY_AFTER_TIME`] old,
+    /// if it answered ours, and close it at [`REJECT_AFTER_TIME`], so the
+    /// packet about to be sent starts one.
+    fn renew_session<'a>(&self, tunn: &mut Tunn, dst: &'a mut [u8]) -> TunnResult<'a> {
+        match tunn.time_since_last_handshake() {
+            Some(age) if age >= REJECT_AFTER_TIME => {
+                self.close_sessions(tunn);
+                TunnResult::Done
+            }
+            Some(age) if age >= REKEY_AFTER_TIME && self.initiator.load(Ordering::Relaxed) => {
+                tunn.format_handshake_initiation(dst, false)
+            }
+            _ => TunnResult::Done,
         }
-        Ok(())
     }
 
-    #[async_recursion]
-    async fn handle_routine_task_result<'a: 'async_recursion>(
-        &self,
-        tunn: &Mutex<Tunn>,
-        result: TunnResult<'a>,
-    ) -> Result<()> {
-        match result {
-            TunnResult::WriteToNetwork(packet) => {
-                let send_socket = self
-                    .send_socket
-                    .clone()
-                    .ok_or(anyhow!("Udp socket not found"))?;
-                let endpoint = self.endpoint.ok_or(anyhow!("Missing endpoint"))?;
-
-                send_socket
-                    .send_to(packet, endpoint)
-                    .await
-                    .map_err(|e| anyhow!("Send to network failed: {e}"))?;
+    pub async fn handle_routine_task(&self, batch: &mut SendBatch) -> Result<()> {
+        self.check_quota().await;
+        if self.endpoint().is_none() || self.is_removed() || self.is_suspended() {
+            return Ok(());
+        }
+        if let Some(tunn) = &self.tunn {
+            self.make_room(batch).await?;
+            {
+                let result = tunn.lock().await.update_timers(batch.slot());
+                // Expired stays reported until the next initiation
+                if let TunnResult::Err(WireGuardError::ConnectionExpired) = result {
+                    self.handshake_expired();
+                } else {
+                    let len = self.handle_routine_task_result(result)?;
+                    self.commit(len, batch)?;
+                }
             }
-            TunnResult::Err(WireGuardError::ConnectionExpired) => {
-                let mut buf = vec![0u8; 2048];
+            if self.attempt_due() {
                 let result = tunn
                     .lock()
                     .await
-                    .format_handshake_initiation(&mut buf[..], false);
+                    .format_handshake_initiation(batch.slot(), false);
+                let len = self.handle_routine_task_result(result)?;
+                self.commit(len, batch)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Start a new handshake after the outer socket was rebound, so the
+    /// peer learns our new address. The session keeps working meanwhile.
+    pub async fn rehandshake(&self, batch: &mut SendBatch) -> Result<()> {
+        if self.is_removed() || self.is_suspended() {
+            return Ok(());
+        }
+        self.set_source(None);
+        self.reset_backoff();
+        if let (Some(tunn), Some(_)) = (&self.tunn, self.endpoint()) {
+            self.make_room(batch).await?;
+            let result = tunn
+                .lock()
+                .await
+                .format_handshake_initiation(batch.slot(), false);
+            let len = self.handle_routine_task_result(result)?;
+            self.commit(len, batch)?;
+        }
+        Ok(())
+    }
+
+    /// Start a handshake right away, even while a session is up.
+    pub async fn handshake(&self, batch: &mut SendBatch) -> Result<()> {
+        if self.endpoint().is_none() {
+            return Err(anyhow!("Peer has no endpoint"));
+        }
+        if self.is_removed() {
+            return Err(anyhow!("Peer was removed"));
+        }
+        if self.is_suspended() {
+            return Err(anyhow!("Peer is suspended"));
+        }
+        self.reset_backoff();
+        if let Some(tunn) = &self.tunn {
+            self.make_room(batch).await?;
+            let result = tunn
+                .lock()
+                .await
+                .format_handshake_initiation(batch.slot(), true);
+            let len = self.handle_routine_task_result(result)?;
+            self.commit(len, batch)?;
+        }
+        Ok(())
+    }
+
+    pub async fn stats(&self) -> PeerStats {
+        match &self.tunn {
+            Some(tunn) => {
+                let (last_handshake, tx_bytes, rx_bytes, _, _) = tunn.lock().await.stats();
+                PeerStats {
+                    last_handshake,
+                    tx_bytes,
+                    rx_bytes,
+                }
+            }
+            None => PeerStats::default(),
+        }
+    }
+
+    /// Send an initiation to `endpoint`, an address a rendezvous server
+    /// reported, so the NAT in front of this side lets the peer's packets
+    /// from there through. It becomes the endpoint once the peer answers from
+    /// it. An established session is left alone.
+    pub async fn punch(&self, endpoint: SocketAddr, batch: &mut SendBatch) -> Result<()> {
+        if self.is_removed() || self.is_suspended() || self.status().state == PeerState::Established
+        {
+            return Ok(());
+        }
+        if self.endpoint().is_none() {
+            self.update_endpoint(endpoint);
+        }
+        self.reset_backoff();
+        if let Some(tunn) = &self.tunn {
+            self.make_room(batch).await?;
+            let result = tunn
+                .lock()
+                .await
+                .format_handshake_initiation(batch.slot(), true);
+            if let Some(len) = self.handle_routine_task_result(result)? {
+                batch.commit(len, endpoint, None);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send every packet still waiting in `batch`.
+    pub async fn flush(&self, batch: &mut SendBatch) -> Result<()> {
+        let transport = self
+            .send_socket
+            .as_deref()
+            .ok_or_else(|| anyhow!("Transport not found"))?;
+        transport::send(transport, batch)
+            .await
+            .map_err(|e| anyhow!("Send to network failed: {e}"))?;
+        Ok(())
+    }
+
+    /// Write every packet still waiting in `tun_batch` to the device.
+    pub async fn flush_tun(&self, tun_batch: &mut TunBatch) -> Result<()> {
+        self.send_device
+            .as_ref()
+            .ok_or_else(|| anyhow!("Tun device not found"))?
+            .send(tun_batch)
+            .await
+            .map_err(|e| anyhow!("Send to tun dev failed: {e}"))?;
+        Ok(())
+    }
+
+    fn destination(&self) -> Result<(SocketAddr, Option<IpAddr>)> {
+        let endpoint = self.endpoint().ok_or_else(|| anyhow!("Missing endpoint"))?;
+        Ok((endpoint, self.source()))
+    }
+
+    fn count_handshake(&self, packet: &[u8]) {
+        if packet.first() == Some(&HANDSHAKE_INIT) {
+            self.failed_handshakes.fetch_add(1, Ordering::Relaxed);
+            // A rekey keeps the session established meanwhile
+            self.update_connection(|connection| {
+                if connection.state != PeerState::Established {
+                    connection.state = PeerState::Handshaking;
+                    connection.next_attempt = None;
+                }
+            });
+            if self.endpoints().len() > 1 {
+                *self.race.lock().unwrap_or_else(|e| e.into_inner()) = Some(EndpointRace {
+                    packet: packet.to_vec(),
+                    sent: 0,
+                    last: Instant::now(),
+                });
+            }
+        }
+    }
+
+    fn established(&self) {
+        self.update_connection(|connection| {
+            connection.state = PeerState::Established;
+            connection.failed_attempts = 0;
+            connection.next_attempt = None;
+        });
+    }
 
-                self.handle_routine_task_result(tunn, result).await?;
+    /// Tunn gave up on the handshake or the session. A failed handshake is
+    /// retried after a backoff, an expired session only with persistent
+    /// keepalive.
+    fn handshake_expired(&self) {
+        let keepalive = self.persistent_keepalive.is_some();
+        let attempts = self.handshake_attempts;
+        self.update_connection(|connection| match connection.state {
+            PeerState::Handshaking => {
+                connection.failed_attempts += 1;
+                connection.state = PeerState::Unreachable;
+                connection.next_attempt = match attempts {
+                    Some(attempts) if connection.failed_attempts >= attempts => None,
+                    _ => Some(Instant::now() + retry_backoff(connection.failed_attempts)),
+                };
             }
-            TunnResult::Err(e) => {
-                Err(anyhow!("Handle failed: {:?}", e))?;
+            PeerState::Established => {
+                connection.state = PeerState::Expired;
+                connection.next_attempt = keepalive.then(Instant::now);
+            }
+            _ => {}
+        });
+    }
+
+    fn attempt_due(&self) -> bool {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        matches!(
+            connection.state,
+            PeerState::Expired | PeerState::Unreachable
+        ) && connection
+            .next_attempt
+            .is_some_and(|next_attempt| next_attempt <= Instant::now())
+    }
+
+    /// Apply `update` and report the new status if the state changed.
+    fn update_connection(&self, update: impl FnOnce(&mut Connection)) {
+        let changed = {
+            let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+            let previous = connection.state;
+            // Only lifting the suspension or removing the peer ends it
+            if previous == PeerState::Removed
+                || (previous == PeerState::Suspended && self.is_suspended() && !self.is_removed())
+            {
+                return;
             }
-            TunnResult::Done => {}
-            other => {
-                Err(anyhow!(
-                    "handle_routine_task unexpected result: {:?}",
-                    other
-                ))?;
+            update(&mut connection);
+            previous != connection.state
+        };
+        if let (true, Some(events)) = (changed, &self.events) {
+            // Nobody listening is fine
+            let _ = events.send(PeerEvent {
+                public_key: self.public_key,
+                status: self.status(),
+            });
+        }
+    }
+
+    /// Stick with `endpoint`, which just proved to reach the peer.
+    fn confirm_endpoint(&self, endpoint: SocketAddr) {
+        self.race.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if self.endpoint() != Some(endpoint) {
+            self.update_endpoint(endpoint);
+        }
+    }
+
+    /// Send the pending initiation on to the next endpoint, when the ones it
+    /// went to stayed silent for [`ENDPOINT_RACE_DELAY`]. The initiation is
+    /// the same, so the peer answers whichever copy reaches it first.
+    pub async fn race_endpoints(&self, batch: &mut SendBatch) -> Result<()> {
+        if self.is_removed() || self.is_suspended() {
+            return Ok(());
+        }
+        let (endpoint, packet) = {
+            let mut race = self.race.lock().unwrap_or_else(|e| e.into_inner());
+            let state = match race.as_mut() {
+                Some(state) if state.last.elapsed() >= ENDPOINT_RACE_DELAY => state,
+                _ => return Ok(()),
+            };
+            let current = self.endpoint();
+            let next = self
+                .endpoints()
+                .into_iter()
+                .filter(|endpoint| Some(*endpoint) != current)
+                .nth(state.sent);
+            match next {
+                Some(next) => {
+                    state.sent += 1;
+                    state.last = Instant::now();
+                    (next, state.packet.clone())
+                }
+                None => {
+                    race.take();
+                    return Ok(());
+                }
             }
+        };
+        self.make_room(batch).await?;
+        batch.slot()[..packet.len()].copy_from_slice(&packet);
+        batch.commit(packet.len(), endpoint, None);
+        Ok(())
+    }
+
+    fn count_stat(&self, count: fn(&HandshakeStats)) {
+        if let Some(handshake_stats) = &self.handshake_stats {
+            count(handshake_stats);
+        }
+    }
+
+    async fn make_room(&self, batch: &mut SendBatch) -> Result<()> {
+        if batch.is_full() {
+            self.flush(batch).await?;
+        }
+        Ok(())
+    }
+
+    fn commit(&self, len: Option<usize>, batch: &mut SendBatch) -> Result<()> {
+        if let Some(len) = len {
+            let (endpoint, source) = self.destination()?;
+            batch.commit(len, endpoint, source);
+        }
+        Ok(())
+    }
+
+    /// Packets queued while waiting for a handshake are released one by one
+    /// once a session is established.
+    async fn send_queued_packets(
+        &self,
+        tunn: &Mutex<Tunn>,
+        endpoint: SocketAddr,
+        source: Option<IpAddr>,
+        batch: &mut SendBatch,
+    ) -> Result<()> {
+        loop {
+            self.make_room(batch).await?;
+            let len = match tunn.lock().await.decapsulate(None, &[], batch.slot()) {
+                TunnResult::WriteToNetwork(packet) => packet.len(),
+                _ => break,
+            };
+            batch.commit(len, endpoint, source);
         }
         Ok(())
     }
+
+    fn handle_routine_task_result(&self, result: TunnResult<'_>) -> Result<Option<usize>> {
+        match result {
+            TunnResult::WriteToNetwork(packet) => {
+                self.count_handshake(packet);
+                Ok(Some(packet.len()))
+            }
+            TunnResult::Err(e) => Err(anyhow!("Handle failed: {:?}", e)),
+            TunnResult::Done => Ok(None),
+            other => Err(anyhow!(
+                "handle_routine_task unexpected result: {:?}",
+                other
+            )),
+        }
+    }
+}
+
+/// A handshake initiation on its way to the endpoints of a peer, one after
+/// the other.
+struct EndpointRace {
+    packet: Vec<u8>,
+    /// Endpoints besides the current one the initiation was sent to.
+    sent: usize,
+    last: Instant,
+}
+
+/// The wait before the next handshake after `failed_attempts` timed out,
+/// with up to a quarter more at random so peers don't retry in lockstep.
+fn retry_backoff(failed_attempts: u32) -> Duration {
+    let exponent = failed_attempts.saturating_sub(1).min(16);
+    let delay = RETRY_BACKOFF_BASE
+        .saturating_mul(1 << exponent)
+        .min(RETRY_BACKOFF_MAX);
+    let jitter = rand::rng().random_range(0..=delay.as_millis() as u64 / 4);
+    delay + Duration::from_millis(jitter)
 }
 
-fn endpoint_socket_addr(endpoint: &str) -> Result<SocketAddr> {
-    let socket_addr = endpoint
-        .to_socket_addrs()
-        .map_err(|e| anyhow!("Parse socket address failed: {}", e))?
-        .next()
-        .ok_or(anyhow!("No addresses found"))?;
-    Ok(socket_addr)
+/// Alternate address families, starting with the first one, so an endpoint
+/// of the other family is tried early when one family is broken.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_ipv6 = addrs.first().is_some_and(|addr| addr.is_ipv6());
+    let (mut first, mut second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_ipv6);
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    first.reverse();
+    second.reverse();
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+    interleaved
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use std::time::Instant;
+
+    use tokio::sync::broadcast;
+
+    use super::{
+        interleave_families, retry_backoff, Peer, PeerState, HANDSHAKE_INIT, RETRY_BACKOFF_BASE,
+        RETRY_BACKOFF_MAX,
+    };
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn endpoints_follow_the_configured_order() {
+        let mut peer = Peer::new().unwrap();
+        peer.add_endpoint("192.0.2.1:51820").unwrap();
+        peer.add_endpoint("gateway.example:51820").unwrap();
+        peer.add_endpoint("[2001:db8::1]:51820").unwrap();
+        assert!(peer.add_endpoint("gateway.example").is_err());
+        assert_eq!(
+            peer.endpoints(),
+            addrs(&["192.0.2.1:51820", "[2001:db8::1]:51820"])
+        );
+        assert_eq!(peer.endpoint(), Some(addrs(&["192.0.2.1:51820"])[0]));
+
+        let resolved = addrs(&["192.0.2.2:51820", "192.0.2.1:51820"]);
+        peer.set_resolved("gateway.example:51820", resolved);
+        assert_eq!(
+            peer.endpoints(),
+            addrs(&["192.0.2.1:51820", "192.0.2.2:51820", "[2001:db8::1]:51820"])
+        );
+
+        // Replacing the endpoints moves off an address that is gone
+        peer.set_endpoint("[2001:db8::2]:51820").unwrap();
+        assert_eq!(peer.endpoint(), Some(addrs(&["[2001:db8::2]:51820"])[0]));
+    }
+
+    #[test]
+    fn address_families_alternate() {
+        let resolved = addrs(&[
+            "[2001:db8::1]:1",
+            "[2001:db8::2]:1",
+            "[2001:db8::3]:1",
+            "192.0.2.1:1",
+        ]);
+        assert_eq!(
+            interleave_families(resolved),
+            addrs(&[
+                "[2001:db8::1]:1",
+                "192.0.2.1:1",
+                "[2001:db8::2]:1",
+                "[2001:db8::3]:1",
+            ])
+        );
+    }
+
+    #[test]
+    fn retry_backoff_doubles_up_to_the_maximum() {
+        for (failed_attempts, base) in [(1, RETRY_BACKOFF_BASE), (3, RETRY_BACKOFF_BASE * 4)] {
+            let backoff = retry_backoff(failed_attempts);
+            assert!(backoff >= base && backoff <= base * 5 / 4, "{backoff:?}");
+        }
+        let backoff = retry_backoff(u32::MAX);
+        assert!(backoff >= RETRY_BACKOFF_MAX && backoff <= RETRY_BACKOFF_MAX * 5 / 4);
+    }
+
+    #[test]
+    fn failed_handshakes_back_off_until_the_attempt_limit() {
+        let mut peer = Peer::new().unwrap();
+        peer.set_handshake_attempts(2).unwrap();
+        let (events, mut rx) = broadcast::channel(16);
+        peer.set_events(events).unwrap();
+        assert_eq!(peer.status().state, PeerState::Idle);
+
+        peer.count_handshake(&[HANDSHAKE_INIT]);
+        assert_eq!(rx.try_recv().unwrap().status.state, PeerState::Handshaking);
+        peer.handshake_expired();
+        let status = rx.try_recv().unwrap().status;
+        assert_eq!(status.state, PeerState::Unreachable);
+        assert_eq!(status.failed_attempts, 1);
+        assert!(status.next_attempt.unwrap() > Instant::now());
+        assert!(!peer.attempt_due());
+
+        // Expired stays reported while unreachable, without counting again
+        peer.handshake_expired();
+        assert_eq!(peer.status().failed_attempts, 1);
+
+        peer.count_handshake(&[HANDSHAKE_INIT]);
+        peer.handshake_expired();
+        let status = peer.status();
+        assert_eq!(status.failed_attempts, 2);
+        assert_eq!(status.next_attempt, None);
+
+        peer.reset_backoff();
+        assert_eq!(peer.status().failed_attempts, 0);
+        assert!(peer.attempt_due());
+    }
+
+    #[test]
+    fn expired_session_is_restarted_only_with_keepalive() {
+        for keepalive in [None, Some(25)] {
+            let mut peer = Peer::new().unwrap();
+            peer.persistent_keepalive = keepalive;
+            peer.established();
+            peer.handshake_expired();
+            assert_eq!(peer.status().state, PeerState::Expired);
+            assert_eq!(peer.attempt_due(), keepalive.is_some());
+        }
+    }
 }
@@ -0,0 +1,479 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{watch, Mutex},
+};
+
+use crate::{
+    socks5::{
+        encode_address, parse_datagram, read_target, write_datagram_header, Target, AUTH_VERSION,
+        COMMAND_NOT_SUPPORTED, NO_ACCEPTABLE_METHODS, NO_AUTH, SUCCEEDED, UDP_ASSOCIATE,
+        USERNAME_PASSWORD, VERSION,
+    },
+    transport::Transport,
+    udp::{RecvBatch, SendBatch},
+};
+
+const SCHEME: &str = "socks5://";
+const ASSOCIATE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait after a failed association, doubled after each further one.
+const REASSOCIATE_DELAY_MIN: Duration = Duration::from_secs(1);
+const REASSOCIATE_DELAY_MAX: Duration = Duration::from_secs(30);
+
+/// An upstream SOCKS5 proxy, configured as
+/// `socks5://[username:password@]host:port`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Proxy {
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Proxy {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix(SCHEME)
+            .ok_or(anyhow!("Proxy must be a {SCHEME} URL: {url}"))?;
+        let (credentials, address) = match rest.rsplit_once('@') {
+            Some((credentials, address)) => (Some(credentials), address),
+            None => (None, rest),
+        };
+        if address
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse::<u16>().ok())
+            .is_none()
+        {
+            return Err(anyhow!("Proxy address needs a port: {address}"));
+        }
+        let (username, password) = match credentials {
+            Some(credentials) => {
+                let (username, password) = credentials
+                    .split_once(':')
+                    .ok_or(anyhow!("Proxy credentials must be username:password"))?;
+                // Both are sent with a one byte length
+                if username.is_empty() || username.len() > 255 || password.len() > 255 {
+                    return Err(anyhow!(
+                        "Proxy username must be 1 to 255 bytes and password at most 255"
+                    ));
+                }
+                (Some(username.to_string()), Some(password.to_string()))
+            }
+            None => (None, None),
+        };
+        Ok(Self {
+            address: address.to_string(),
+            username,
+            password,
+        })
+    }
+}
+
+/// Sends every datagram of another transport through the UDP relay of a
+/// SOCKS5 proxy, and receives only what that relay forwards back. The
+/// association lasts as long as its TCP connection to the proxy. When the
+/// proxy closes it, sends fail until a new one is made.
+pub struct ProxyTransport {
+    inner: Arc<dyn Transport>,
+    relay: watch::Receiver<Option<SocketAddr>>,
+    out: Mutex<SendBatch>,
+}
+
+impl ProxyTransport {
+    /// Associate with `proxy`, failing if it can't be reached, rejects the
+    /// credentials or does not relay UDP.
+    pub async fn connect(inner: Arc<dyn Transport>, proxy: Proxy) -> Result<Self> {
+        let (control, relay) = associate(&proxy).await?;
+        println!("Relaying through SOCKS5 proxy {} at {relay}", proxy.address);
+        let (relay_tx, relay) = watch::channel(Some(relay));
+        tokio::spawn(maintain(proxy, control, relay_tx));
+        Ok(Self {
+            inner,
+            relay,
+            out: Mutex::new(SendBatch::new()),
+        })
+    }
+
+    /// The relay of the current association, if there is one.
+    pub fn relay(&self) -> Option<SocketAddr> {
+        *self.relay.borrow()
+    }
+}
+
+#[async_trait]
+impl Transport for ProxyTransport {
+    async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()> {
+        let Some(relay) = self.relay() else {
+            batch.clear();
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "SOCKS5 proxy association is down",
+            ));
+        };
+        let mut out = self.out.lock().await;
+        for (packet, endpoint, source) in batch.packets() {
+            let slot = out.slot();
+            let header = write_datagram_header(slot, endpoint);
+            slot[header..header + packet.len()].copy_from_slice(packet);
+            out.commit(header + packet.len(), relay, source);
+        }
+        batch.clear();
+        let result = self.inner.send_batch(&mut out).await;
+        out.clear();
+        result
+    }
+
+    async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        self.inner.recv_batch(batch).await?;
+        let relay = self.relay();
+        batch.retain_mut(|buf, endpoint| {
+            if relay != Some(*endpoint) {
+                return false;
+            }
+            let Some((Target::Addr(from), offset)) = parse_datagram(buf) else {
+                return false;
+            };
+            let len = buf.len();
+            buf.copy_within(offset.., 0);
+            buf.set_len(len - offset);
+            *endpoint = from;
+            true
+        });
+        Ok(())
+    }
+
+    async fn rebind(&self) -> io::Result<()> {
+        self.inner.rebind().await
+    }
+}
+
+/// Associate again whenever the proxy closes the association, until the
+/// transport is dropped.
+async fn maintain(proxy: Proxy, mut control: TcpStream, relay: watch::Sender<Option<SocketAddr>>) {
+    loop {
+        tokio::select! {
+            _ = closed(&mut control) => {}
+            _ = relay.closed() => return,
+        }
+        relay.send_replace(None);
+        println!(
+            "SOCKS5 proxy {} closed the association, reconnecting",
+            proxy.address
+        );
+        let mut delay = REASSOCIATE_DELAY_MIN;
+        control = loop {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = relay.closed() => return,
+            }
+            match associate(&proxy).await {
+                Ok((control, address)) => {
+                    relay.send_replace(Some(address));
+                    break control;
+                }
+                Err(e) => println!("{e}"),
+            }
+            delay = (delay * 2).min(REASSOCIATE_DELAY_MAX);
+        };
+    }
+}
+
+/// Wait until the proxy closes `control`. It sends nothing on it after the
+/// reply.
+async fn closed(control: &mut TcpStream) {
+    let mut buf = [0u8; 64];
+    while let Ok(len) = control.read(&mut buf).await {
+        if len == 0 {
+            return;
+        }
+    }
+}
+
+/// Open a UDP association, returning its control connection and relay.
+async fn associate(proxy: &Proxy) -> Result<(TcpStream, SocketAddr)> {
+    tokio::time::timeout(ASSOCIATE_TIMEOUT, async {
+        let mut stream = TcpStream::connect(&proxy.address)
+            .await
+            .map_err(|e| anyhow!("Connect to SOCKS5 proxy {} failed: {e}", proxy.address))?;
+        let relay = request_association(&mut stream, proxy)
+            .await
+            .map_err(|e| anyhow!("SOCKS5 proxy {}: {e}", proxy.address))?;
+        // An unspecified relay address means the proxy's own
+        let relay = match relay.ip().is_unspecified() {
+            true => SocketAddr::new(stream.peer_addr()?.ip(), relay.port()),
+            false => relay,
+        };
+        Ok((stream, relay))
+    })
+    .await
+    .map_err(|_| anyhow!("SOCKS5 proxy {} timed out", proxy.address))?
+}
+
+async fn request_association(stream: &mut TcpStream, proxy: &Proxy) -> Result<SocketAddr> {
+    authenticate(stream, proxy).await?;
+
+    // The datagrams' source is not known in advance
+    let mut request = vec![VERSION, UDP_ASSOCIATE, 0];
+    encode_address(&mut request, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 3];
+    stream.read_exact(&mut reply).await?;
+    match reply[1] {
+        SUCCEEDED => {}
+        COMMAND_NOT_SUPPORTED => return Err(anyhow!("UDP ASSOCIATE is not supported")),
+        code => return Err(anyhow!("UDP ASSOCIATE failed with reply {code}")),
+    }
+    match read_target(stream).await? {
+        Target::Addr(relay) => Ok(relay),
+        Target::Domain(name, _) => Err(anyhow!("Relay named by domain {name} is not supported")),
+    }
+}
+
+async fn authenticate(stream: &mut TcpStream, proxy: &Proxy) -> Result<()> {
+    let credentials = proxy.username.as_ref().zip(proxy.password.as_ref());
+    let mut greeting = vec![VERSION, 1, NO_AUTH];
+    if credentials.is_some() {
+        greeting[1] = 2;
+        greeting.push(USERNAME_PASSWORD);
+    }
+    stream.write_all(&greeting).await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    match (choice[1], credentials) {
+        (NO_AUTH, _) => Ok(()),
+        (USERNAME_PASSWORD, Some((username, password))) => {
+            let mut request = vec![AUTH_VERSION, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            match status[1] {
+                0 => Ok(()),
+                _ => Err(anyhow!("Username and password rejected")),
+            }
+        }
+        (NO_ACCEPTABLE_METHODS, None) | (USERNAME_PASSWORD, None) => {
+            Err(anyhow!("Authentication required"))
+        }
+        _ => Err(anyhow!("No acceptable authentication method")),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{Proxy, ProxyTransport};
+    use crate::{
+        buffer::BufferPool,
+        socks5::{
+            encode_address, parse_datagram, read_target, Target, COMMAND_NOT_SUPPORTED,
+            NO_ACCEPTABLE_METHODS, SUCCEEDED, USERNAME_PASSWORD, VERSION,
+        },
+        transport::{ChannelNetwork, ChannelTransport, Transport},
+        udp::{RecvBatch, SendBatch},
+    };
+
+    pub(crate) const USERNAME: &str = "user";
+    pub(crate) const PASSWORD: &str = "secret";
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A SOCKS5 proxy that asks for [`USERNAME`] and [`PASSWORD`] and relays
+    /// datagrams of `client` through `relay`, or refuses to if `udp` is
+    /// false. Returns the address of its TCP listener.
+    pub(crate) async fn stand_in(
+        relay: ChannelTransport,
+        client: SocketAddr,
+        udp: bool,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let relay_address = relay.local_addr();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if serve(&mut stream, relay_address, udp).await.is_ok() {
+                        // The association lasts until the client hangs up
+                        let _ = stream.read(&mut [0u8; 1]).await;
+                    }
+                });
+            }
+        });
+        tokio::spawn(async move {
+            let mut batch = RecvBatch::new(BufferPool::new(16));
+            while relay.recv_batch(&mut batch).await.is_ok() {
+                let mut out = SendBatch::new();
+                for (buf, from, _) in batch.drain() {
+                    let slot = out.slot();
+                    if from == client {
+                        if let Some((Target::Addr(target), offset)) = parse_datagram(&buf) {
+                            slot[..buf.len() - offset].copy_from_slice(&buf[offset..]);
+                            out.commit(buf.len() - offset, target, None);
+                        }
+                    } else {
+                        let mut header = vec![0, 0, 0];
+                        encode_address(&mut header, from);
+                        slot[..header.len()].copy_from_slice(&header);
+                        slot[header.len()..header.len() + buf.len()].copy_from_slice(&buf);
+                        out.commit(header.len() + buf.len(), client, None);
+                    }
+                }
+                let _ = relay.send_batch(&mut out).await;
+            }
+        });
+        address
+    }
+
+    async fn serve(stream: &mut TcpStream, relay: SocketAddr, udp: bool) -> std::io::Result<()> {
+        let mut greeting = [0u8; 2];
+        stream.read_exact(&mut greeting).await?;
+        let mut methods = vec![0u8; greeting[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        if !methods.contains(&USERNAME_PASSWORD) {
+            stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+            return Err(std::io::ErrorKind::PermissionDenied.into());
+        }
+        stream.write_all(&[VERSION, USERNAME_PASSWORD]).await?;
+
+        stream.read_u8().await?;
+        let username = read_bytes(stream).await?;
+        let password = read_bytes(stream).await?;
+        if username != USERNAME.as_bytes() || password != PASSWORD.as_bytes() {
+            stream.write_all(&[1, 1]).await?;
+            return Err(std::io::ErrorKind::PermissionDenied.into());
+        }
+        stream.write_all(&[1, 0]).await?;
+
+        let mut request = [0u8; 3];
+        stream.read_exact(&mut request).await?;
+        read_target(stream).await?;
+        let mut reply = vec![VERSION, SUCCEEDED, 0];
+        if !udp {
+            reply[1] = COMMAND_NOT_SUPPORTED;
+        }
+        encode_address(&mut reply, relay);
+        stream.write_all(&reply).await?;
+        match udp {
+            true => Ok(()),
+            false => Err(std::io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    async fn read_bytes(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+        let len = stream.read_u8().await?;
+        let mut buf = vec![0u8; len as usize];
+        stream.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    fn proxy(address: SocketAddr, password: &str) -> Proxy {
+        Proxy::parse(&format!("socks5://{USERNAME}:{password}@{address}")).unwrap()
+    }
+
+    async fn send(transport: &impl Transport, packet: &[u8], endpoint: SocketAddr) {
+        let mut batch = SendBatch::new();
+        batch.slot()[..packet.len()].copy_from_slice(packet);
+        batch.commit(packet.len(), endpoint, None);
+        transport.send_batch(&mut batch).await.unwrap();
+    }
+
+    async fn recv(transport: &impl Transport) -> (Vec<u8>, SocketAddr) {
+        let mut batch = RecvBatch::new(BufferPool::new(4));
+        tokio::time::timeout(TIMEOUT, transport.recv_batch(&mut batch))
+            .await
+            .unwrap()
+            .unwrap();
+        let (buf, from, _) = batch.drain().next().unwrap();
+        (buf.to_vec(), from)
+    }
+
+    #[test]
+    fn proxy_urls_are_parsed() {
+        assert_eq!(
+            Proxy::parse("socks5://user:pa:ss@proxy.example:1080").unwrap(),
+            Proxy {
+                address: "proxy.example:1080".to_string(),
+                username: Some("user".to_string()),
+                password: Some("pa:ss".to_string()),
+            }
+        );
+        assert_eq!(Proxy::parse("socks5://[::1]:1080").unwrap().username, None);
+        assert!(Proxy::parse("http://proxy.example:8080").is_err());
+        assert!(Proxy::parse("socks5://proxy.example").is_err());
+        assert!(Proxy::parse("socks5://user@proxy.example:1080").is_err());
+    }
+
+    #[tokio::test]
+    async fn datagrams_are_relayed_through_the_proxy() {
+        let network = ChannelNetwork::new();
+        let client = network.attach("192.0.2.1:51820".parse().unwrap());
+        let relay = network.attach("192.0.2.9:1080".parse().unwrap());
+        let peer = network.attach("192.0.2.2:51820".parse().unwrap());
+        let address = stand_in(relay, client.local_addr(), true).await;
+
+        let transport = ProxyTransport::connect(Arc::new(client), proxy(address, PASSWORD))
+            .await
+            .unwrap();
+        assert_eq!(transport.relay(), Some("192.0.2.9:1080".parse().unwrap()));
+
+        send(&transport, b"hello", peer.local_addr()).await;
+        let (packet, from) = recv(&peer).await;
+        assert_eq!(
+            (packet.as_slice(), from),
+            (&b"hello"[..], "192.0.2.9:1080".parse().unwrap())
+        );
+
+        send(&peer, b"welcome", from).await;
+        assert_eq!(
+            recv(&transport).await,
+            (b"welcome".to_vec(), peer.local_addr())
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_without_udp_support_is_an_error() {
+        let network = ChannelNetwork::new();
+        let client = network.attach("192.0.2.1:51820".parse().unwrap());
+        let relay = network.attach("192.0.2.9:1080".parse().unwrap());
+        let address = stand_in(relay, client.local_addr(), false).await;
+
+        let error = ProxyTransport::connect(Arc::new(client), proxy(address, PASSWORD))
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("UDP ASSOCIATE is not supported"));
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_rejected() {
+        let network = ChannelNetwork::new();
+        let client = network.attach("192.0.2.1:51820".parse().unwrap());
+        let relay = network.attach("192.0.2.9:1080".parse().unwrap());
+        let address = stand_in(relay, client.local_addr(), true).await;
+
+        let error = ProxyTransport::connect(Arc::new(client), proxy(address, "wrong"))
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("rejected"));
+    }
+}
@@ -0,0 +1,307 @@
+//! Data limits and expiry of peers, and the state file that keeps their
+//! usage across restarts.
+//!
+//! A peer with a [`DataLimit`] may move that many bytes through the tunnel,
+//! both directions together, per period. The first period starts when the
+//! peer is first seen, the next ones follow back to back. A peer over its
+//! limit or past its `ExpiresAt` is suspended: its session is closed and its
+//! packets are dropped, until the next period or a new configuration.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// Time between writes of the state file, and so the usage lost at most on a
+/// crash.
+pub const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DataLimit {
+    pub bytes: u64,
+    pub period: Duration,
+}
+
+impl DataLimit {
+    /// `<amount>/<period>`, e.g. `10G/30d`. Amounts take a `K`, `M`, `G` or
+    /// `T` suffix, powers of 1024, periods an `s`, `m`, `h`, `d` or `w` one.
+    pub fn parse(data_limit: &str) -> Result<Self> {
+        let (bytes, period) = data_limit.split_once('/').ok_or(anyhow!(
+            "Parse data limit failed: missing period in {data_limit}"
+        ))?;
+        let bytes = parse_bytes(bytes.trim()).ok_or(anyhow!(
+            "Parse data limit failed: bad amount in {data_limit}"
+        ))?;
+        let period = parse_amount(
+            period.trim(),
+            &[
+                ('s', 1),
+                ('m', 60),
+                ('h', 3600),
+                ('d', 86400),
+                ('w', 604800),
+            ],
+        )
+        .filter(|period| *period > 0)
+        .ok_or(anyhow!(
+            "Parse data limit failed: bad period in {data_limit}"
+        ))?;
+        Ok(Self {
+            bytes,
+            period: Duration::from_secs(period),
+        })
+    }
+}
+
+/// Bytes with an optional `K`, `M`, `G` or `T` suffix, powers of 1024.
+pub(crate) fn parse_bytes(bytes: &str) -> Option<u64> {
+    parse_amount(
+        bytes,
+        &[
+            ('K', 1 << 10),
+            ('M', 1 << 20),
+            ('G', 1 << 30),
+            ('T', 1 << 40),
+        ],
+    )
+}
+
+/// A number with an optional unit suffix.
+fn parse_amount(amount: &str, units: &[(char, u64)]) -> Option<u64> {
+    let (number, unit) = match units.iter().find(|(suffix, _)| amount.ends_with(*suffix)) {
+        Some((suffix, unit)) => (&amount[..amount.len() - suffix.len_utf8()], *unit),
+        None => (amount, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// `YYYY-MM-DD`, midnight, or `YYYY-MM-DDTHH:MM:SSZ`, both in UTC.
+pub fn parse_time(time: &str) -> Result<SystemTime> {
+    let error = || anyhow!("Parse time failed: {time}");
+    let (date, clock) = match time.split_once('T') {
+        Some((date, clock)) => (date, Some(clock.strip_suffix('Z').ok_or_else(error)?)),
+        None => (time, None),
+    };
+    let date = date
+        .split('-')
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(error)?;
+    let [year, month, day] = date[..] else {
+        return Err(error());
+    };
+    if !(1970..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+    {
+        return Err(error());
+    }
+    let mut secs = days_from_civil(year, month, day) * 86400;
+    if let Some(clock) = clock {
+        let clock = clock
+            .split(':')
+            .map(|part| part.parse::<u64>().ok())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(error)?;
+        let [hour, minute, second] = clock[..] else {
+            return Err(error());
+        };
+        if hour > 23 || minute > 59 || second > 59 {
+            return Err(error());
+        }
+        secs += hour * 3600 + minute * 60 + second;
+    }
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01, after Howard Hinnant's `days_from_civil`.
+fn days_from_civil(year: u32, month: u32, day: u32) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year } as u64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month = month as u64;
+    let day_of_year =
+        (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// What a peer used of its data limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Unix time the current period started at.
+    pub period_start: u64,
+    pub bytes: u64,
+}
+
+impl Usage {
+    /// The usage at `now`, which is in a later period once `period` passed.
+    pub fn at(self, period: Duration, now: u64) -> Self {
+        let period = period.as_secs().max(1);
+        if now < self.period_start + period {
+            return self;
+        }
+        Self {
+            period_start: self.period_start + (now - self.period_start) / period * period,
+            bytes: 0,
+        }
+    }
+}
+
+/// The usage of every peer, by public key, as last saved. Without a path it
+/// is only kept while running, for peers that are replaced.
+pub(crate) struct StateFile {
+    path: Option<PathBuf>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl StateFile {
+    /// Read `path`, a missing file is an empty one.
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self {
+                path: None,
+                usage: Mutex::new(HashMap::new()),
+            });
+        };
+        let usage = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| anyhow!("Parse state file failed: {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(anyhow!("Read state file failed: {}: {e}", path.display())),
+        };
+        Ok(Self {
+            path: Some(path),
+            usage: Mutex::new(usage),
+        })
+    }
+
+    pub fn get(&self, public_key: &str) -> Option<Usage> {
+        self.usage
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(public_key)
+            .copied()
+    }
+
+    pub fn set(&self, public_key: String, usage: Usage) {
+        self.usage
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(public_key, usage);
+    }
+
+    /// Write the file, through a temporary one so a crash never leaves half
+    /// of it behind.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let content = {
+            let usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+            serde_json::to_string_pretty(&*usage)?
+        };
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, content)
+            .and_then(|_| fs::rename(&temporary, path))
+            .map_err(|e| anyhow!("Write state file failed: {}: {e}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{parse_time, DataLimit, StateFile, Usage};
+
+    #[test]
+    fn data_limits_parse_with_units() {
+        let limit = DataLimit::parse("10G/30d").unwrap();
+        assert_eq!(limit.bytes, 10 << 30);
+        assert_eq!(limit.period, Duration::from_secs(30 * 86400));
+        let limit = DataLimit::parse("1500/1h").unwrap();
+        assert_eq!(limit.bytes, 1500);
+        assert_eq!(limit.period, Duration::from_secs(3600));
+        assert!(DataLimit::parse("10G").is_err());
+        assert!(DataLimit::parse("10X/1d").is_err());
+        assert!(DataLimit::parse("10G/0d").is_err());
+    }
+
+    #[test]
+    fn times_parse_as_utc() {
+        let time = parse_time("2026-03-01").unwrap();
+        assert_eq!(time, UNIX_EPOCH + Duration::from_secs(1772323200));
+        let time = parse_time("2020-01-01").unwrap();
+        assert_eq!(time, UNIX_EPOCH + Duration::from_secs(1577836800));
+        let time = parse_time("2000-02-29T12:30:15Z").unwrap();
+        assert_eq!(time, UNIX_EPOCH + Duration::from_secs(951827415));
+        assert!(parse_time("2026-13-01").is_err());
+        assert!(parse_time("2026-02-31").is_err());
+        assert!(parse_time("2026-04-31").is_err());
+        assert!(parse_time("2100-02-29").is_err());
+        assert!(parse_time("2024-02-29").is_ok());
+        assert!(parse_time("2026-03-01T12:00:00").is_err());
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn usage_starts_over_each_period() {
+        let period = Duration::from_secs(100);
+        let usage = Usage {
+            period_start: 1000,
+            bytes: 50,
+        };
+        assert_eq!(usage.at(period, 1099), usage);
+        assert_eq!(
+            usage.at(period, 1100),
+            Usage {
+                period_start: 1100,
+                bytes: 0,
+            }
+        );
+        // Idle periods are skipped
+        assert_eq!(usage.at(period, 1350).period_start, 1300);
+    }
+
+    #[test]
+    fn state_file_keeps_usage() {
+        let path = std::env::temp_dir().join(format!("wireguard-{}.state", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let usage = Usage {
+            period_start: 1000,
+            bytes: 50,
+        };
+        let state = StateFile::load(Some(path.clone())).unwrap();
+        assert_eq!(state.get("key"), None);
+        state.set("key".to_string(), usage);
+        state.save().unwrap();
+        assert_eq!(
+            StateFile::load(Some(path.clone())).unwrap().get("key"),
+            Some(usage)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
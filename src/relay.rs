@@ -0,0 +1,183 @@
+use std::sync::{Arc, RwLock, Weak};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    buffer::{BufferPool, PacketBuffer},
+    peer::Peer,
+    runtime::PeerSet,
+};
+
+/// Which peers may reach each other through this interface, without the
+/// packet passing the tun device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RelayPolicy {
+    /// Packets between peers are written to the tun device, for the kernel
+    /// to forward.
+    #[default]
+    Off,
+    /// Every peer may reach every other.
+    All,
+    /// Peers may reach each other if they share a relay group.
+    Groups,
+}
+
+impl RelayPolicy {
+    pub fn parse(policy: &str) -> Result<Self> {
+        match policy.to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "all" => Ok(Self::All),
+            "groups" => Ok(Self::Groups),
+            other => Err(anyhow!("Relay must be off, all or groups: {other}")),
+        }
+    }
+
+    fn allows(&self, from: &Peer, to: &Peer) -> bool {
+        match self {
+            Self::Off => false,
+            Self::All => true,
+            Self::Groups => from
+                .relay_groups
+                .iter()
+                .any(|group| to.relay_groups.contains(group)),
+        }
+    }
+}
+
+/// Buffers for packets on their way between two peers. Each worker holds at
+/// most one at a time.
+const RELAY_BUFFERS: usize = 16;
+
+/// Where a packet decrypted from a peer goes.
+pub(crate) enum Hop {
+    /// To the tun device, it is for this host or for no peer.
+    Device,
+    /// Encrypted again for another peer, copied out of the slot it was
+    /// decrypted to.
+    Peer(Arc<Peer>, PacketBuffer),
+    /// Nowhere, the policy does not let the two peers talk.
+    Drop,
+}
+
+/// Forwards between peers in process, routed by the allowed IPs of the
+/// current peers.
+pub(crate) struct Relay {
+    policy: RelayPolicy,
+    // Weak, as the peers hold the relay
+    peers: Weak<RwLock<Arc<PeerSet>>>,
+    pool: Arc<BufferPool>,
+}
+
+impl Relay {
+    pub fn new(policy: RelayPolicy, peers: Weak<RwLock<Arc<PeerSet>>>) -> Self {
+        Self {
+            policy,
+            peers,
+            pool: BufferPool::new(RELAY_BUFFERS),
+        }
+    }
+
+    /// Where `packet`, decrypted from `from`, goes next.
+    pub fn next_hop(&self, from: &Peer, packet: &[u8]) -> Hop {
+        let to = self.peers.upgrade().and_then(|peers| {
+            peers
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .route(packet)
+        });
+        match to {
+            Some(to) if std::ptr::eq(from, Arc::as_ptr(&to)) => Hop::Device,
+            Some(to) if self.policy.allows(from, &to) => {
+                let mut buffer = self.pool.get();
+                buffer.set_len(packet.len());
+                buffer.copy_from_slice(packet);
+                Hop::Peer(to, buffer)
+            }
+            Some(_) => Hop::Drop,
+            None => Hop::Device,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use super::{Hop, Relay, RelayPolicy};
+    use crate::{harness::udp_packet, peer::Peer, runtime::PeerSet};
+
+    fn peer(allowed_ip: &str, groups: &[&str]) -> Arc<Peer> {
+        let mut peer = Peer::new().unwrap();
+        peer.set_allowed_ips(&[allowed_ip]).unwrap();
+        peer.set_relay_groups(groups).unwrap();
+        Arc::new(peer)
+    }
+
+    fn packet(destination: &str) -> Vec<u8> {
+        udp_packet(
+            "10.0.0.9:1000".parse().unwrap(),
+            format!("{destination}:2000").parse().unwrap(),
+            b"hello",
+        )
+    }
+
+    /// A relay among `peers`, which it knows as long as the returned set
+    /// lives.
+    fn relay(policy: RelayPolicy, peers: &[&Arc<Peer>]) -> (Relay, Arc<RwLock<Arc<PeerSet>>>) {
+        let peers = Arc::new(RwLock::new(Arc::new(PeerSet::of(peers))));
+        (Relay::new(policy, Arc::downgrade(&peers)), peers)
+    }
+
+    #[test]
+    fn packets_for_other_peers_are_relayed() {
+        let a = peer("10.0.0.2/32", &[]);
+        let b = peer("10.0.0.3/32", &[]);
+        let (relay, _peers) = relay(RelayPolicy::All, &[&a, &b]);
+
+        assert!(
+            matches!(relay.next_hop(&a, &packet("10.0.0.3")), Hop::Peer(to, _) if Arc::ptr_eq(&to, &b))
+        );
+        assert!(matches!(
+            relay.next_hop(&a, &packet("10.0.0.2")),
+            Hop::Device
+        ));
+        assert!(matches!(
+            relay.next_hop(&a, &packet("10.0.0.1")),
+            Hop::Device
+        ));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let a = peer("10.0.0.2/32", &[]);
+        let b = peer("10.0.0.0/24", &[]);
+        let c = peer("10.0.0.3/32", &[]);
+        let (relay, _peers) = relay(RelayPolicy::All, &[&a, &b, &c]);
+
+        assert!(
+            matches!(relay.next_hop(&a, &packet("10.0.0.3")), Hop::Peer(to, _) if Arc::ptr_eq(&to, &c))
+        );
+        assert!(
+            matches!(relay.next_hop(&a, &packet("10.0.0.4")), Hop::Peer(to, _) if Arc::ptr_eq(&to, &b))
+        );
+    }
+
+    #[test]
+    fn groups_keep_peers_apart() {
+        let a = peer("10.0.0.2/32", &["office", "lab"]);
+        let b = peer("10.0.0.3/32", &["lab"]);
+        let c = peer("10.0.0.4/32", &["guests"]);
+        let (relay, _peers) = relay(RelayPolicy::Groups, &[&a, &b, &c]);
+
+        assert!(matches!(
+            relay.next_hop(&a, &packet("10.0.0.3")),
+            Hop::Peer(_, _)
+        ));
+        assert!(matches!(
+            relay.next_hop(&b, &packet("10.0.0.2")),
+            Hop::Peer(_, _)
+        ));
+        assert!(matches!(relay.next_hop(&a, &packet("10.0.0.4")), Hop::Drop));
+        assert!(matches!(relay.next_hop(&c, &packet("10.0.0.3")), Hop::Drop));
+    }
+}
@@ -0,0 +1,235 @@
+//! A small rendezvous protocol, so peers behind NATs can find each other.
+//!
+//! Every peer regularly registers its public key with a rendezvous server,
+//! together with the keys of the peers it wants to reach. The server notes
+//! the address each registration came from. Once two peers want each other,
+//! it sends each the other's address, and both send handshakes to it at
+//! once, punching holes into their NATs.
+//!
+//! Messages travel over the outer socket, so the server sees the address the
+//! NAT maps WireGuard traffic to. They start with [`MAGIC`], which no
+//! WireGuard message does.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use boringtun::x25519::PublicKey;
+
+use crate::{
+    buffer::BufferPool,
+    transport::Transport,
+    udp::{RecvBatch, SendBatch, BATCH_SIZE},
+};
+
+pub const MAGIC: &[u8; 4] = b"wgrv";
+/// Time between registrations, short enough to keep NAT mappings open.
+pub const REGISTER_INTERVAL: Duration = Duration::from_secs(15);
+/// Registrations not renewed within this time are forgotten.
+const REGISTRATION_TTL: Duration = Duration::from_secs(60);
+
+const REGISTER: u8 = 1;
+const ENDPOINT: u8 = 2;
+/// Wanted keys per registration message, to stay well below the MTU.
+const MAX_WANTED: usize = 32;
+const KEY_LEN: usize = 32;
+
+pub fn is_message(packet: &[u8]) -> bool {
+    packet.starts_with(MAGIC)
+}
+
+/// The registration messages of `public_key`, wanting `wanted`.
+pub fn register(public_key: &PublicKey, wanted: &[PublicKey]) -> Vec<Vec<u8>> {
+    let message = |wanted: &[PublicKey]| {
+        let mut message = MAGIC.to_vec();
+        message.push(REGISTER);
+        message.extend_from_slice(public_key.as_bytes());
+        for key in wanted {
+            message.extend_from_slice(key.as_bytes());
+        }
+        message
+    };
+    match wanted.is_empty() {
+        true => vec![message(&[])],
+        false => wanted.chunks(MAX_WANTED).map(message).collect(),
+    }
+}
+
+/// The peer and address an endpoint message announces.
+pub fn parse_endpoint(message: &[u8]) -> Option<(PublicKey, SocketAddr)> {
+    let rest = message.strip_prefix(MAGIC)?.strip_prefix(&[ENDPOINT])?;
+    let (key, rest) = rest.split_first_chunk::<KEY_LEN>()?;
+    let (ip, rest) = match rest.split_first()? {
+        (4, rest) => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            (IpAddr::from(*ip), rest)
+        }
+        (6, rest) => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            (IpAddr::from(*ip), rest)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(*rest.first_chunk::<2>()?);
+    Some((PublicKey::from(*key), SocketAddr::new(ip, port)))
+}
+
+/// The endpoint message introducing `key` at `address`.
+pub fn endpoint(key: &[u8; KEY_LEN], address: SocketAddr) -> Vec<u8> {
+    let mut message = MAGIC.to_vec();
+    message.push(ENDPOINT);
+    message.extend_from_slice(key);
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            message.push(4);
+            message.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            message.push(6);
+            message.extend_from_slice(&ip.octets());
+        }
+    }
+    message.extend_from_slice(&address.port().to_be_bytes());
+    message
+}
+
+struct Registration {
+    address: SocketAddr,
+    wanted: HashSet<[u8; KEY_LEN]>,
+    renewed: Instant,
+}
+
+/// Keeps the registrations and introduces peers that want each other. Keys
+/// are not verified, an address only ever leads to a handshake, which the
+/// peer's key has to answer.
+#[derive(Default)]
+pub struct Registry {
+    registrations: HashMap<[u8; KEY_LEN], Registration>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a registration from `from`. Returns the introductions to
+    /// send, with their destination. Anything else is ignored.
+    pub fn handle(&mut self, message: &[u8], from: SocketAddr) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut introductions = vec![];
+        let Some((key, wanted)) = message
+            .strip_prefix(MAGIC)
+            .and_then(|rest| rest.strip_prefix(&[REGISTER]))
+            .and_then(|rest| rest.split_first_chunk::<KEY_LEN>())
+        else {
+            return introductions;
+        };
+        self.registrations
+            .retain(|_, registration| registration.renewed.elapsed() < REGISTRATION_TTL);
+
+        let wanted = wanted
+            .chunks_exact(KEY_LEN)
+            .filter_map(|wanted| wanted.try_into().ok())
+            .collect::<HashSet<[u8; KEY_LEN]>>();
+        for other in &wanted {
+            let Some(registration) = self.registrations.get(other) else {
+                continue;
+            };
+            if registration.wanted.contains(key) {
+                introductions.push((endpoint(other, registration.address), from));
+                introductions.push((endpoint(key, from), registration.address));
+            }
+        }
+        // A peer may register its wanted keys over several messages
+        let registration = self.registrations.entry(*key).or_insert(Registration {
+            address: from,
+            wanted: HashSet::new(),
+            renewed: Instant::now(),
+        });
+        if registration.address != from {
+            registration.address = from;
+            registration.wanted.clear();
+        }
+        registration.wanted.extend(wanted);
+        registration.renewed = Instant::now();
+        introductions
+    }
+}
+
+/// Run a rendezvous server on `transport` until it fails.
+pub async fn serve(transport: Arc<dyn Transport>) -> io::Result<()> {
+    let mut registry = Registry::new();
+    let mut batch = RecvBatch::new(BufferPool::new(BATCH_SIZE * 2));
+    let mut replies = SendBatch::new();
+    loop {
+        transport.recv_batch(&mut batch).await?;
+        for (buf, from, _) in batch.drain() {
+            for (message, to) in registry.handle(&buf, from) {
+                if replies.is_full() {
+                    transport.send_batch(&mut replies).await?;
+                }
+                replies.slot()[..message.len()].copy_from_slice(&message);
+                replies.commit(message.len(), to, None);
+            }
+        }
+        if !replies.is_empty() {
+            transport.send_batch(&mut replies).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use boringtun::x25519::{PublicKey, StaticSecret};
+
+    use super::{is_message, parse_endpoint, register, Registry};
+
+    fn key(byte: u8) -> PublicKey {
+        PublicKey::from(&StaticSecret::from([byte; 32]))
+    }
+
+    fn parse(
+        introductions: Vec<(Vec<u8>, SocketAddr)>,
+    ) -> Vec<(SocketAddr, PublicKey, SocketAddr)> {
+        introductions
+            .into_iter()
+            .map(|(message, to)| {
+                let (key, address) = parse_endpoint(&message).unwrap();
+                (to, key, address)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn peers_that_want_each_other_are_introduced() {
+        let a: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+        let b: SocketAddr = "[2001:db8::2]:50000".parse().unwrap();
+        let c: SocketAddr = "198.51.100.3:60000".parse().unwrap();
+        let mut registry = Registry::new();
+
+        for message in register(&key(1), &[key(2), key(3)]) {
+            assert!(is_message(&message));
+            assert!(registry.handle(&message, a).is_empty());
+        }
+        // The third peer does not want the first, it learns nothing
+        assert!(registry
+            .handle(&register(&key(3), &[key(2)])[0], c)
+            .is_empty());
+
+        let introductions = registry.handle(&register(&key(2), &[key(1)])[0], b);
+        assert_eq!(parse(introductions), vec![(b, key(1), a), (a, key(2), b)]);
+    }
+
+    #[test]
+    fn many_wanted_keys_are_split() {
+        let wanted = (0..40).map(key).collect::<Vec<_>>();
+        let messages = register(&key(100), &wanted);
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|message| message.len() <= 5 + 33 * 32));
+    }
+}
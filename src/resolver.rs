@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+
+use crate::peer::Peer;
+
+/// Resolves `host:port` endpoints. Lookups may block, they are run on the
+/// blocking thread pool.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, endpoint: &str) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves with the system resolver.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, endpoint: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(endpoint.to_socket_addrs()?.collect())
+    }
+}
+
+/// Answers from a fixed table, which can be changed at any time.
+#[derive(Default)]
+pub struct StaticResolver {
+    entries: Mutex<HashMap<String, Vec<SocketAddr>>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, endpoint: &str, addrs: Vec<SocketAddr>) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(endpoint.to_string(), addrs);
+        }
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, endpoint: &str) -> io::Result<Vec<SocketAddr>> {
+        self.entries
+            .lock()
+            .ok()
+            .and_then(|entries| entries.get(endpoint).cloned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, endpoint.to_string()))
+    }
+}
+
+/// Resolve the endpoint hostnames of `peer` again, and update the peer and
+/// `endpoint_peer_map` with every address they point to. Returns whether the
+/// addresses changed. A failed lookup keeps the addresses of that name.
+pub(crate) async fn refresh_endpoint(
+    peer: &Arc<Peer>,
+    resolver: &Arc<dyn Resolver>,
+    endpoint_peer_map: &DashMap<SocketAddr, Arc<Peer>>,
+) -> Result<bool> {
+    let mut changed = false;
+    let mut failure = None;
+    for host in peer.endpoint_hosts() {
+        let lookup_resolver = resolver.clone();
+        let lookup_host = host.clone();
+        let addrs = tokio::task::spawn_blocking(move || lookup_resolver.resolve(&lookup_host))
+            .await
+            .map_err(|e| anyhow!("Resolve {host} failed: {e}"))
+            .and_then(|addrs| addrs.map_err(|e| anyhow!("Resolve {host} failed: {e}")))
+            .and_then(|addrs| match addrs.is_empty() {
+                true => Err(anyhow!("Resolve {host} failed: no addresses found")),
+                false => Ok(addrs),
+            });
+        let addrs = match addrs {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                failure.get_or_insert(e);
+                continue;
+            }
+        };
+
+        let (previous, current) = peer.set_resolved(&host, addrs);
+        if previous == current {
+            continue;
+        }
+        changed = true;
+        for addr in previous.iter().filter(|addr| !current.contains(addr)) {
+            endpoint_peer_map.remove_if(addr, |_, mapped| Arc::ptr_eq(mapped, peer));
+        }
+        for addr in current {
+            endpoint_peer_map.insert(addr, peer.clone());
+        }
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(changed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "gateway.example:51820";
+
+    fn peer() -> Arc<Peer> {
+        let mut peer = Peer::new().unwrap();
+        peer.set_endpoint(HOST).unwrap();
+        Arc::new(peer)
+    }
+
+    #[test]
+    fn hostname_endpoint_is_not_resolved_when_parsed() {
+        let peer = peer();
+        assert_eq!(peer.endpoint_hosts(), vec![HOST.to_string()]);
+        assert_eq!(peer.endpoint(), None);
+    }
+
+    #[tokio::test]
+    async fn refresh_switches_endpoint_and_map() {
+        let stub = Arc::new(StaticResolver::new());
+        let resolver: Arc<dyn Resolver> = stub.clone();
+        let map = DashMap::new();
+        let peer = peer();
+
+        let first: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        stub.set(HOST, vec![first]);
+        assert!(refresh_endpoint(&peer, &resolver, &map).await.unwrap());
+        assert_eq!(peer.endpoint(), Some(first));
+        assert!(map.contains_key(&first));
+
+        assert!(!refresh_endpoint(&peer, &resolver, &map).await.unwrap());
+
+        let second: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        stub.set(HOST, vec![second]);
+        assert!(refresh_endpoint(&peer, &resolver, &map).await.unwrap());
+        assert_eq!(peer.endpoint(), Some(second));
+        assert!(!map.contains_key(&first));
+        assert!(Arc::ptr_eq(&map.get(&second).unwrap(), &peer));
+    }
+
+    #[tokio::test]
+    async fn failed_lookup_keeps_endpoint() {
+        let stub = Arc::new(StaticResolver::new());
+        let resolver: Arc<dyn Resolver> = stub.clone();
+        let map = DashMap::new();
+        let peer = peer();
+
+        let addr: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        stub.set(HOST, vec![addr]);
+        refresh_endpoint(&peer, &resolver, &map).await.unwrap();
+
+        stub.set(HOST, vec![]);
+        assert!(refresh_endpoint(&peer, &resolver, &map).await.is_err());
+        assert_eq!(peer.endpoint(), Some(addr));
+        assert!(map.contains_key(&addr));
+    }
+
+    #[tokio::test]
+    async fn every_address_of_every_name_maps_to_the_peer() {
+        let stub = Arc::new(StaticResolver::new());
+        let resolver: Arc<dyn Resolver> = stub.clone();
+        let map = DashMap::new();
+        let mut peer = Peer::new().unwrap();
+        peer.add_endpoint(HOST).unwrap();
+        peer.add_endpoint("backup.example:51820").unwrap();
+        let peer = Arc::new(peer);
+
+        let addrs: Vec<SocketAddr> = ["192.0.2.1:51820", "[2001:db8::1]:51820"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        stub.set(HOST, addrs.clone());
+        // The backup name does not resolve, the other one is used anyway
+        assert!(refresh_endpoint(&peer, &resolver, &map).await.is_err());
+        assert_eq!(peer.endpoints(), addrs);
+        assert_eq!(peer.endpoint(), Some(addrs[0]));
+
+        let backup: SocketAddr = "198.51.100.1:51820".parse().unwrap();
+        stub.set("backup.example:51820", vec![backup]);
+        assert!(refresh_endpoint(&peer, &resolver, &map).await.unwrap());
+        assert_eq!(peer.endpoints().len(), 3);
+        for addr in addrs.iter().chain([&backup]) {
+            assert!(Arc::ptr_eq(&map.get(addr).unwrap(), &peer));
+        }
+    }
+}
@@ -0,0 +1,433 @@
+//! The peers of a running instance, which may change while it runs. Every
+//! task looks peers up in the current [`PeerSet`], which is replaced as a
+//! whole on each change, so lookups never see half of one.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex, RwLock,
+    },
+    time::{Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine};
+use boringtun::{
+    noise::{rate_limiter::RateLimiter, Tunn},
+    x25519::{PublicKey, StaticSecret},
+};
+use dashmap::DashMap;
+use rand::RngCore;
+use route_manager::{Route, RouteManager};
+use tokio::sync::{broadcast, Mutex, Notify};
+
+use crate::{
+    device::Device,
+    limiter::HandshakeStats,
+    peer::{Peer, PeerEvent},
+    quota::{unix_secs, StateFile, Usage},
+    relay::{Relay, RelayPolicy},
+    resolver::{refresh_endpoint, Resolver},
+    shaper::{Shaper, TokenBucket},
+    tcp::TcpTransport,
+    transport::Transport,
+    utils::cidr_contains,
+    worker::Endpoints,
+};
+
+/// Where the routes to the allowed IPs of peers point, with a tun device.
+pub(crate) struct RouteTarget {
+    pub if_index: u32,
+    pub if_name: String,
+    pub gateway: IpAddr,
+}
+
+/// What every peer is set up with.
+pub(crate) struct Context {
+    pub private_key: StaticSecret,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub transport: Arc<dyn Transport>,
+    pub device: Arc<dyn Device>,
+    pub tcp: Option<Arc<TcpTransport>>,
+    pub relay: RelayPolicy,
+    pub resolver: Arc<dyn Resolver>,
+    pub handshake_stats: Arc<HandshakeStats>,
+    pub events: broadcast::Sender<PeerEvent>,
+    pub route_target: Option<RouteTarget>,
+    pub state_file: StateFile,
+    /// The interface-wide rates.
+    pub send_bucket: Option<Arc<StdMutex<TokenBucket>>>,
+    pub receive_bucket: Option<Arc<StdMutex<TokenBucket>>>,
+    pub shaped: Arc<Notify>,
+}
+
+#[derive(Clone)]
+struct Entry {
+    peer: Arc<Peer>,
+    allowed_ips: Vec<(IpAddr, u8)>,
+    routes: Vec<Route>,
+}
+
+#[derive(Default)]
+pub(crate) struct PeerSet {
+    entries: Vec<Entry>,
+    by_key: HashMap<[u8; 32], Arc<Peer>>,
+    /// Longest prefix first, so the first match is the most specific route.
+    allowed_ips: Vec<((IpAddr, u8), Arc<Peer>)>,
+}
+
+impl PeerSet {
+    fn new(entries: Vec<Entry>) -> Self {
+        let mut by_key = HashMap::new();
+        let mut allowed_ips = vec![];
+        for entry in &entries {
+            if let Some(public_key) = entry.peer.public_key {
+                by_key.insert(public_key.to_bytes(), entry.peer.clone());
+            }
+            for cidr in &entry.allowed_ips {
+                allowed_ips.push((*cidr, entry.peer.clone()));
+            }
+        }
+        allowed_ips.sort_by(|((_, a), _), ((_, b), _)| b.cmp(a));
+        Self {
+            entries,
+            by_key,
+            allowed_ips,
+        }
+    }
+
+    /// A set of `peers` with their allowed IPs, without routes.
+    #[cfg(test)]
+    pub fn of(peers: &[&Arc<Peer>]) -> Self {
+        Self::new(
+            peers
+                .iter()
+                .map(|peer| Entry {
+                    peer: (*peer).clone(),
+                    allowed_ips: peer.allowed_ips.clone().unwrap_or_default(),
+                    routes: vec![],
+                })
+                .collect(),
+        )
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &Arc<Peer>> {
+        self.entries.iter().map(|entry| &entry.peer)
+    }
+
+    /// Every peer with its allowed IPs.
+    pub fn with_allowed_ips(&self) -> impl Iterator<Item = (&Arc<Peer>, &[(IpAddr, u8)])> {
+        self.entries
+            .iter()
+            .map(|entry| (&entry.peer, entry.allowed_ips.as_slice()))
+    }
+
+    /// Whether the running peer with the key of `peer` has the settings of
+    /// `peer`.
+    pub fn is_unchanged(&self, peer: &Peer) -> bool {
+        self.entries.iter().any(|entry| {
+            let running = &entry.peer;
+            running.public_key == peer.public_key
+                && Some(&entry.allowed_ips) == peer.allowed_ips.as_ref()
+                && running.configured_endpoints() == peer.configured_endpoints()
+                && running.persistent_keepalive == peer.persistent_keepalive
+                && running.handshake_attempts == peer.handshake_attempts
+                && running.over_tcp == peer.over_tcp
+                && running.relay_groups == peer.relay_groups
+                && running.data_limit == peer.data_limit
+                && running.expires_at == peer.expires_at
+                && running.send_rate == peer.send_rate
+                && running.receive_rate == peer.receive_rate
+        })
+    }
+
+    pub fn get(&self, public_key: &[u8; 32]) -> Option<&Arc<Peer>> {
+        self.by_key.get(public_key)
+    }
+
+    /// The peer whose allowed IPs contain the destination of `packet`.
+    pub fn route(&self, packet: &[u8]) -> Option<Arc<Peer>> {
+        let destination = Tunn::dst_address(packet)?;
+        self.allowed_ips
+            .iter()
+            .find(|(cidr, _)| cidr_contains(cidr, &destination))
+            .map(|(_, peer)| peer.clone())
+    }
+}
+
+pub(crate) struct Runtime {
+    context: Context,
+    route_manager: StdMutex<RouteManager>,
+    peers: Arc<RwLock<Arc<PeerSet>>>,
+    relay: Option<Arc<Relay>>,
+    pub endpoints: Arc<Endpoints>,
+    next_worker_index: AtomicUsize,
+    /// Held for a whole change, so two changes never start from the same
+    /// set.
+    changing: Mutex<()>,
+}
+
+impl Runtime {
+    pub fn new(context: Context, route_manager: RouteManager) -> Self {
+        let peers = Arc::new(RwLock::new(Arc::new(PeerSet::default())));
+        // Peers reach each other without the packets passing the device
+        let relay = (context.relay != RelayPolicy::Off)
+            .then(|| Arc::new(Relay::new(context.relay, Arc::downgrade(&peers))));
+        Self {
+            context,
+            route_manager: StdMutex::new(route_manager),
+            peers,
+            relay,
+            endpoints: Arc::new(DashMap::new()),
+            next_worker_index: AtomicUsize::new(0),
+            changing: Mutex::new(()),
+        }
+    }
+
+    pub fn peers(&self) -> Arc<PeerSet> {
+        self.peers.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Remove the peers of `removed` and add `added`, in one step. Nothing
+    /// changes if a peer can't be set up.
+    pub async fn change(&self, removed: &[PublicKey], added: Vec<Peer>) -> Result<()> {
+        let changing = self.changing.lock().await;
+        let current = self.peers();
+        for public_key in removed {
+            if current.get(public_key.as_bytes()).is_none() {
+                return Err(anyhow!("No peer {}", encode_key(public_key)));
+            }
+        }
+        let (removed, kept): (Vec<Entry>, Vec<Entry>) =
+            current.entries.iter().cloned().partition(|entry| {
+                entry
+                    .peer
+                    .public_key
+                    .is_some_and(|public_key| removed.contains(&public_key))
+            });
+        let mut keys = kept
+            .iter()
+            .filter_map(|entry| entry.peer.public_key)
+            .map(|public_key| public_key.to_bytes())
+            .collect::<HashSet<_>>();
+        for peer in &added {
+            let public_key = peer.public_key.ok_or(anyhow!("Missing peer public key"))?;
+            if !keys.insert(public_key.to_bytes()) {
+                return Err(anyhow!("Peer {} exists", encode_key(&public_key)));
+            }
+        }
+
+        // A new peer may take over the routes of a removed one, and the
+        // usage of one with its key
+        for entry in &removed {
+            self.delete_routes(&entry.routes);
+            self.record_usage(&entry.peer);
+        }
+        let mut new_entries = vec![];
+        for peer in added {
+            match self.set_up(peer) {
+                Ok(entry) => new_entries.push(entry),
+                Err(e) => {
+                    for entry in &new_entries {
+                        self.delete_routes(&entry.routes);
+                    }
+                    for entry in &removed {
+                        if let Err(e) = self.add_routes(&entry.allowed_ips) {
+                            println!("Restore routes failed: {e}");
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        for entry in &new_entries {
+            self.restore_usage(&entry.peer);
+            entry.peer.check_quota().await;
+        }
+
+        let mut entries = kept;
+        entries.extend(new_entries.iter().cloned());
+        let peers = Arc::new(PeerSet::new(entries));
+        *self.peers.write().unwrap_or_else(|e| e.into_inner()) = peers;
+
+        for entry in removed {
+            self.endpoints
+                .retain(|_, peer| !Arc::ptr_eq(peer, &entry.peer));
+            if let (Some(tcp), true) = (&self.context.tcp, entry.peer.over_tcp) {
+                for endpoint in entry.peer.endpoints() {
+                    tcp.remove_client(endpoint);
+                }
+            }
+            entry.peer.close();
+        }
+        for entry in &new_entries {
+            for endpoint in entry.peer.endpoints() {
+                self.endpoints.insert(endpoint, entry.peer.clone());
+            }
+            self.add_tcp_clients(&entry.peer);
+        }
+        // A slow lookup must not hold up the next change, which may remove
+        // the peer meanwhile
+        drop(changing);
+        for entry in new_entries {
+            if let Err(e) = self.refresh_endpoint(&entry.peer).await {
+                println!("Resolve peer endpoint failed: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve the endpoint hostnames of `peer` again, see
+    /// [`refresh_endpoint`]. Returns whether the addresses changed.
+    pub async fn refresh_endpoint(&self, peer: &Arc<Peer>) -> Result<bool> {
+        let changed = refresh_endpoint(peer, &self.context.resolver, &self.endpoints).await?;
+        // Removed during the lookup, its addresses must not stay behind
+        if peer.is_removed() {
+            self.endpoints
+                .retain(|_, mapped| !Arc::ptr_eq(mapped, peer));
+            return Ok(false);
+        }
+        if changed {
+            self.add_tcp_clients(peer);
+        }
+        Ok(changed)
+    }
+
+    /// Reach every endpoint of `peer` over TCP, if it asks for that.
+    fn add_tcp_clients(&self, peer: &Peer) {
+        if let (Some(tcp), true) = (&self.context.tcp, peer.over_tcp) {
+            for endpoint in peer.endpoints() {
+                tcp.add_client(endpoint);
+            }
+        }
+    }
+
+    /// Write the usage of every peer with a data limit to the state file.
+    pub fn save_usage(&self) -> Result<()> {
+        for peer in self.peers().peers() {
+            self.record_usage(peer);
+        }
+        self.context.state_file.save()
+    }
+
+    fn record_usage(&self, peer: &Peer) {
+        if let (Some(public_key), Some(_)) = (&peer.public_key, &peer.data_limit) {
+            self.context
+                .state_file
+                .set(encode_key(public_key), peer.usage());
+        }
+    }
+
+    /// A new peer starts its first period now.
+    fn restore_usage(&self, peer: &Peer) {
+        if let (Some(public_key), Some(_)) = (&peer.public_key, &peer.data_limit) {
+            let usage = self
+                .context
+                .state_file
+                .get(&encode_key(public_key))
+                .unwrap_or(Usage {
+                    period_start: unix_secs(SystemTime::now()),
+                    bytes: 0,
+                });
+            peer.restore_usage(usage);
+        }
+    }
+
+    /// Delete the routes of every peer, when the instance stops.
+    pub fn delete_all_routes(&self) {
+        for entry in &self.peers().entries {
+            self.delete_routes(&entry.routes);
+        }
+    }
+
+    fn set_up(&self, mut peer: Peer) -> Result<Entry> {
+        let context = &self.context;
+        if peer.over_tcp && context.tcp.is_none() {
+            return Err(anyhow!(
+                "Transport = tcp needs TcpListenPort or a TCP peer at startup"
+            ));
+        }
+        peer.set_worker_index(self.next_worker_index.fetch_add(1, Ordering::Relaxed))?;
+        peer.set_send_socket(context.transport.clone())?;
+        peer.set_send_device(context.device.clone())?;
+        peer.set_handshake_stats(context.handshake_stats.clone())?;
+        peer.set_events(context.events.clone())?;
+        if let Some(relay) = &self.relay {
+            peer.set_relay(relay.clone())?;
+        }
+
+        let tunn = Tunn::new(
+            context.private_key.clone(),
+            peer.public_key.ok_or(anyhow!("Missing peer public key"))?,
+            None,
+            peer.persistent_keepalive,
+            rand::rng().next_u32(),
+            Some(context.rate_limiter.clone()),
+        )
+        .map_err(|e| anyhow!("Create tunn failed: {e}"))?;
+        peer.set_tunn(tunn)?;
+        peer.set_session_key(context.private_key.clone(), context.rate_limiter.clone())?;
+        let shaper = |rate: Option<u64>, interface: &Option<Arc<StdMutex<TokenBucket>>>| {
+            let own =
+                rate.map(|rate| Arc::new(StdMutex::new(TokenBucket::new(rate, Instant::now()))));
+            Shaper::new(
+                own.into_iter().chain(interface.clone()).collect(),
+                context.shaped.clone(),
+            )
+        };
+        peer.set_shapers(
+            shaper(peer.send_rate, &context.send_bucket),
+            shaper(peer.receive_rate, &context.receive_bucket),
+        )?;
+
+        let allowed_ips = peer.allowed_ips.take().unwrap_or_default();
+        let routes = self.add_routes(&allowed_ips)?;
+        Ok(Entry {
+            peer: Arc::new(peer),
+            allowed_ips,
+            routes,
+        })
+    }
+
+    /// Route `allowed_ips` to the tun device. On failure, the routes added
+    /// so far are deleted again.
+    fn add_routes(&self, allowed_ips: &[(IpAddr, u8)]) -> Result<Vec<Route>> {
+        let Some(target) = &self.context.route_target else {
+            return Ok(vec![]);
+        };
+        let mut routes = vec![];
+        for (destination, prefix) in allowed_ips {
+            let route = Route::new(*destination, *prefix)
+                .with_if_index(target.if_index)
+                .with_if_name(target.if_name.clone())
+                .with_gateway(target.gateway);
+            let added = self
+                .route_manager
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .add(&route);
+            if let Err(e) = added {
+                self.delete_routes(&routes);
+                return Err(anyhow!("Add route failed: {route}: {e}"));
+            }
+            routes.push(route);
+        }
+        Ok(routes)
+    }
+
+    fn delete_routes(&self, routes: &[Route]) {
+        let mut route_manager = self.route_manager.lock().unwrap_or_else(|e| e.into_inner());
+        for route in routes.iter().rev() {
+            if let Err(e) = route_manager.delete(route) {
+                println!("Delete route failed: {e}");
+            }
+        }
+    }
+}
+
+fn encode_key(public_key: &PublicKey) -> String {
+    general_purpose::STANDARD.encode(public_key.as_bytes())
+}
@@ -0,0 +1,258 @@
+//! Bandwidth shaping: token buckets per peer and direction, optionally
+//! below an interface-wide one shared by every peer.
+//!
+//! A bucket holds up to a second of its rate and may go into debt, so a
+//! packet passes while every bucket it goes through has tokens left. Packets
+//! that don't pass wait in a queue, up to a second of the lowest rate, and
+//! are dropped when it is full. A single task sends the queued packets on
+//! once the buckets have refilled. Time is passed in, so the decisions are
+//! the same for the same arrivals.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::Notify;
+
+use crate::quota::parse_bytes;
+
+/// Bytes per second, see [`parse_bytes`], e.g. `10M`.
+pub fn parse_rate(rate: &str) -> Result<u64> {
+    parse_bytes(rate.trim())
+        .filter(|rate| *rate > 0)
+        .ok_or(anyhow!("Parse rate failed: {rate}"))
+}
+
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket of `rate` bytes per second.
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last {
+            let elapsed = (now - self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+            self.last = now;
+        }
+    }
+
+    /// Time until the bucket has tokens again.
+    fn wait(&self) -> Duration {
+        if self.tokens > 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admit {
+    Pass,
+    Queued,
+    Dropped,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Next {
+    Packet(Vec<u8>),
+    /// The queue waits for the buckets to refill.
+    Wait(Duration),
+    Empty,
+}
+
+/// One direction of one peer.
+pub struct Shaper {
+    /// Locked in order, the peer's own first.
+    buckets: Vec<Arc<Mutex<TokenBucket>>>,
+    /// The queued packets and their bytes.
+    queue: Mutex<(VecDeque<Vec<u8>>, usize)>,
+    queue_limit: usize,
+    /// Woken when a packet is queued.
+    queued: Arc<Notify>,
+    dropped: AtomicU64,
+}
+
+impl Shaper {
+    /// None without any bucket.
+    pub fn new(buckets: Vec<Arc<Mutex<TokenBucket>>>, queued: Arc<Notify>) -> Option<Self> {
+        let queue_limit = buckets
+            .iter()
+            .map(|bucket| bucket.lock().unwrap_or_else(|e| e.into_inner()).rate as usize)
+            .min()?;
+        Some(Self {
+            buckets,
+            queue: Mutex::new((VecDeque::new(), 0)),
+            queue_limit,
+            queued,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether `packet` passes now. Behind queued packets it queues too.
+    pub fn admit(&self, packet: &[u8], now: Instant) -> Admit {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if queue.0.is_empty() && self.take(packet.len(), now).is_none() {
+            return Admit::Pass;
+        }
+        // A packet larger than the queue still gets in alone
+        if !queue.0.is_empty() && queue.1 + packet.len() > self.queue_limit {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return Admit::Dropped;
+        }
+        // The task draining the queue knows about a queue that isn't empty
+        if queue.0.is_empty() {
+            self.queued.notify_one();
+        }
+        queue.0.push_back(packet.to_vec());
+        queue.1 += packet.len();
+        Admit::Queued
+    }
+
+    /// The next queued packet, if the buckets let it pass now.
+    pub fn next(&self, now: Instant) -> Next {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(len) = queue.0.front().map(|packet| packet.len()) else {
+            return Next::Empty;
+        };
+        if let Some(wait) = self.take(len, now) {
+            return Next::Wait(wait);
+        }
+        queue.1 -= len;
+        Next::Packet(queue.0.pop_front().unwrap_or_default())
+    }
+
+    /// Drop every queued packet.
+    pub fn clear(&self) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        self.dropped
+            .fetch_add(queue.0.len() as u64, Ordering::Relaxed);
+        *queue = (VecDeque::new(), 0);
+    }
+
+    /// Packets dropped for a full queue, or queued for a peer that went.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Take `len` bytes from every bucket, if they all have tokens. Otherwise
+    /// the time until they do.
+    fn take(&self, len: usize, now: Instant) -> Option<Duration> {
+        let mut buckets = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.lock().unwrap_or_else(|e| e.into_inner()))
+            .collect::<Vec<_>>();
+        let mut wait = Duration::ZERO;
+        for bucket in &mut buckets {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait());
+        }
+        if wait > Duration::ZERO {
+            return Some(wait);
+        }
+        for bucket in &mut buckets {
+            bucket.tokens -= len as f64;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use tokio::sync::Notify;
+
+    use super::{parse_rate, Admit, Next, Shaper, TokenBucket};
+
+    fn bucket(rate: u64, now: Instant) -> Arc<Mutex<TokenBucket>> {
+        Arc::new(Mutex::new(TokenBucket::new(rate, now)))
+    }
+
+    fn shaper(buckets: &[&Arc<Mutex<TokenBucket>>]) -> Shaper {
+        let buckets = buckets.iter().map(|bucket| (*bucket).clone()).collect();
+        Shaper::new(buckets, Arc::new(Notify::new())).unwrap()
+    }
+
+    #[test]
+    fn rates_parse_with_units() {
+        assert_eq!(parse_rate("10M").unwrap(), 10 << 20);
+        assert_eq!(parse_rate("1500").unwrap(), 1500);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn packets_over_the_rate_are_queued_then_dropped() {
+        let now = Instant::now();
+        let shaper = shaper(&[&bucket(1000, now)]);
+        let packet = [0; 100];
+        // A second of the rate passes, another one waits
+        for _ in 0..10 {
+            assert_eq!(shaper.admit(&packet, now), Admit::Pass);
+        }
+        for _ in 0..10 {
+            assert_eq!(shaper.admit(&packet, now), Admit::Queued);
+        }
+        assert_eq!(shaper.admit(&packet, now), Admit::Dropped);
+        assert_eq!(shaper.dropped(), 1);
+
+        // Then one packet each time the bucket has tokens again
+        assert_eq!(shaper.next(now), Next::Wait(Duration::from_millis(1)));
+        let now = now + Duration::from_millis(1);
+        assert_eq!(shaper.next(now), Next::Packet(packet.to_vec()));
+        assert_eq!(shaper.next(now), Next::Wait(Duration::from_millis(100)));
+        // Queued packets go first
+        let now = now + Duration::from_millis(100);
+        assert_eq!(shaper.admit(&packet, now), Admit::Queued);
+        assert_eq!(shaper.next(now), Next::Packet(packet.to_vec()));
+    }
+
+    #[test]
+    fn large_packets_pass_on_credit() {
+        let now = Instant::now();
+        let shaper = shaper(&[&bucket(1000, now)]);
+        assert_eq!(shaper.admit(&[0; 1500], now), Admit::Pass);
+        assert_eq!(shaper.admit(&[0; 1500], now), Admit::Queued);
+        assert_eq!(shaper.next(now), Next::Wait(Duration::from_millis(501)));
+        shaper.clear();
+        assert_eq!(shaper.next(now), Next::Empty);
+        assert_eq!(shaper.dropped(), 1);
+    }
+
+    #[test]
+    fn interface_rate_is_shared() {
+        let now = Instant::now();
+        let interface = bucket(1000, now);
+        let (a_bucket, b_bucket) = (bucket(1000, now), bucket(1000, now));
+        let a = shaper(&[&a_bucket, &interface]);
+        let b = shaper(&[&b_bucket, &interface]);
+        for _ in 0..10 {
+            assert_eq!(a.admit(&[0; 100], now), Admit::Pass);
+        }
+        // b has its own tokens, but the interface has none left
+        assert_eq!(b.admit(&[0; 100], now), Admit::Queued);
+        let now = now + Duration::from_millis(1);
+        assert_eq!(b.next(now), Next::Packet(vec![0; 100]));
+    }
+}
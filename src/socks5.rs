@@ -0,0 +1,502 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+use crate::netstack::Netstack;
+
+pub(crate) const VERSION: u8 = 5;
+pub(crate) const AUTH_VERSION: u8 = 1;
+
+pub(crate) const NO_AUTH: u8 = 0;
+pub(crate) const USERNAME_PASSWORD: u8 = 2;
+pub(crate) const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const CONNECT: u8 = 1;
+pub(crate) const UDP_ASSOCIATE: u8 = 3;
+
+pub(crate) const ATYP_IPV4: u8 = 1;
+pub(crate) const ATYP_DOMAIN: u8 = 3;
+pub(crate) const ATYP_IPV6: u8 = 4;
+
+pub(crate) const SUCCEEDED: u8 = 0;
+const GENERAL_FAILURE: u8 = 1;
+const HOST_UNREACHABLE: u8 = 4;
+const CONNECTION_REFUSED: u8 = 5;
+pub(crate) const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:1080";
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Wait before accepting again, accept keeps failing while fds run out.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// A local SOCKS5 server whose connections egress through the tunnel, via
+/// the userspace network stack.
+pub struct Socks5 {
+    pub bind_address: Option<SocketAddr>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Socks5 {
+    pub fn new() -> Result<Self> {
+        let socks5 = Self {
+            bind_address: None,
+            username: None,
+            password: None,
+        };
+        Ok(socks5)
+    }
+
+    pub fn set_bind_address(&mut self, bind_address: &str) -> Result<()> {
+        self.bind_address = Some(
+            bind_address
+                .parse()
+                .map_err(|e| anyhow!("Parse socks5 bind address failed: {e}"))?,
+        );
+        Ok(())
+    }
+
+    pub fn set_username(&mut self, username: &str) -> Result<()> {
+        self.username = Some(username.to_string());
+        Ok(())
+    }
+
+    pub fn set_password(&mut self, password: &str) -> Result<()> {
+        self.password = Some(password.to_string());
+        Ok(())
+    }
+
+    pub async fn bind(&self) -> Result<TcpListener> {
+        let bind_address = match self.bind_address {
+            Some(bind_address) => bind_address,
+            None => DEFAULT_BIND_ADDRESS.parse()?,
+        };
+        TcpListener::bind(bind_address)
+            .await
+            .map_err(|e| anyhow!("Socks5 bind {bind_address} failed: {e}"))
+    }
+
+    /// Accept clients on `listener` until it fails.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, netstack: Arc<Netstack>) {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(client) => client,
+                Err(e) => {
+                    println!("Socks5 accept failed: {e}");
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let socks5 = self.clone();
+            let netstack = netstack.clone();
+            tokio::spawn(async move {
+                if let Err(e) = socks5.handle_client(stream, &netstack).await {
+                    println!("Socks5 client failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle_client(&self, mut stream: TcpStream, netstack: &Netstack) -> Result<()> {
+        self.negotiate(&mut stream).await?;
+
+        let mut header = [0u8; 3];
+        stream.read_exact(&mut header).await?;
+        if header[0] != VERSION {
+            return Err(anyhow!("Unsupported socks version: {}", header[0]));
+        }
+        let target = match read_target(&mut stream).await {
+            Ok(target) => target,
+            Err(e) => {
+                reply(&mut stream, ADDRESS_NOT_SUPPORTED, None).await?;
+                return Err(e.into());
+            }
+        };
+
+        match header[1] {
+            CONNECT => connect(stream, netstack, target).await,
+            UDP_ASSOCIATE => associate(stream, netstack).await,
+            other => {
+                reply(&mut stream, COMMAND_NOT_SUPPORTED, None).await?;
+                Err(anyhow!("Unsupported socks command: {other}"))
+            }
+        }
+    }
+
+    /// Agree on an authentication method and authenticate the client.
+    async fn negotiate(&self, stream: &mut TcpStream) -> Result<()> {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header).await?;
+        if header[0] != VERSION {
+            return Err(anyhow!("Unsupported socks version: {}", header[0]));
+        }
+        let mut methods = vec![0u8; header[1] as usize];
+        stream.read_exact(&mut methods).await?;
+
+        let credentials = self.username.as_deref().zip(self.password.as_deref());
+        let method = if credentials.is_some() {
+            USERNAME_PASSWORD
+        } else {
+            NO_AUTH
+        };
+        if !methods.contains(&method) {
+            stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+            return Err(anyhow!("No acceptable socks authentication method"));
+        }
+        stream.write_all(&[VERSION, method]).await?;
+
+        if let Some((username, password)) = credentials {
+            let mut version = [0u8; 1];
+            stream.read_exact(&mut version).await?;
+            if version[0] != AUTH_VERSION {
+                return Err(anyhow!("Unsupported socks auth version: {}", version[0]));
+            }
+            let client_username = read_string(stream).await?;
+            let client_password = read_string(stream).await?;
+            if client_username != username || client_password != password {
+                stream.write_all(&[AUTH_VERSION, 1]).await?;
+                return Err(anyhow!("Socks authentication failed for {client_username}"));
+            }
+            stream.write_all(&[AUTH_VERSION, 0]).await?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) enum Target {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+async fn connect(mut stream: TcpStream, netstack: &Netstack, target: Target) -> Result<()> {
+    let remote = match resolve(netstack, &target).await {
+        Ok(remote) => remote,
+        Err(e) => {
+            reply(&mut stream, reply_code(&e), None).await?;
+            return Err(e.into());
+        }
+    };
+    let mut remote_stream = match netstack.connect(remote).await {
+        Ok(remote_stream) => remote_stream,
+        Err(e) => {
+            reply(&mut stream, reply_code(&e), None).await?;
+            return Err(anyhow!("Connect {remote} failed: {e}"));
+        }
+    };
+    reply(&mut stream, SUCCEEDED, remote_stream.local_addr().ok()).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut remote_stream).await?;
+    Ok(())
+}
+
+/// Relay datagrams between the client and the tunnel for as long as the
+/// control connection stays open.
+async fn associate(mut stream: TcpStream, netstack: &Netstack) -> Result<()> {
+    let client_ip = stream.peer_addr()?.ip();
+    let relay = UdpSocket::bind(SocketAddr::new(stream.local_addr()?.ip(), 0)).await?;
+    let remote_socket = netstack.bind_udp(None)?;
+    reply(&mut stream, SUCCEEDED, Some(relay.local_addr()?)).await?;
+
+    let mut client_addr = None;
+    let mut control = [0u8; 1];
+    let mut from_client = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut from_remote = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            result = stream.read(&mut control) => {
+                if matches!(result, Ok(0) | Err(_)) {
+                    return Ok(());
+                }
+            }
+            result = relay.recv_from(&mut from_client) => {
+                let (len, from) = result?;
+                if from.ip() != client_ip {
+                    continue;
+                }
+                client_addr = Some(from);
+                let (target, offset) = match parse_datagram(&from_client[..len]) {
+                    Some(datagram) => datagram,
+                    None => continue,
+                };
+                let remote = match resolve(netstack, &target).await {
+                    Ok(remote) => remote,
+                    Err(e) => {
+                        println!("Socks5 resolve failed: {e}");
+                        continue;
+                    }
+                };
+                if let Err(e) = remote_socket.send_to(&from_client[offset..len], remote).await {
+                    println!("Socks5 send to {remote} failed: {e}");
+                }
+            }
+            result = remote_socket.recv_from(&mut from_remote) => {
+                let (len, from) = result?;
+                if let Some(client_addr) = client_addr {
+                    let mut datagram = vec![0, 0, 0];
+                    encode_address(&mut datagram, from);
+                    datagram.extend_from_slice(&from_remote[..len]);
+                    relay.send_to(&datagram, client_addr).await?;
+                }
+            }
+        }
+    }
+}
+
+async fn resolve(netstack: &Netstack, target: &Target) -> io::Result<SocketAddr> {
+    match target {
+        Target::Addr(addr) => Ok(*addr),
+        Target::Domain(name, port) => netstack.lookup(name, *port).await,
+    }
+}
+
+fn reply_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+        io::ErrorKind::TimedOut | io::ErrorKind::NotFound => HOST_UNREACHABLE,
+        io::ErrorKind::Unsupported => ADDRESS_NOT_SUPPORTED,
+        _ => GENERAL_FAILURE,
+    }
+}
+
+async fn reply(stream: &mut TcpStream, code: u8, bound: Option<SocketAddr>) -> io::Result<()> {
+    let mut buf = vec![VERSION, code, 0];
+    encode_address(
+        &mut buf,
+        bound.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+    );
+    stream.write_all(&buf).await
+}
+
+pub(crate) async fn read_target(stream: &mut TcpStream) -> io::Result<Target> {
+    let mut atyp = [0u8; 1];
+    stream.read_exact(&mut atyp).await?;
+    let ip = match atyp[0] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        ATYP_DOMAIN => {
+            let name = read_string(stream).await?;
+            let port = stream.read_u16().await?;
+            return Ok(Target::Domain(name, port));
+        }
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported socks address type: {other}"),
+            ))
+        }
+    };
+    let port = stream.read_u16().await?;
+    Ok(Target::Addr(SocketAddr::new(ip, port)))
+}
+
+async fn read_string(stream: &mut TcpStream) -> io::Result<String> {
+    let len = stream.read_u8().await?;
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The target of a UDP relay datagram and the offset of its payload.
+/// Fragmented datagrams are not supported and dropped.
+pub(crate) fn parse_datagram(datagram: &[u8]) -> Option<(Target, usize)> {
+    let (header, rest) = datagram.split_first_chunk::<4>()?;
+    if header[2] != 0 {
+        return None;
+    }
+    let (ip, len) = match header[3] {
+        ATYP_IPV4 => (IpAddr::from(*rest.first_chunk::<4>()?), 4),
+        ATYP_IPV6 => (IpAddr::from(*rest.first_chunk::<16>()?), 16),
+        ATYP_DOMAIN => {
+            let len = *rest.first()? as usize;
+            let name = std::str::from_utf8(rest.get(1..1 + len)?).ok()?;
+            let port = u16::from_be_bytes(*rest.get(1 + len..)?.first_chunk::<2>()?);
+            return Some((Target::Domain(name.to_string(), port), 4 + 1 + len + 2));
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(*rest.get(len..)?.first_chunk::<2>()?);
+    Some((Target::Addr(SocketAddr::new(ip, port)), 4 + len + 2))
+}
+
+/// Write the header of a UDP relay datagram to `addr` to the start of
+/// `buf`, returns its length.
+pub(crate) fn write_datagram_header(buf: &mut [u8], addr: SocketAddr) -> usize {
+    buf[..3].fill(0);
+    let len = match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf[3] = ATYP_IPV4;
+            buf[4..8].copy_from_slice(&ip.octets());
+            8
+        }
+        IpAddr::V6(ip) => {
+            buf[3] = ATYP_IPV6;
+            buf[4..20].copy_from_slice(&ip.octets());
+            20
+        }
+    };
+    buf[len..len + 2].copy_from_slice(&addr.port().to_be_bytes());
+    len + 2
+}
+
+pub(crate) fn encode_address(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpStream, UdpSocket},
+    };
+
+    use super::{parse_datagram, write_datagram_header, Socks5, Target};
+    use crate::netstack::tests::linked_pair;
+
+    async fn start(socks5: Socks5) -> SocketAddr {
+        let (a, b) = linked_pair();
+        let mut listener = b.listen(7).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = tokio::io::split(&mut stream);
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        let echo = b.bind_udp(Some(7)).unwrap();
+        tokio::spawn(async move {
+            let _keep = b;
+            let mut buf = [0u8; 1500];
+            while let Ok((len, from)) = echo.recv_from(&mut buf).await {
+                echo.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+
+        let mut socks5 = socks5;
+        socks5.set_bind_address("127.0.0.1:0").unwrap();
+        let listener = socks5.bind().await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(socks5).serve(listener, a));
+        addr
+    }
+
+    async fn request(stream: &mut TcpStream, command: u8) -> SocketAddr {
+        stream
+            .write_all(&[5, command, 0, 1, 10, 0, 0, 2, 0, 7])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+        SocketAddr::from((
+            [reply[4], reply[5], reply[6], reply[7]],
+            u16::from_be_bytes([reply[8], reply[9]]),
+        ))
+    }
+
+    #[test]
+    fn datagram_headers_parse_back() {
+        for addr in ["192.0.2.1:51820", "[2001:db8::1]:51820"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let mut buf = [0xff; 32];
+            let len = write_datagram_header(&mut buf, addr);
+            assert!(matches!(
+                parse_datagram(&buf),
+                Some((Target::Addr(parsed), offset)) if parsed == addr && offset == len
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_egresses_through_netstack() {
+        let addr = start(Socks5::new().unwrap()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+
+        let bound = request(&mut stream, 1).await;
+        assert_eq!(bound.ip().to_string(), "10.0.0.1");
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn udp_associate_relays_datagrams() {
+        let addr = start(Socks5::new().unwrap()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+
+        let relay = request(&mut stream, 3).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut datagram = vec![0, 0, 0, 1, 10, 0, 0, 2, 0, 7];
+        datagram.extend_from_slice(b"ping");
+        client.send_to(&datagram, relay).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], &datagram[..]);
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_rejected() {
+        let mut socks5 = Socks5::new().unwrap();
+        socks5.set_username("user").unwrap();
+        socks5.set_password("secret").unwrap();
+        let addr = start(socks5).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0xff]);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[5, 1, 2]).await.unwrap();
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 2]);
+        stream
+            .write_all(&[
+                1, 4, b'u', b's', b'e', b'r', 5, b'w', b'r', b'o', b'n', b'g',
+            ])
+            .await
+            .unwrap();
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await.unwrap();
+        assert_eq!(status, [1, 1]);
+    }
+}
@@ -0,0 +1,384 @@
+//! A STUN (RFC 5389) binding client, to learn the address NATs map the
+//! outer socket to.
+//!
+//! Requests go out over the outer socket itself, and the receive loop hands
+//! every response back. They can't be taken for WireGuard messages, whose
+//! second byte is always zero.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::RngCore;
+
+use crate::{resolver::Resolver, transport::Transport, udp::SendBatch};
+
+/// Time between binding requests, short enough to notice a new mapping.
+pub const STUN_INTERVAL: Duration = Duration::from_secs(60);
+/// Time the servers have to answer, before the results are evaluated.
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112a442;
+const HEADER_LEN: usize = 20;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 1;
+const FAMILY_IPV6: u8 = 2;
+
+type TransactionId = [u8; 12];
+
+/// How the NAT in front of the outer socket maps it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NatType {
+    /// Fewer than two STUN servers answered.
+    #[default]
+    Unknown,
+    /// Every server saw the same address, so peers can reach the socket at
+    /// it. Hole punching works.
+    EndpointIndependent,
+    /// Servers saw different addresses, each destination gets its own
+    /// mapping (a symmetric NAT). Hole punching does not work.
+    EndpointDependent,
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "unknown"),
+            Self::EndpointIndependent => write!(f, "endpoint independent"),
+            Self::EndpointDependent => write!(f, "endpoint dependent"),
+        }
+    }
+}
+
+/// What the STUN servers last reported.
+#[derive(Default)]
+pub struct StunStatus {
+    inner: Mutex<(Option<SocketAddr>, NatType)>,
+}
+
+impl StunStatus {
+    /// The public address of the outer socket, as the first server that
+    /// answered saw it.
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).0
+    }
+
+    pub fn nat_type(&self) -> NatType {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).1
+    }
+
+    /// Returns whether anything changed.
+    fn update(&self, mapped_address: Option<SocketAddr>, nat_type: NatType) -> bool {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let changed = *inner != (mapped_address, nat_type);
+        *inner = (mapped_address, nat_type);
+        changed
+    }
+}
+
+/// Sends binding requests to the configured servers every
+/// [`STUN_INTERVAL`], and turns the answers into a [`StunStatus`].
+pub(crate) struct StunClient {
+    servers: Vec<String>,
+    status: Arc<StunStatus>,
+    /// Requests of the current round, with the server they went to.
+    pending: Mutex<HashMap<TransactionId, (usize, SocketAddr)>>,
+    /// The mapped address each server answered with, in server order.
+    results: Mutex<Vec<Option<SocketAddr>>>,
+}
+
+impl StunClient {
+    pub fn new(servers: Vec<String>, status: Arc<StunStatus>) -> Self {
+        Self {
+            servers,
+            status,
+            pending: Mutex::new(HashMap::new()),
+            results: Mutex::new(vec![]),
+        }
+    }
+
+    /// Take `packet` if it answers one of the requests. Returns false for
+    /// anything else.
+    pub fn handle(&self, packet: &[u8], from: SocketAddr) -> bool {
+        let Some((transaction, mapped)) = parse_binding_response(packet) else {
+            return false;
+        };
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        match pending.get(&transaction) {
+            Some(&(index, server)) if server == from => {
+                pending.remove(&transaction);
+                if let Some(result) = self
+                    .results
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .get_mut(index)
+                {
+                    *result = Some(mapped);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Ask every server for the mapped address, then report what changed,
+    /// until the task is stopped.
+    pub async fn run(&self, transport: Arc<dyn Transport>, resolver: Arc<dyn Resolver>) {
+        let mut batch = SendBatch::new();
+        loop {
+            self.pending
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clear();
+            *self.results.lock().unwrap_or_else(|e| e.into_inner()) =
+                vec![None; self.servers.len()];
+            for (index, server) in self.servers.iter().enumerate() {
+                let lookup_resolver = resolver.clone();
+                let lookup_server = server.clone();
+                let address = match tokio::task::spawn_blocking(move || {
+                    lookup_resolver.resolve(&lookup_server)
+                })
+                .await
+                {
+                    Ok(Ok(addrs)) if !addrs.is_empty() => addrs[0],
+                    Ok(Err(e)) => {
+                        println!("Resolve STUN server {server} failed: {e}");
+                        continue;
+                    }
+                    _ => {
+                        println!("Resolve STUN server {server} failed");
+                        continue;
+                    }
+                };
+                let mut transaction = TransactionId::default();
+                rand::rng().fill_bytes(&mut transaction);
+                self.pending
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(transaction, (index, address));
+                let request = binding_request(&transaction);
+                batch.slot()[..request.len()].copy_from_slice(&request);
+                batch.commit(request.len(), address, None);
+            }
+            if let Err(e) = transport.send_batch(&mut batch).await {
+                println!("Send STUN requests failed: {e}");
+                batch.clear();
+            }
+
+            tokio::time::sleep(STUN_TIMEOUT).await;
+            let results = self
+                .results
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .iter()
+                .flatten()
+                .copied()
+                .collect::<Vec<_>>();
+            match results.first() {
+                Some(mapped_address)
+                    if self
+                        .status
+                        .update(Some(*mapped_address), nat_type(&results)) =>
+                {
+                    println!(
+                        "Public address is {mapped_address}, NAT mapping is {}",
+                        self.status.nat_type()
+                    );
+                }
+                Some(_) => {}
+                None => println!("No STUN server answered"),
+            }
+            tokio::time::sleep(STUN_INTERVAL.saturating_sub(STUN_TIMEOUT)).await;
+        }
+    }
+}
+
+/// The NAT type the mapped addresses of several servers reveal.
+fn nat_type(results: &[SocketAddr]) -> NatType {
+    match results {
+        [] | [_] => NatType::Unknown,
+        [first, rest @ ..] if rest.iter().all(|mapped| mapped == first) => {
+            NatType::EndpointIndependent
+        }
+        _ => NatType::EndpointDependent,
+    }
+}
+
+/// Whether `packet` looks like a STUN message, by its magic cookie.
+pub fn is_message(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_LEN
+        && packet[0] & 0xc0 == 0
+        && u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]) == MAGIC_COOKIE
+}
+
+fn binding_request(transaction: &TransactionId) -> [u8; HEADER_LEN] {
+    let mut request = [0u8; HEADER_LEN];
+    request[..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request[8..].copy_from_slice(transaction);
+    request
+}
+
+/// The transaction and mapped address of a binding success response.
+fn parse_binding_response(packet: &[u8]) -> Option<(TransactionId, SocketAddr)> {
+    let (header, mut attributes) = packet.split_first_chunk::<HEADER_LEN>()?;
+    if u16::from_be_bytes([header[0], header[1]]) != BINDING_SUCCESS
+        || u32::from_be_bytes([header[4], header[5], header[6], header[7]]) != MAGIC_COOKIE
+        || u16::from_be_bytes([header[2], header[3]]) as usize != attributes.len()
+    {
+        return None;
+    }
+    let transaction: TransactionId = header[8..].try_into().ok()?;
+
+    let mut mapped = None;
+    while let Some((attribute, rest)) = attributes.split_first_chunk::<4>() {
+        let kind = u16::from_be_bytes([attribute[0], attribute[1]]);
+        let len = u16::from_be_bytes([attribute[2], attribute[3]]) as usize;
+        let value = rest.get(..len)?;
+        match kind {
+            // The XOR form survives NATs that rewrite addresses in payloads
+            XOR_MAPPED_ADDRESS => return Some((transaction, parse_address(value, header)?)),
+            MAPPED_ADDRESS => mapped = parse_address(value, &[0; HEADER_LEN]),
+            _ => {}
+        }
+        // Attributes are padded to four bytes
+        attributes = rest.get(len.next_multiple_of(4)..)?;
+    }
+    Some((transaction, mapped?))
+}
+
+/// An address attribute, XORed with `mask` the way XOR-MAPPED-ADDRESS is
+/// with the cookie and transaction. A zero mask reads MAPPED-ADDRESS.
+fn parse_address(value: &[u8], mask: &[u8; HEADER_LEN]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes([value.get(2)? ^ mask[4], value.get(3)? ^ mask[5]]);
+    let ip = match *value.get(1)? {
+        FAMILY_IPV4 => {
+            let mut octets: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            octets
+                .iter_mut()
+                .zip(&mask[4..8])
+                .for_each(|(o, m)| *o ^= m);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        FAMILY_IPV6 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            octets
+                .iter_mut()
+                .zip(&mask[4..20])
+                .for_each(|(o, m)| *o ^= m);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use super::{
+        binding_request, nat_type, parse_binding_response, NatType, BINDING_SUCCESS, FAMILY_IPV4,
+        FAMILY_IPV6, HEADER_LEN, XOR_MAPPED_ADDRESS,
+    };
+    use crate::{
+        buffer::BufferPool,
+        transport::{ChannelTransport, Transport},
+        udp::{RecvBatch, SendBatch},
+    };
+
+    /// The sample IPv4 response of RFC 5769.
+    const SAMPLE_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    /// The success response to `request`, reporting `mapped`.
+    fn binding_response(request: &[u8], mapped: SocketAddr) -> Vec<u8> {
+        let mut value = vec![0, 0];
+        value.extend_from_slice(&mapped.port().to_be_bytes());
+        match mapped.ip() {
+            IpAddr::V4(ip) => {
+                value[1] = FAMILY_IPV4;
+                value.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                value[1] = FAMILY_IPV6;
+                value.extend_from_slice(&ip.octets());
+            }
+        }
+        let (port, address) = value[2..].split_at_mut(2);
+        for (byte, mask) in port
+            .iter_mut()
+            .chain(address)
+            .zip(request[4..6].iter().chain(&request[4..HEADER_LEN]))
+        {
+            *byte ^= mask;
+        }
+        let mut response = request[..HEADER_LEN].to_vec();
+        response[..2].copy_from_slice(&BINDING_SUCCESS.to_be_bytes());
+        response[2..4].copy_from_slice(&(4 + value.len() as u16).to_be_bytes());
+        response.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+        response.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        response.extend_from_slice(&value);
+        response
+    }
+
+    /// A STUN server on `transport`, that reports every client's address
+    /// with `port_offset` added to the port.
+    pub(crate) async fn serve(transport: ChannelTransport, port_offset: u16) {
+        let mut batch = RecvBatch::new(BufferPool::new(16));
+        while transport.recv_batch(&mut batch).await.is_ok() {
+            let mut replies = SendBatch::new();
+            for (request, from, _) in batch.drain() {
+                let mapped = SocketAddr::new(from.ip(), from.port() + port_offset);
+                let response = binding_response(&request, mapped);
+                replies.slot()[..response.len()].copy_from_slice(&response);
+                replies.commit(response.len(), from, None);
+            }
+            let _ = transport.send_batch(&mut replies).await;
+        }
+    }
+
+    #[test]
+    fn rfc_5769_sample_response_is_parsed() {
+        let (transaction, mapped) = parse_binding_response(&SAMPLE_RESPONSE).unwrap();
+        assert_eq!(transaction, SAMPLE_RESPONSE[8..20]);
+        assert_eq!(mapped, "192.0.2.1:32853".parse().unwrap());
+
+        // Anything else is not a response
+        assert!(parse_binding_response(&SAMPLE_RESPONSE[..40]).is_none());
+        assert!(parse_binding_response(&binding_request(&[7; 12])).is_none());
+        let mut data = SAMPLE_RESPONSE;
+        data[..4].copy_from_slice(&[4, 0, 0, 0]);
+        assert!(parse_binding_response(&data).is_none());
+    }
+
+    #[test]
+    fn ipv6_mapped_addresses_are_parsed() {
+        let request = binding_request(&[9; 12]);
+        let mapped = "[2001:db8::1]:51820".parse().unwrap();
+        let response = binding_response(&request, mapped);
+        assert_eq!(parse_binding_response(&response), Some(([9; 12], mapped)));
+    }
+
+    #[test]
+    fn nat_type_compares_the_servers() {
+        let a = "198.51.100.1:51820".parse().unwrap();
+        let b = "198.51.100.1:61000".parse().unwrap();
+        assert_eq!(nat_type(&[a]), NatType::Unknown);
+        assert_eq!(nat_type(&[a, a]), NatType::EndpointIndependent);
+        assert_eq!(nat_type(&[a, a, b]), NatType::EndpointDependent);
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use futures::StreamExt;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Mutex},
+};
+use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
+
+use crate::{
+    buffer::{BufferPool, PacketBuffer, MAX_PACKET_SIZE},
+    transport::Transport,
+    udp::{RecvBatch, SendBatch, BATCH_SIZE},
+};
+
+const CONNECTION_DEPTH: usize = 256;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait after a failed connection attempt, doubled after each further one.
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+/// Pause after a failed accept, so a broken listener does not spin.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Carries datagrams over TCP for the endpoints that need it, and over the
+/// inner transport for all others. Each datagram is prefixed with its length
+/// as a big endian `u16`, the framing of udp-over-tcp.
+///
+/// Client endpoints are connected on the first packet and reconnected when
+/// the connection drops. Peers connecting to a listener are answered over
+/// their connection while it lasts.
+pub struct TcpTransport {
+    inner: Arc<dyn Transport>,
+    clients: RwLock<HashSet<SocketAddr>>,
+    connections: Arc<DashMap<SocketAddr, mpsc::Sender<PacketBuffer>>>,
+    received_tx: mpsc::Sender<Datagram>,
+    received: Mutex<mpsc::Receiver<Datagram>>,
+    /// Buffers of the packets queued on connections.
+    pool: Arc<BufferPool>,
+    /// The packets of a batch that go over the inner transport.
+    others: Mutex<SendBatch>,
+}
+
+impl TcpTransport {
+    pub fn new(inner: Arc<dyn Transport>) -> Self {
+        let (received_tx, received) = mpsc::channel(CONNECTION_DEPTH);
+        Self {
+            inner,
+            clients: RwLock::new(HashSet::new()),
+            connections: Arc::new(DashMap::new()),
+            received_tx,
+            received: Mutex::new(received),
+            pool: BufferPool::new(CONNECTION_DEPTH),
+            others: Mutex::new(SendBatch::new()),
+        }
+    }
+
+    /// Reach `endpoint` over TCP from now on.
+    pub fn add_client(&self, endpoint: SocketAddr) {
+        self.clients
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(endpoint);
+    }
+
+    /// Stop reaching `endpoint` over TCP, closing its connection.
+    pub fn remove_client(&self, endpoint: SocketAddr) {
+        self.clients
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&endpoint);
+        self.connections.remove(&endpoint);
+    }
+
+    /// Accept peers connecting over TCP on `addr`. Returns the bound
+    /// address.
+    pub async fn listen(&self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let connections = self.connections.clone();
+        let received = self.received_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, remote) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        println!("Accept TCP connection failed: {e}");
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
+                let _ = stream.set_nodelay(true);
+                let (tx, mut rx) = mpsc::channel(CONNECTION_DEPTH);
+                connections.insert(remote, tx);
+                let connections = connections.clone();
+                let received = received.clone();
+                tokio::spawn(async move {
+                    carry(stream, remote, &mut rx, &received).await;
+                    connections.remove(&remote);
+                });
+            }
+        });
+        Ok(local_addr)
+    }
+
+    fn is_tcp(&self, endpoint: &SocketAddr) -> bool {
+        self.connections.contains_key(endpoint)
+            || self
+                .clients
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .contains(endpoint)
+    }
+
+    /// Queue `packet` on the connection to `endpoint`, connecting first if
+    /// there is none. A full queue drops the packet, like a full socket
+    /// buffer.
+    fn send_tcp(&self, packet: &[u8], endpoint: SocketAddr) {
+        let connection = self.connections.entry(endpoint).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel(CONNECTION_DEPTH);
+            tokio::spawn(connect(endpoint, rx, self.received_tx.clone()));
+            tx
+        });
+        let mut buf = self.pool.get();
+        buf.as_full_mut()[..packet.len()].copy_from_slice(packet);
+        buf.set_len(packet.len());
+        let _ = connection.try_send(buf);
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()> {
+        if !batch
+            .packets()
+            .any(|(_, endpoint, _)| self.is_tcp(&endpoint))
+        {
+            return self.inner.send_batch(batch).await;
+        }
+
+        let mut others = self.others.lock().await;
+        for (packet, endpoint, source) in batch.packets() {
+            if self.is_tcp(&endpoint) {
+                self.send_tcp(packet, endpoint);
+                continue;
+            }
+            others.slot()[..packet.len()].copy_from_slice(packet);
+            others.commit(packet.len(), endpoint, source);
+        }
+        batch.clear();
+        let result = self.inner.send_batch(&mut others).await;
+        others.clear();
+        result
+    }
+
+    async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        let mut received = self.received.lock().await;
+        tokio::select! {
+            result = self.inner.recv_batch(batch) => result,
+            Some((packet, from)) = received.recv() => {
+                batch.clear();
+                batch.push(&packet, from, None);
+                for _ in 1..BATCH_SIZE {
+                    match received.try_recv() {
+                        Ok((packet, from)) => batch.push(&packet, from, None),
+                        Err(_) => break,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn rebind(&self) -> io::Result<()> {
+        self.inner.rebind().await
+    }
+}
+
+fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .length_field_length(2)
+        .big_endian()
+        .max_frame_length(u16::MAX as usize)
+        .new_codec()
+}
+
+/// Keep a connection to `endpoint` up for as long as the transport sends to
+/// it. Packets queued while disconnected go out once connected again.
+async fn connect(
+    endpoint: SocketAddr,
+    mut outgoing: mpsc::Receiver<PacketBuffer>,
+    received: mpsc::Sender<Datagram>,
+) {
+    let mut delay = RECONNECT_DELAY_MIN;
+    loop {
+        match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(endpoint)).await {
+            Ok(Ok(stream)) => {
+                delay = RECONNECT_DELAY_MIN;
+                let _ = stream.set_nodelay(true);
+                if !carry(stream, endpoint, &mut outgoing, &received).await {
+                    return;
+                }
+                println!("TCP connection to {endpoint} closed, reconnecting");
+                continue;
+            }
+            Ok(Err(e)) => println!("Connect to {endpoint} over TCP failed: {e}"),
+            Err(_) => println!("Connect to {endpoint} over TCP timed out"),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+    }
+}
+
+/// Move datagrams between `stream` and the transport until either side is
+/// done. Returns false once the transport is gone.
+async fn carry(
+    stream: TcpStream,
+    endpoint: SocketAddr,
+    outgoing: &mut mpsc::Receiver<PacketBuffer>,
+    received: &mpsc::Sender<Datagram>,
+) -> bool {
+    let (read, mut writer) = stream.into_split();
+    let mut reader = FramedRead::new(read, codec());
+    // Length and packet go out in one write
+    let mut frame = Vec::with_capacity(2 + MAX_PACKET_SIZE);
+    loop {
+        tokio::select! {
+            packet = outgoing.recv() => match packet {
+                Some(packet) => {
+                    frame.clear();
+                    frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                    frame.extend_from_slice(&packet);
+                    if writer.write_all(&frame).await.is_err() {
+                        return true;
+                    }
+                }
+                None => return false,
+            },
+            frame = reader.next() => match frame {
+                Some(Ok(frame)) => {
+                    if received.send((frame.to_vec(), endpoint)).await.is_err() {
+                        return false;
+                    }
+                }
+                _ => return true,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::TcpTransport;
+    use crate::{
+        buffer::BufferPool,
+        transport::{ChannelTransport, Transport},
+        udp::{RecvBatch, SendBatch},
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn transport() -> (TcpTransport, ChannelTransport) {
+        let (inner, other) = ChannelTransport::pair(
+            "192.0.2.1:51820".parse().unwrap(),
+            "192.0.2.2:51820".parse().unwrap(),
+        );
+        (TcpTransport::new(Arc::new(inner)), other)
+    }
+
+    async fn send(transport: &impl Transport, packet: &[u8], endpoint: SocketAddr) {
+        let mut batch = SendBatch::new();
+        batch.slot()[..packet.len()].copy_from_slice(packet);
+        batch.commit(packet.len(), endpoint, None);
+        transport.send_batch(&mut batch).await.unwrap();
+    }
+
+    async fn recv(transport: &impl Transport) -> (Vec<u8>, SocketAddr) {
+        let mut batch = RecvBatch::new(BufferPool::new(4));
+        tokio::time::timeout(TIMEOUT, transport.recv_batch(&mut batch))
+            .await
+            .unwrap()
+            .unwrap();
+        let (buf, from, _) = batch.drain().next().unwrap();
+        (buf.to_vec(), from)
+    }
+
+    async fn read_frame(stream: &mut TcpStream) -> Vec<u8> {
+        let len = tokio::time::timeout(TIMEOUT, stream.read_u16())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut frame = vec![0u8; len as usize];
+        stream.read_exact(&mut frame).await.unwrap();
+        frame
+    }
+
+    async fn write_frame(stream: &mut TcpStream, frame: &[u8]) {
+        stream.write_u16(frame.len() as u16).await.unwrap();
+        stream.write_all(frame).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_endpoints_are_reached_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let (transport, other) = transport();
+        transport.add_client(server);
+
+        send(&transport, b"over tcp", server).await;
+        send(&transport, b"over udp", other.local_addr()).await;
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(read_frame(&mut stream).await, b"over tcp");
+        assert_eq!(recv(&other).await.0, b"over udp");
+
+        write_frame(&mut stream, b"reply").await;
+        assert_eq!(recv(&transport).await, (b"reply".to_vec(), server));
+
+        // A dropped connection is made again, and queued packets follow
+        drop(stream);
+        send(&transport, b"again", server).await;
+        let (mut stream, _) = tokio::time::timeout(TIMEOUT, listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        loop {
+            // The packet may have gone out on the dead connection
+            send(&transport, b"again", server).await;
+            if read_frame(&mut stream).await == b"again" {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn listener_answers_over_the_same_connection() {
+        let (transport, _other) = transport();
+        let server = transport
+            .listen("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let client = stream.local_addr().unwrap();
+        write_frame(&mut stream, b"hello").await;
+        assert_eq!(recv(&transport).await, (b"hello".to_vec(), client));
+
+        send(&transport, b"welcome", client).await;
+        assert_eq!(read_frame(&mut stream).await, b"welcome");
+    }
+}
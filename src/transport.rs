@@ -0,0 +1,220 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::udp::{OuterSocket, RecvBatch, SendBatch, BATCH_SIZE};
+
+const CHANNEL_DEPTH: usize = 1024;
+
+/// Carries encrypted datagrams between the local instance and peer
+/// endpoints. The outer UDP socket is the default transport.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Send every packet of `batch` to its endpoint and clear it.
+    async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()>;
+
+    /// Send `batch` without waiting and clear it, if the transport can.
+    /// Returns false, keeping what is left for [`Self::send_batch`],
+    /// otherwise. Unlike that one it doesn't allocate a future.
+    fn try_send_batch(&self, _batch: &mut SendBatch) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Receive at least one datagram into `batch`, with the endpoint it came
+    /// from.
+    async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()>;
+
+    /// Start over after the network changed. Transports that are not bound
+    /// to the network keep working as they are.
+    async fn rebind(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Transport for OuterSocket {
+    async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()> {
+        OuterSocket::send_batch(self, batch).await
+    }
+
+    fn try_send_batch(&self, batch: &mut SendBatch) -> io::Result<bool> {
+        OuterSocket::try_send_batch(self, batch)
+    }
+
+    async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        OuterSocket::recv_batch(self, batch).await
+    }
+
+    async fn rebind(&self) -> io::Result<()> {
+        OuterSocket::rebind(self).await
+    }
+}
+
+/// Send every packet of `batch` through `transport` and clear it, waiting
+/// only when it can't go at once.
+pub async fn send(transport: &dyn Transport, batch: &mut SendBatch) -> io::Result<()> {
+    if transport.try_send_batch(batch)? {
+        return Ok(());
+    }
+    transport.send_batch(batch).await
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// An in-memory network of [`ChannelTransport`]s, each reachable at its
+/// own address.
+#[derive(Clone, Default)]
+pub struct ChannelNetwork {
+    inner: Arc<StdMutex<Links>>,
+}
+
+#[derive(Default)]
+struct Links {
+    links: HashMap<SocketAddr, mpsc::Sender<Datagram>>,
+    down: HashSet<SocketAddr>,
+    taps: Vec<mpsc::UnboundedSender<(SocketAddr, SocketAddr, Vec<u8>)>>,
+}
+
+impl ChannelNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A transport reachable at `addr`, replacing any earlier one.
+    pub fn attach(&self, addr: SocketAddr) -> ChannelTransport {
+        let (tx, rx) = mpsc::channel(CHANNEL_DEPTH);
+        self.lock().links.insert(addr, tx);
+        ChannelTransport {
+            local: addr,
+            network: self.clone(),
+            rx: Mutex::new(rx),
+        }
+    }
+
+    /// Drop every datagram from or to `addr` while `down` is set.
+    pub fn set_down(&self, addr: SocketAddr, down: bool) {
+        let mut links = self.lock();
+        if down {
+            links.down.insert(addr);
+        } else {
+            links.down.remove(&addr);
+        }
+    }
+
+    /// Every datagram delivered from now on, as `(from, to, datagram)`.
+    pub fn tap(&self) -> mpsc::UnboundedReceiver<(SocketAddr, SocketAddr, Vec<u8>)> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.lock().taps.push(tx);
+        rx
+    }
+
+    fn deliver(&self, from: SocketAddr, to: SocketAddr, datagram: &[u8]) {
+        let mut links = self.lock();
+        if links.down.contains(&from) || links.down.contains(&to) {
+            return;
+        }
+        let link = match links.links.get(&to) {
+            Some(link) => link,
+            None => return,
+        };
+        // A full channel drops the datagram, like a full socket buffer
+        if link.try_send((datagram.to_vec(), from)).is_ok() {
+            links
+                .taps
+                .retain(|tap| tap.send((from, to, datagram.to_vec())).is_ok());
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Links> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// One member of a [`ChannelNetwork`]. Datagrams for addresses that are
+/// not attached are dropped, as if unreachable.
+pub struct ChannelTransport {
+    local: SocketAddr,
+    network: ChannelNetwork,
+    rx: Mutex<mpsc::Receiver<Datagram>>,
+}
+
+impl ChannelTransport {
+    /// Two transports linked to each other, on the addresses `a` and `b`.
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (Self, Self) {
+        let network = ChannelNetwork::new();
+        (network.attach(a), network.attach(b))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+}
+
+#[async_trait]
+impl Transport for ChannelTransport {
+    async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()> {
+        for (packet, endpoint, _) in batch.packets() {
+            self.network.deliver(self.local, endpoint, packet);
+        }
+        batch.clear();
+        Ok(())
+    }
+
+    async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        batch.clear();
+        let mut rx = self.rx.lock().await;
+        let (packet, from) = rx
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        batch.push(&packet, from, Some(self.local.ip()));
+        for _ in 1..BATCH_SIZE {
+            match rx.try_recv() {
+                Ok((packet, from)) => batch.push(&packet, from, Some(self.local.ip())),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{ChannelTransport, Transport};
+    use crate::{
+        buffer::BufferPool,
+        udp::{RecvBatch, SendBatch},
+    };
+
+    #[tokio::test]
+    async fn channel_pair_delivers_to_the_other_end() {
+        let a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let (a_end, b_end) = ChannelTransport::pair(a, b);
+
+        let mut send = SendBatch::new();
+        for (payload, endpoint) in [(b"one", b), (b"two", "192.0.2.3:1".parse().unwrap())] {
+            send.slot()[..3].copy_from_slice(payload);
+            send.commit(3, endpoint, None);
+        }
+        a_end.send_batch(&mut send).await.unwrap();
+        assert!(send.is_empty());
+
+        let mut recv = RecvBatch::new(BufferPool::new(4));
+        b_end.recv_batch(&mut recv).await.unwrap();
+        let packets = recv.drain().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        let (buf, endpoint, local) = &packets[0];
+        assert_eq!(&buf[..], b"one");
+        assert_eq!(*endpoint, a);
+        assert_eq!(*local, Some(b.ip()));
+    }
+}
@@ -0,0 +1,476 @@
+use std::{io, net::IpAddr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tun_rs::AsyncDevice;
+
+use crate::{
+    buffer::{BufferPool, PacketBuffer, MAX_PACKET_SIZE},
+    device::Device,
+};
+
+/// Maximum number of packets written to the tun device at once.
+pub const TUN_BATCH_SIZE: usize = 32;
+
+/// Room left in front of every packet written to the tun device, needed for
+/// the virtio-net header when offload is enabled.
+pub const TUN_HEADROOM: usize = offload::VIRTIO_NET_HDR_LEN;
+
+/// Largest packet the kernel hands to an offload enabled tun device.
+const MAX_OFFLOAD_SIZE: usize = 65535;
+const TUN_MTU: usize = 1500;
+
+/// A kernel tun device.
+pub struct TunDevice {
+    dev: AsyncDevice,
+    name: String,
+    if_index: u32,
+}
+
+impl TunDevice {
+    pub fn new(address: IpAddr, prefix: u8, offload: bool) -> Result<Self> {
+        let builder = tun_rs::DeviceBuilder::new()
+            .ipv4(address, prefix, None)
+            .mtu(TUN_MTU as u16);
+        #[cfg(target_os = "linux")]
+        let builder = builder.offload(offload);
+        #[cfg(not(target_os = "linux"))]
+        if offload {
+            return Err(anyhow!("Offload is only supported on Linux"));
+        }
+        let dev = builder
+            .build_async()
+            .map_err(|e| anyhow!("Create tun device failed: {}", e))?;
+        let name = dev
+            .name()
+            .map_err(|e| anyhow!("Get tun dev interface name failed: {e}"))?;
+        let if_index = dev
+            .if_index()
+            .map_err(|e| anyhow!("Get tun dev interface index failed: {e}"))?;
+        Ok(Self {
+            dev,
+            name,
+            if_index,
+        })
+    }
+}
+
+#[async_trait]
+impl Device for TunDevice {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn mtu(&self) -> usize {
+        TUN_MTU
+    }
+
+    fn if_index(&self) -> Option<u32> {
+        Some(self.if_index)
+    }
+
+    async fn recv(&self, reader: &mut TunReader) -> io::Result<()> {
+        reader.recv(&self.dev).await
+    }
+
+    async fn send(&self, batch: &mut TunBatch) -> io::Result<()> {
+        batch.flush(&self.dev).await
+    }
+}
+
+/// Reads packets from the tun device into pooled buffers. With offload the
+/// kernel delivers large TCP/UDP segments that are split into MTU sized
+/// packets here, before they are encapsulated.
+pub struct TunReader {
+    pool: Arc<BufferPool>,
+    offload: bool,
+    original: Vec<u8>,
+    bufs: Vec<Vec<u8>>,
+    sizes: Vec<usize>,
+    packets: Vec<PacketBuffer>,
+}
+
+impl TunReader {
+    pub fn new(pool: Arc<BufferPool>, offload: bool) -> Self {
+        let (original, segments) = if offload {
+            (
+                vec![0u8; TUN_HEADROOM + MAX_OFFLOAD_SIZE],
+                offload::MAX_SEGMENTS,
+            )
+        } else {
+            (vec![], 1)
+        };
+        Self {
+            pool,
+            offload,
+            original,
+            bufs: vec![vec![0u8; MAX_PACKET_SIZE]; segments],
+            sizes: vec![0; segments],
+            packets: Vec::with_capacity(segments),
+        }
+    }
+
+    pub async fn recv(&mut self, dev: &AsyncDevice) -> io::Result<()> {
+        self.packets.clear();
+        if !self.offload {
+            let mut buf = self.pool.get();
+            let len = dev.recv(buf.as_full_mut()).await?;
+            buf.set_len(len);
+            self.packets.push(buf);
+            return Ok(());
+        }
+
+        let len = dev.recv(&mut self.original).await?;
+        let count = split_offload(&mut self.original[..len], &mut self.bufs, &mut self.sizes)?;
+        for (buf, size) in self.bufs.iter().zip(&self.sizes).take(count) {
+            let mut packet = self.pool.get();
+            packet.as_full_mut()[..*size].copy_from_slice(&buf[..*size]);
+            packet.set_len(*size);
+            self.packets.push(packet);
+        }
+        Ok(())
+    }
+
+    /// Add a copy of `packet`. Packets larger than a buffer are dropped.
+    pub fn push(&mut self, packet: &[u8]) {
+        let mut buf = self.pool.get();
+        if packet.len() > buf.as_full_mut().len() {
+            return;
+        }
+        buf.as_full_mut()[..packet.len()].copy_from_slice(packet);
+        buf.set_len(packet.len());
+        self.packets.push(buf);
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = PacketBuffer> + '_ {
+        self.packets.drain(..)
+    }
+}
+
+/// Split a packet read from an offload enabled tun device, prefixed by its
+/// virtio-net header, into MTU sized packets. Returns the number of packets
+/// written to `bufs`.
+pub fn split_offload(
+    input: &mut [u8],
+    bufs: &mut [Vec<u8>],
+    sizes: &mut [usize],
+) -> io::Result<usize> {
+    offload::split(input, bufs, sizes)
+}
+
+/// Decrypted packets waiting to be written to the tun device. With offload
+/// consecutive segments of the same flow are coalesced before writing.
+pub struct TunBatch {
+    bufs: Vec<BytesMut>,
+    len: usize,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    gro_table: offload::GroTable,
+}
+
+impl TunBatch {
+    pub fn new() -> Self {
+        Self {
+            bufs: (0..TUN_BATCH_SIZE)
+                .map(|_| BytesMut::with_capacity(TUN_HEADROOM + MAX_OFFLOAD_SIZE))
+                .collect(),
+            len: 0,
+            gro_table: offload::GroTable::new(),
+        }
+    }
+
+    pub fn push(&mut self, packet: &[u8]) {
+        if self.is_full() {
+            return;
+        }
+        let buf = &mut self.bufs[self.len];
+        buf.clear();
+        buf.resize(TUN_HEADROOM, 0);
+        buf.extend_from_slice(packet);
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len >= TUN_BATCH_SIZE
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// The buffers holding packets, each starting with [`TUN_HEADROOM`]
+    /// bytes of headroom.
+    pub fn bufs_mut(&mut self) -> &mut [BytesMut] {
+        &mut self.bufs[..self.len]
+    }
+
+    /// Write every packet of the batch to `dev` and clear it.
+    pub async fn flush(&mut self, dev: &AsyncDevice) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let result = offload::send(dev, &mut self.gro_table, &mut self.bufs[..self.len]).await;
+        self.len = 0;
+        result
+    }
+}
+
+impl Default for TunBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod offload {
+    use std::io;
+
+    use bytes::BytesMut;
+    use tun_rs::{checksum, gso_split, AsyncDevice, VirtioNetHdr};
+
+    use super::TUN_HEADROOM;
+
+    pub use tun_rs::{GROTable as GroTable, VIRTIO_NET_HDR_LEN};
+
+    /// Segments a single offloaded packet can be split into.
+    pub const MAX_SEGMENTS: usize = tun_rs::IDEAL_BATCH_SIZE;
+
+    const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+    const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+
+    pub fn split(input: &mut [u8], bufs: &mut [Vec<u8>], sizes: &mut [usize]) -> io::Result<usize> {
+        if input.len() <= VIRTIO_NET_HDR_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Offload packet too short: {}", input.len()),
+            ));
+        }
+        let hdr = VirtioNetHdr::decode(&input[..VIRTIO_NET_HDR_LEN])?;
+        let packet = &mut input[VIRTIO_NET_HDR_LEN..];
+
+        if hdr.gso_type == VIRTIO_NET_HDR_GSO_NONE {
+            if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                partial_checksum(packet, hdr.csum_start as usize, hdr.csum_offset as usize);
+            }
+            let len = packet.len();
+            let buf = bufs
+                .first_mut()
+                .filter(|buf| buf.len() >= len)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Packet too large"))?;
+            buf[..len].copy_from_slice(packet);
+            sizes[0] = len;
+            return Ok(1);
+        }
+
+        let is_v6 = packet[0] >> 4 == 6;
+        gso_split(packet, hdr, bufs, sizes, 0, is_v6)
+    }
+
+    /// Complete a checksum the kernel left partial: the field holds the
+    /// pseudo header sum, the rest is summed from `start` on.
+    fn partial_checksum(packet: &mut [u8], start: usize, offset: usize) {
+        let at = start + offset;
+        if at + 2 > packet.len() {
+            return;
+        }
+        let initial = u16::from_be_bytes([packet[at], packet[at + 1]]);
+        packet[at..at + 2].fill(0);
+        let sum = checksum(&packet[start..], initial as u64);
+        packet[at..at + 2].copy_from_slice(&(!sum).to_be_bytes());
+    }
+
+    pub async fn send(
+        dev: &AsyncDevice,
+        gro_table: &mut GroTable,
+        bufs: &mut [BytesMut],
+    ) -> io::Result<()> {
+        dev.send_multiple(gro_table, bufs, TUN_HEADROOM).await?;
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod offload {
+    use std::io;
+
+    use bytes::BytesMut;
+    use tun_rs::AsyncDevice;
+
+    use super::TUN_HEADROOM;
+
+    pub const VIRTIO_NET_HDR_LEN: usize = 0;
+    pub const MAX_SEGMENTS: usize = 1;
+
+    pub struct GroTable;
+
+    impl GroTable {
+        pub fn new() -> Self {
+            Self
+        }
+    }
+
+    pub fn split(
+        _input: &mut [u8],
+        _bufs: &mut [Vec<u8>],
+        _sizes: &mut [usize],
+    ) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub async fn send(
+        dev: &AsyncDevice,
+        _gro_table: &mut GroTable,
+        bufs: &mut [BytesMut],
+    ) -> io::Result<()> {
+        for buf in bufs {
+            dev.send(&buf[TUN_HEADROOM..]).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use tun_rs::{GROTable, VirtioNetHdr, VIRTIO_NET_HDR_GSO_TCPV4, VIRTIO_NET_HDR_LEN};
+
+    use super::*;
+
+    const IP_HEADER_LEN: usize = 20;
+    const TCP_HEADER_LEN: usize = 20;
+
+    /// An IPv4/TCP packet from 10.0.0.1:1000 to 10.0.0.2:2000.
+    fn tcp_packet(seq: u32, payload: &[u8]) -> Vec<u8> {
+        let total = IP_HEADER_LEN + TCP_HEADER_LEN + payload.len();
+        let mut packet = vec![0u8; total];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+        packet[8] = 64;
+        packet[9] = 6;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        let checksum = tun_rs::checksum(&packet[..IP_HEADER_LEN], 0);
+        packet[10..12].copy_from_slice(&(!checksum).to_be_bytes());
+
+        let tcp = &mut packet[IP_HEADER_LEN..];
+        tcp[0..2].copy_from_slice(&1000u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&2000u16.to_be_bytes());
+        tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+        tcp[12] = (TCP_HEADER_LEN as u8 / 4) << 4;
+        tcp[13] = 0x10;
+        tcp[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+        tcp[TCP_HEADER_LEN..].copy_from_slice(payload);
+
+        let tcp_len = (TCP_HEADER_LEN + payload.len()) as u16;
+        let mut pseudo = [0u8; 12];
+        pseudo[0..8].copy_from_slice(&packet[12..20]);
+        pseudo[9] = 6;
+        pseudo[10..12].copy_from_slice(&tcp_len.to_be_bytes());
+        let initial = tun_rs::checksum_no_fold(&pseudo, 0);
+        let checksum = tun_rs::checksum(&packet[IP_HEADER_LEN..], initial);
+        packet[IP_HEADER_LEN + 16..IP_HEADER_LEN + 18].copy_from_slice(&(!checksum).to_be_bytes());
+        packet
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn split_offload_segments_large_tcp_packet() {
+        let segment = 1360;
+        let data = payload(segment * 3 + 100);
+        let hdr = VirtioNetHdr {
+            flags: 0,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+            hdr_len: (IP_HEADER_LEN + TCP_HEADER_LEN) as u16,
+            gso_size: segment as u16,
+            csum_start: IP_HEADER_LEN as u16,
+            csum_offset: 16,
+        };
+        let mut input = vec![0u8; VIRTIO_NET_HDR_LEN];
+        hdr.encode(&mut input).unwrap();
+        input.extend_from_slice(&tcp_packet(1, &data));
+
+        let mut bufs = vec![vec![0u8; MAX_PACKET_SIZE]; 8];
+        let mut sizes = vec![0; 8];
+        let count = split_offload(&mut input, &mut bufs, &mut sizes).unwrap();
+
+        assert_eq!(count, 4);
+        let headers = IP_HEADER_LEN + TCP_HEADER_LEN;
+        assert_eq!(
+            &sizes[..count],
+            &[
+                headers + segment,
+                headers + segment,
+                headers + segment,
+                headers + 100
+            ]
+        );
+        for (index, buf) in bufs.iter().take(count).enumerate() {
+            let seq = u32::from_be_bytes(
+                buf[IP_HEADER_LEN + 4..IP_HEADER_LEN + 8]
+                    .try_into()
+                    .unwrap(),
+            );
+            assert_eq!(seq, 1 + (index * segment) as u32);
+            let total = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            assert_eq!(total, sizes[index]);
+            let start = index * segment;
+            assert_eq!(
+                &buf[headers..sizes[index]],
+                &data[start..start + sizes[index] - headers]
+            );
+        }
+    }
+
+    #[test]
+    fn split_offload_passes_through_plain_packet() {
+        let packet = tcp_packet(1, &payload(100));
+        let mut input = vec![0u8; VIRTIO_NET_HDR_LEN];
+        input.extend_from_slice(&packet);
+
+        let mut bufs = vec![vec![0u8; MAX_PACKET_SIZE]; 2];
+        let mut sizes = vec![0; 2];
+        let count = split_offload(&mut input, &mut bufs, &mut sizes).unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(&bufs[0][..sizes[0]], &packet[..]);
+    }
+
+    #[test]
+    fn tun_batch_segments_are_coalesced() {
+        let segment = 1000;
+        let mut batch = TunBatch::new();
+        for index in 0..4 {
+            let seq = 1 + (index * segment) as u32;
+            batch.push(&tcp_packet(seq, &payload(segment)));
+        }
+        assert_eq!(batch.len(), 4);
+
+        let mut gro_table = GROTable::default();
+        gro_table
+            .apply_gro(batch.bufs_mut(), TUN_HEADROOM, false)
+            .unwrap();
+
+        let first = &batch.bufs_mut()[0];
+        let hdr = VirtioNetHdr::decode(&first[..VIRTIO_NET_HDR_LEN]).unwrap();
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!(hdr.gso_size as usize, segment);
+        assert_eq!(
+            first.len(),
+            TUN_HEADROOM + IP_HEADER_LEN + TCP_HEADER_LEN + segment * 4
+        );
+    }
+}
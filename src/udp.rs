@@ -0,0 +1,1034 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use socket2::{Domain, Socket, Type};
+use tokio::{net::UdpSocket, sync::watch};
+
+use crate::buffer::{BufferPool, PacketBuffer, MAX_PACKET_SIZE};
+
+/// Maximum number of datagrams handled by one batched system call.
+pub const BATCH_SIZE: usize = 32;
+
+/// The outer UDP socket. On Linux it receives and sends with
+/// recvmmsg/sendmmsg and uses UDP GRO/GSO when the kernel supports them,
+/// otherwise it falls back to one datagram per system call.
+///
+/// When bound to a wildcard address it also records the local address each
+/// datagram arrived on, so replies can be sent from that same address.
+pub struct BatchSocket {
+    socket: UdpSocket,
+    ipv6: bool,
+    mmsg: AtomicBool,
+    gso: AtomicBool,
+    gro: bool,
+    pktinfo: bool,
+}
+
+impl BatchSocket {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self::new(UdpSocket::bind(addr).await?))
+    }
+
+    /// Bind the listening socket. Without `address` it binds the dual-stack
+    /// wildcard `[::]`, or `0.0.0.0` when IPv6 is unavailable. `device`
+    /// restricts the socket to one network interface.
+    pub fn listen(address: Option<IpAddr>, port: u16, device: Option<&str>) -> io::Result<Self> {
+        let socket = match address {
+            Some(address) => bind_socket(SocketAddr::new(address, port), device)?,
+            None => bind_socket(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port), device)
+                .or_else(|_| {
+                    bind_socket(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port), device)
+                })?,
+        };
+        Ok(Self::new(UdpSocket::from_std(socket.into())?))
+    }
+
+    pub fn new(socket: UdpSocket) -> Self {
+        let local_addr = socket.local_addr().ok();
+        let ipv6 = local_addr.is_some_and(|addr| addr.is_ipv6());
+        let (mmsg, gso, gro) = offload::probe(&socket);
+        // A socket bound to one address always replies from it
+        let pktinfo = local_addr.is_some_and(|addr| addr.ip().is_unspecified())
+            && offload::enable_pktinfo(&socket, ipv6);
+        Self {
+            socket,
+            ipv6,
+            mmsg: AtomicBool::new(mmsg),
+            gso: AtomicBool::new(gso),
+            gro,
+            pktinfo,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn gso_enabled(&self) -> bool {
+        self.gso.load(Ordering::Relaxed)
+    }
+
+    pub fn gro_enabled(&self) -> bool {
+        self.gro
+    }
+
+    pub fn pktinfo_enabled(&self) -> bool {
+        self.pktinfo
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, self.outgoing(target)).await
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, endpoint) = self.socket.recv_from(buf).await?;
+        Ok((len, canonical(endpoint)))
+    }
+
+    /// Receive at least one datagram into `batch`, splitting coalesced GRO
+    /// datagrams back into single packets.
+    pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        batch.packets.clear();
+        if self.mmsg.load(Ordering::Relaxed) {
+            match offload::recv_batch(self, batch).await {
+                Err(e) if offload::is_unsupported(&e) => {
+                    self.mmsg.store(false, Ordering::Relaxed);
+                }
+                other => return other,
+            }
+        }
+
+        let mut buf = batch.pool.get();
+        let (len, endpoint) = self.recv_from(buf.as_full_mut()).await?;
+        buf.set_len(len);
+        batch.packets.push((buf, endpoint, None));
+        Ok(())
+    }
+
+    /// Send every packet of `batch` and clear it.
+    pub async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let result = if self.mmsg.load(Ordering::Relaxed) {
+            match offload::send_batch(self, batch).await {
+                Err(e) if offload::is_unsupported(&e) => {
+                    self.mmsg.store(false, Ordering::Relaxed);
+                    self.send_each(batch).await
+                }
+                other => other,
+            }
+        } else {
+            self.send_each(batch).await
+        };
+        batch.clear();
+        result
+    }
+
+    /// Send what `batch` holds without waiting and clear it. Returns false,
+    /// keeping what is left, when the socket isn't writable or the send needs
+    /// [`Self::send_batch`] to recover from an error.
+    pub fn try_send_batch(&self, batch: &mut SendBatch) -> io::Result<bool> {
+        if batch.is_empty() {
+            return Ok(true);
+        }
+        if !self.mmsg.load(Ordering::Relaxed) || !offload::try_send_batch(self, batch) {
+            return Ok(false);
+        }
+        batch.clear();
+        Ok(true)
+    }
+
+    async fn send_each(&self, batch: &SendBatch) -> io::Result<()> {
+        for (packet, endpoint, _) in batch.packets().skip(batch.sent) {
+            self.send_to(packet, endpoint).await?;
+        }
+        Ok(())
+    }
+
+    /// IPv4 endpoints are reached through a dual-stack socket by their
+    /// IPv4-mapped IPv6 address.
+    fn outgoing(&self, endpoint: SocketAddr) -> SocketAddr {
+        match endpoint {
+            SocketAddr::V4(addr) if self.ipv6 => {
+                SocketAddr::new(addr.ip().to_ipv6_mapped().into(), addr.port())
+            }
+            other => other,
+        }
+    }
+}
+
+/// The listening [`BatchSocket`], replaced by a freshly bound one when the
+/// network changes. Senders look the current socket up for every batch.
+pub struct OuterSocket {
+    address: Option<IpAddr>,
+    port: u16,
+    device: Option<String>,
+    current: RwLock<Option<Arc<BatchSocket>>>,
+    changed: watch::Sender<()>,
+}
+
+impl OuterSocket {
+    pub fn listen(address: Option<IpAddr>, port: u16, device: Option<String>) -> io::Result<Self> {
+        let socket = BatchSocket::listen(address, port, device.as_deref())?;
+        // Keep an ephemeral port across rebinds
+        let port = socket.local_addr()?.port();
+        Ok(Self {
+            address,
+            port,
+            device,
+            current: RwLock::new(Some(Arc::new(socket))),
+            changed: watch::Sender::new(()),
+        })
+    }
+
+    /// The bound socket, or `None` while it is being rebound.
+    pub fn current(&self) -> Option<Arc<BatchSocket>> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Notified whenever the socket is released or replaced.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    /// Send every packet of `batch` and clear it. Packets are dropped while
+    /// the socket is being rebound.
+    pub async fn send_batch(&self, batch: &mut SendBatch) -> io::Result<()> {
+        match self.current() {
+            Some(socket) => socket.send_batch(batch).await,
+            None => {
+                batch.clear();
+                Ok(())
+            }
+        }
+    }
+
+    /// See [`BatchSocket::try_send_batch`].
+    pub fn try_send_batch(&self, batch: &mut SendBatch) -> io::Result<bool> {
+        match self.current() {
+            Some(socket) => socket.try_send_batch(batch),
+            None => {
+                batch.clear();
+                Ok(true)
+            }
+        }
+    }
+
+    /// Receive at least one datagram into `batch`, moving on to the new
+    /// socket when it is rebound meanwhile.
+    pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        let mut changed = self.subscribe();
+        loop {
+            changed.borrow_and_update();
+            let socket = match self.current() {
+                Some(socket) => socket,
+                None => {
+                    let _ = changed.changed().await;
+                    continue;
+                }
+            };
+            tokio::select! {
+                result = socket.recv_batch(batch) => return result,
+                _ = changed.changed() => {}
+            }
+        }
+    }
+
+    /// Close the current socket and bind a new one with the same options.
+    /// On failure no socket is bound until the next successful call.
+    pub async fn rebind(&self) -> io::Result<()> {
+        let old = self
+            .current
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        self.changed.send_replace(());
+        if let Some(old) = old {
+            // The port can only be bound again once every user let go
+            for _ in 0..100 {
+                if Arc::strong_count(&old) == 1 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        let socket = BatchSocket::listen(self.address, self.port, self.device.as_deref())?;
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(socket));
+        self.changed.send_replace(());
+        Ok(())
+    }
+}
+
+fn bind_socket(addr: SocketAddr, device: Option<&str>) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    if let Some(device) = device {
+        bind_device(&socket, device)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Binding to an interface is only supported on Linux",
+    ))
+}
+
+/// Addresses seen on a dual-stack socket with IPv4-mapped IPv6 addresses
+/// turned back into IPv4, so they match the configured endpoints.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        other => other,
+    }
+}
+
+/// Datagrams received by one `recv_batch` call, with the endpoint they came
+/// from and the local address they arrived on if known.
+pub struct RecvBatch {
+    pool: Arc<BufferPool>,
+    packets: Vec<(PacketBuffer, SocketAddr, Option<IpAddr>)>,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    bufs: Vec<Vec<u8>>,
+}
+
+impl RecvBatch {
+    pub fn new(pool: Arc<BufferPool>) -> Self {
+        Self {
+            pool,
+            packets: Vec::with_capacity(BATCH_SIZE * offload::MAX_SEGMENTS),
+            bufs: vec![],
+        }
+    }
+
+    /// Add a copy of `packet`, truncated to the buffer size.
+    pub fn push(&mut self, packet: &[u8], endpoint: SocketAddr, local: Option<IpAddr>) {
+        let mut buf = self.pool.get();
+        let size = packet.len().min(buf.as_full_mut().len());
+        buf.as_full_mut()[..size].copy_from_slice(&packet[..size]);
+        buf.set_len(size);
+        self.packets.push((buf, endpoint, local));
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+
+    /// Keep only the datagrams `keep` returns true for. It may rewrite them
+    /// and the endpoint they came from.
+    pub fn retain_mut(&mut self, mut keep: impl FnMut(&mut PacketBuffer, &mut SocketAddr) -> bool) {
+        self.packets
+            .retain_mut(|(buf, endpoint, _)| keep(buf, endpoint));
+    }
+
+    /// Size the system call buffers for datagrams of up to `buf_size` bytes.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn reserve(&mut self, buf_size: usize) {
+        if self.bufs.first().map(|buf| buf.len()) != Some(buf_size) {
+            self.bufs = vec![vec![0u8; buf_size]; BATCH_SIZE];
+        }
+    }
+
+    pub fn drain(
+        &mut self,
+    ) -> impl Iterator<Item = (PacketBuffer, SocketAddr, Option<IpAddr>)> + '_ {
+        self.packets.drain(..)
+    }
+}
+
+/// Datagrams waiting to be sent, stored back to back in packet sized slots.
+/// Each one may carry the local address it should be sent from.
+pub struct SendBatch {
+    buf: Vec<u8>,
+    lens: Vec<usize>,
+    endpoints: Vec<SocketAddr>,
+    sources: Vec<Option<IpAddr>>,
+    /// Packets already sent by a send that had to stop half way.
+    sent: usize,
+}
+
+impl SendBatch {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![0u8; BATCH_SIZE * MAX_PACKET_SIZE],
+            lens: Vec::with_capacity(BATCH_SIZE),
+            endpoints: Vec::with_capacity(BATCH_SIZE),
+            sources: Vec::with_capacity(BATCH_SIZE),
+            sent: 0,
+        }
+    }
+
+    /// The next free slot, to be filled and then passed to [`Self::commit`].
+    pub fn slot(&mut self) -> &mut [u8] {
+        let start = self.lens.len() * MAX_PACKET_SIZE;
+        &mut self.buf[start..start + MAX_PACKET_SIZE]
+    }
+
+    pub fn commit(&mut self, len: usize, endpoint: SocketAddr, source: Option<IpAddr>) {
+        self.lens.push(len.min(MAX_PACKET_SIZE));
+        self.endpoints.push(endpoint);
+        self.sources.push(source);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lens.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.lens.len() >= BATCH_SIZE
+    }
+
+    pub fn clear(&mut self) {
+        self.lens.clear();
+        self.endpoints.clear();
+        self.sources.clear();
+        self.sent = 0;
+    }
+
+    pub fn packets_mut(&mut self) -> impl Iterator<Item = &mut [u8]> + '_ {
+        self.buf
+            .chunks_mut(MAX_PACKET_SIZE)
+            .zip(&self.lens)
+            .map(|(slot, len)| &mut slot[..*len])
+    }
+
+    pub fn packets(&self) -> impl Iterator<Item = (&[u8], SocketAddr, Option<IpAddr>)> + '_ {
+        self.lens
+            .iter()
+            .zip(&self.endpoints)
+            .zip(&self.sources)
+            .enumerate()
+            .map(|(index, ((len, endpoint), source))| {
+                let start = index * MAX_PACKET_SIZE;
+                (&self.buf[start..start + len], *endpoint, *source)
+            })
+    }
+}
+
+impl Default for SendBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod offload {
+    use std::{
+        io, mem,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+        os::fd::AsRawFd,
+        ptr,
+        sync::atomic::Ordering,
+    };
+
+    use tokio::{io::Interest, net::UdpSocket};
+
+    use super::{canonical, BatchSocket, RecvBatch, SendBatch, BATCH_SIZE};
+    use crate::buffer::MAX_PACKET_SIZE;
+
+    /// Largest datagram the kernel hands out with UDP GRO.
+    pub const GRO_BUFFER_SIZE: usize = 65535;
+    /// Kernel limit on segments in one UDP GSO send.
+    pub const MAX_SEGMENTS: usize = 64;
+
+    const CMSG_BUF_SIZE: usize = 128;
+
+    #[repr(C, align(8))]
+    struct CmsgBuf([u8; CMSG_BUF_SIZE]);
+
+    pub fn probe(socket: &UdpSocket) -> (bool, bool, bool) {
+        let fd = socket.as_raw_fd();
+
+        let mut segment: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let gso = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                &mut segment as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        } == 0;
+
+        let gro = set_option(fd, libc::SOL_UDP, libc::UDP_GRO);
+
+        (true, gso, gro)
+    }
+
+    /// Ask for the destination address of received datagrams. IPv4 packets
+    /// on a dual-stack socket report it through IP_PKTINFO as well.
+    pub fn enable_pktinfo(socket: &UdpSocket, ipv6: bool) -> bool {
+        let fd = socket.as_raw_fd();
+        let ipv4 = set_option(fd, libc::IPPROTO_IP, libc::IP_PKTINFO);
+        if ipv6 {
+            ipv4 && set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)
+        } else {
+            ipv4
+        }
+    }
+
+    fn set_option(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> bool {
+        let enable: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &enable as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        result == 0
+    }
+
+    pub fn is_unsupported(e: &io::Error) -> bool {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP)
+        )
+    }
+
+    pub async fn recv_batch(socket: &BatchSocket, batch: &mut RecvBatch) -> io::Result<()> {
+        let fd = socket.socket.as_raw_fd();
+        let mut received = [(0usize, None, 0usize, None); BATCH_SIZE];
+        batch.reserve(if socket.gro {
+            GRO_BUFFER_SIZE
+        } else {
+            MAX_PACKET_SIZE
+        });
+
+        let count = socket
+            .socket
+            .async_io(Interest::READABLE, || {
+                recvmmsg(fd, &mut batch.bufs, &mut received)
+            })
+            .await?;
+
+        for (index, (len, endpoint, segment, local)) in received.iter().take(count).enumerate() {
+            let endpoint = match endpoint {
+                Some(endpoint) => canonical(*endpoint),
+                None => continue,
+            };
+            let segment = if *segment == 0 { *len } else { *segment };
+            let bufs = mem::take(&mut batch.bufs);
+            for chunk in bufs[index][..*len].chunks(segment.max(1)) {
+                batch.push(chunk, endpoint, *local);
+            }
+            batch.bufs = bufs;
+        }
+        Ok(())
+    }
+
+    type Received = (usize, Option<SocketAddr>, usize, Option<IpAddr>);
+
+    fn recvmmsg(
+        fd: libc::c_int,
+        bufs: &mut [Vec<u8>],
+        received: &mut [Received; BATCH_SIZE],
+    ) -> io::Result<usize> {
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut cmsgs: [CmsgBuf; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        let count = bufs.len().min(BATCH_SIZE);
+        for index in 0..count {
+            iovecs[index].iov_base = bufs[index].as_mut_ptr() as *mut libc::c_void;
+            iovecs[index].iov_len = bufs[index].len();
+            let hdr = &mut msgs[index].msg_hdr;
+            hdr.msg_name = &mut names[index] as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut iovecs[index];
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = cmsgs[index].0.as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = CMSG_BUF_SIZE as _;
+        }
+
+        let result = unsafe {
+            libc::recvmmsg(
+                fd,
+                msgs.as_mut_ptr(),
+                count as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let result = result as usize;
+        for index in 0..result {
+            let (segment, local) = parse_cmsgs(&msgs[index].msg_hdr);
+            received[index] = (
+                msgs[index].msg_len as usize,
+                from_sockaddr(&names[index]),
+                segment,
+                local,
+            );
+        }
+        Ok(result)
+    }
+
+    /// The GRO segment size and the local address the datagram arrived on.
+    fn parse_cmsgs(hdr: &libc::msghdr) -> (usize, Option<IpAddr>) {
+        let mut segment = 0;
+        let mut local = None;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(hdr) };
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+            let data = unsafe { libc::CMSG_DATA(cmsg) };
+            match (header.cmsg_level, header.cmsg_type) {
+                (libc::SOL_UDP, libc::UDP_GRO) => {
+                    let size = unsafe { ptr::read_unaligned(data as *const libc::c_int) };
+                    segment = size.max(0) as usize;
+                }
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info = unsafe { ptr::read_unaligned(data as *const libc::in_pktinfo) };
+                    local = Some(IpAddr::V4(Ipv4Addr::from(
+                        info.ipi_addr.s_addr.to_ne_bytes(),
+                    )));
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = unsafe { ptr::read_unaligned(data as *const libc::in6_pktinfo) };
+                    let ip = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+                    local = Some(match ip.to_ipv4_mapped() {
+                        Some(ip) => IpAddr::V4(ip),
+                        None => IpAddr::V6(ip),
+                    });
+                }
+                _ => {}
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(hdr, cmsg) };
+        }
+        (segment, local)
+    }
+
+    pub async fn send_batch(socket: &BatchSocket, batch: &SendBatch) -> io::Result<()> {
+        let fd = socket.socket.as_raw_fd();
+        let mut pktinfo = socket.pktinfo;
+        let mut segment = true;
+        let mut sent = batch.sent;
+        while sent < batch.len() {
+            let gso = segment && socket.gso.load(Ordering::Relaxed);
+            let result = socket
+                .socket
+                .async_io(Interest::WRITABLE, || {
+                    sendmmsg(fd, socket, batch, sent, gso, pktinfo)
+                })
+                .await;
+            match result {
+                Ok(packets) => {
+                    sent += packets;
+                    segment = true;
+                }
+                // The egress device can't segment, send each datagram instead
+                Err(e) if gso && e.raw_os_error() == Some(libc::EIO) => {
+                    socket.gso.store(false, Ordering::Relaxed);
+                }
+                // The run is too large for the path, resend it unsegmented
+                Err(e) if gso && e.raw_os_error() == Some(libc::EMSGSIZE) => {
+                    segment = false;
+                }
+                // A source address that is no longer assigned, let the
+                // kernel pick one until the peer is heard from again
+                Err(e)
+                    if pktinfo
+                        && matches!(
+                            e.raw_os_error(),
+                            Some(libc::EINVAL)
+                                | Some(libc::EADDRNOTAVAIL)
+                                | Some(libc::ENETUNREACH)
+                        ) =>
+                {
+                    pktinfo = false;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Send as much of `batch` as the socket takes now, whether all of it
+    /// went. Errors are left to [`send_batch`], which sends the rest.
+    pub fn try_send_batch(socket: &BatchSocket, batch: &mut SendBatch) -> bool {
+        let fd = socket.socket.as_raw_fd();
+        while batch.sent < batch.len() {
+            let gso = socket.gso.load(Ordering::Relaxed);
+            match socket.socket.try_io(Interest::WRITABLE, || {
+                sendmmsg(fd, socket, batch, batch.sent, gso, socket.pktinfo)
+            }) {
+                Ok(packets) => batch.sent += packets,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    /// Send the packets of `batch` starting at `offset`, returns how many
+    /// packets were sent. With `gso`, runs of equally sized packets to the
+    /// same endpoint are sent as one datagram with UDP_SEGMENT. With
+    /// `pktinfo`, packets are sent from their source address.
+    fn sendmmsg(
+        fd: libc::c_int,
+        socket: &BatchSocket,
+        batch: &SendBatch,
+        offset: usize,
+        gso: bool,
+        pktinfo: bool,
+    ) -> io::Result<usize> {
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut cmsgs: [CmsgBuf; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut run_lens = [0usize; BATCH_SIZE];
+
+        let mut packets = [(&[][..], SocketAddr::from(([0, 0, 0, 0], 0)), None); BATCH_SIZE];
+        let mut count = 0;
+        for packet in batch.packets().skip(offset).take(BATCH_SIZE) {
+            packets[count] = packet;
+            count += 1;
+        }
+        let packets = &packets[..count];
+
+        let mut runs = 0;
+        let mut index = 0;
+        while index < packets.len() {
+            let (first, endpoint, source) = packets[index];
+            let source = if pktinfo { source } else { None };
+            let end = if gso {
+                run_end(packets, index, pktinfo)
+            } else {
+                index + 1
+            };
+
+            for (slot, (packet, _, _)) in packets[index..end].iter().enumerate() {
+                iovecs[index + slot].iov_base = packet.as_ptr() as *mut libc::c_void;
+                iovecs[index + slot].iov_len = packet.len();
+            }
+
+            let hdr = &mut msgs[runs].msg_hdr;
+            hdr.msg_namelen = to_sockaddr(&socket.outgoing(endpoint), &mut names[runs]);
+            hdr.msg_name = &mut names[runs] as *mut _ as *mut libc::c_void;
+            hdr.msg_iov = &mut iovecs[index];
+            hdr.msg_iovlen = (end - index) as _;
+            let segment = (end - index > 1).then_some(first.len() as u16);
+            write_cmsgs(hdr, &mut cmsgs[runs], segment, source);
+
+            run_lens[runs] = end - index;
+            runs += 1;
+            index = end;
+        }
+
+        let result = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), runs as libc::c_uint, 0) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(run_lens[..result as usize].iter().sum())
+    }
+
+    /// The end of the GSO run starting at `index`: following packets to the
+    /// same endpoint and source, none longer than the first, and only the
+    /// last one shorter. The payloads of a run share one UDP datagram, so
+    /// together with the UDP and IP headers they must fit in 64 KiB.
+    pub fn run_end(
+        packets: &[(&[u8], SocketAddr, Option<IpAddr>)],
+        index: usize,
+        pktinfo: bool,
+    ) -> usize {
+        let (first, endpoint, source) = packets[index];
+        let ip_header = match endpoint.ip() {
+            IpAddr::V6(ip) if ip.to_ipv4_mapped().is_none() => 40,
+            _ => 20,
+        };
+        let limit = u16::MAX as usize - 8 - ip_header;
+        let mut total = first.len();
+        let mut end = index + 1;
+        while end < packets.len()
+            && end - index < MAX_SEGMENTS
+            && packets[end].1 == endpoint
+            && (!pktinfo || packets[end].2 == source)
+            && packets[end].0.len() <= first.len()
+            && total + packets[end].0.len() <= limit
+        {
+            total += packets[end].0.len();
+            end += 1;
+            // A shorter segment can only be the last one
+            if packets[end - 1].0.len() < first.len() {
+                break;
+            }
+        }
+        end
+    }
+
+    /// Attach the UDP_SEGMENT size and the source address to `hdr`.
+    fn write_cmsgs(
+        hdr: &mut libc::msghdr,
+        buf: &mut CmsgBuf,
+        segment: Option<u16>,
+        source: Option<IpAddr>,
+    ) {
+        if segment.is_none() && source.is_none() {
+            return;
+        }
+
+        hdr.msg_control = buf.0.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = CMSG_BUF_SIZE as _;
+        let mut len = 0;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            if let Some(segment) = segment {
+                len += write_cmsg(cmsg, libc::SOL_UDP, libc::UDP_SEGMENT, segment);
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+            match source {
+                Some(IpAddr::V4(ip)) => {
+                    let mut info: libc::in_pktinfo = mem::zeroed();
+                    info.ipi_spec_dst.s_addr = u32::from_ne_bytes(ip.octets());
+                    len += write_cmsg(cmsg, libc::IPPROTO_IP, libc::IP_PKTINFO, info);
+                }
+                Some(IpAddr::V6(ip)) => {
+                    let mut info: libc::in6_pktinfo = mem::zeroed();
+                    info.ipi6_addr.s6_addr = ip.octets();
+                    len += write_cmsg(cmsg, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info);
+                }
+                None => {}
+            }
+        }
+        hdr.msg_controllen = len as _;
+    }
+
+    /// Write one control message, returns the space it takes.
+    unsafe fn write_cmsg<T>(
+        cmsg: *mut libc::cmsghdr,
+        level: libc::c_int,
+        kind: libc::c_int,
+        data: T,
+    ) -> usize {
+        let size = mem::size_of::<T>() as u32;
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = kind;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut T, data);
+        libc::CMSG_SPACE(size) as usize
+    }
+
+    fn to_sockaddr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match addr {
+            SocketAddr::V4(addr) => {
+                let sin = storage as *mut _ as *mut libc::sockaddr_in;
+                unsafe {
+                    (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                    (*sin).sin_port = addr.port().to_be();
+                    (*sin).sin_addr = libc::in_addr {
+                        s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                    };
+                }
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = storage as *mut _ as *mut libc::sockaddr_in6;
+                unsafe {
+                    (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    (*sin6).sin6_port = addr.port().to_be();
+                    (*sin6).sin6_flowinfo = addr.flowinfo();
+                    (*sin6).sin6_addr = libc::in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    };
+                    (*sin6).sin6_scope_id = addr.scope_id();
+                }
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod offload {
+    use std::io;
+
+    use tokio::net::UdpSocket;
+
+    use super::{BatchSocket, RecvBatch, SendBatch};
+
+    pub const MAX_SEGMENTS: usize = 1;
+
+    pub fn probe(_socket: &UdpSocket) -> (bool, bool, bool) {
+        (false, false, false)
+    }
+
+    pub fn enable_pktinfo(_socket: &UdpSocket, _ipv6: bool) -> bool {
+        false
+    }
+
+    pub fn is_unsupported(_e: &io::Error) -> bool {
+        true
+    }
+
+    pub async fn recv_batch(_socket: &BatchSocket, _batch: &mut RecvBatch) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub async fn send_batch(_socket: &BatchSocket, _batch: &SendBatch) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn try_send_batch(_socket: &BatchSocket, _batch: &mut SendBatch) -> bool {
+        false
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use tokio::net::UdpSocket;
+
+    use super::{offload, BatchSocket, OuterSocket, RecvBatch, SendBatch};
+    use crate::buffer::BufferPool;
+
+    #[test]
+    fn gso_runs_fit_one_datagram() {
+        let payload = [0u8; 1040];
+        let v4 = SocketAddr::from(([192, 0, 2, 1], 51820));
+        let v6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 51820));
+
+        // 63 segments of 1040 bytes fit in 16 bits, not with the headers
+        let packets = vec![(&payload[..], v4, None); 63];
+        assert_eq!(offload::run_end(&packets, 0, false), 62);
+        assert_eq!(offload::run_end(&packets, 62, false), 63);
+
+        // A short last segment fits next to a 20 byte IPv4 header, not a
+        // 40 byte IPv6 one
+        let mut packets = vec![(&payload[..], v4, None); 62];
+        packets.push((&payload[..1020], v4, None));
+        assert_eq!(offload::run_end(&packets, 0, false), 63);
+        for packet in &mut packets {
+            packet.1 = v6;
+        }
+        assert_eq!(offload::run_end(&packets, 0, false), 62);
+    }
+
+    #[tokio::test]
+    async fn wildcard_replies_from_arrival_address() {
+        let socket = BatchSocket::listen(None, 0, None).unwrap();
+        assert!(socket.local_addr().unwrap().ip().is_unspecified());
+        assert!(socket.pktinfo_enabled());
+        let port = socket.local_addr().unwrap().port();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", ("127.0.0.1", port)).await.unwrap();
+
+        let mut batch = RecvBatch::new(BufferPool::new(4));
+        socket.recv_batch(&mut batch).await.unwrap();
+        let (buf, endpoint, local) = batch.drain().next().unwrap();
+        assert_eq!(&buf[..], b"ping");
+        // IPv4 peers keep their IPv4 address on a dual-stack socket
+        assert_eq!(endpoint, client.local_addr().unwrap());
+        let local = local.unwrap();
+        assert_eq!(local, IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let mut send = SendBatch::new();
+        send.slot()[..4].copy_from_slice(b"pong");
+        send.commit(4, endpoint, Some(local));
+        socket.send_batch(&mut send).await.unwrap();
+
+        let mut reply = [0u8; 16];
+        let (len, from) = client.recv_from(&mut reply).await.unwrap();
+        assert_eq!(&reply[..len], b"pong");
+        assert_eq!(from, SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+    }
+
+    #[tokio::test]
+    async fn stale_source_falls_back() {
+        let socket = BatchSocket::listen(None, 0, None).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // Not assigned to any local interface
+        let stale = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut send = SendBatch::new();
+        send.slot()[..4].copy_from_slice(b"pong");
+        send.commit(4, client.local_addr().unwrap(), Some(stale));
+        socket.send_batch(&mut send).await.unwrap();
+
+        let mut reply = [0u8; 16];
+        let (len, from) = client.recv_from(&mut reply).await.unwrap();
+        assert_eq!(&reply[..len], b"pong");
+        assert_eq!(from.port(), port);
+    }
+
+    #[tokio::test]
+    async fn rebind_keeps_port_and_notifies() {
+        let outer = OuterSocket::listen(Some(IpAddr::V4(Ipv4Addr::LOCALHOST)), 0, None).unwrap();
+        let addr = outer.current().unwrap().local_addr().unwrap();
+        let mut changed = outer.subscribe();
+        changed.borrow_and_update();
+
+        outer.rebind().await.unwrap();
+        assert!(changed.has_changed().unwrap());
+        let socket = outer.current().unwrap();
+        assert_eq!(socket.local_addr().unwrap(), addr);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", addr).await.unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, client.local_addr().unwrap());
+    }
+}
@@ -33,7 +33,7 @@ pub(crate) fn decode_public_key(public_key: &str) -> Result<PublicKey> {
         .try_into()
         .map_err(|_| anyhow!("Public Key try_into failed"))?;
 
-    PublicKey::try_from(key).map_err(|e| anyhow!("Curve check failed: {:?}", e))
+    Ok(PublicKey::from(key))
 }
 
 pub(crate) fn parse_address(address: &str) -> Result<(IpAddr, u8)> {
@@ -49,8 +49,7 @@ pub(crate) fn parse_dns(dns: &str) -> Result<IpAddr> {
 pub(crate) fn parse_allowed_ips(cidrs: &[&str]) -> Result<Vec<(IpAddr, u8)>> {
     let mut allowed_ips = Vec::new();
     for cidr in cidrs {
-        let (ip, mask) =
-            parse_cidr(cidr).ok_or(anyhow!("Parse allowed_ips: {}", (*cidr).to_string()))?;
+        let (ip, mask) = parse_cidr(cidr).ok_or(anyhow!("Parse allowed_ips: {}", cidr))?;
         allowed_ips.push((ip, mask));
     }
     Ok(allowed_ips)
@@ -63,10 +62,19 @@ pub(crate) fn parse_cidr(s: &str) -> Option<(IpAddr, u8)> {
     Some((ip, mask))
 }
 
-pub(crate) fn if_index_to_addr(index: u32) -> Result<IpAddr> {
-    let addr = getifaddrs::getifaddrs()?
-        .find(|v| v.index == Some(index) && v.address.is_ipv4())
-        .map(|i| i.address)
-        .ok_or(anyhow!("Interface address not found"))?;
-    Ok(addr)
+pub(crate) fn cidr_contains(cidr: &(IpAddr, u8), ip: &IpAddr) -> bool {
+    let (network, prefix) = cidr;
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let prefix = (*prefix).min(32) as u32;
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(*network) & mask == u32::from(*ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let prefix = (*prefix).min(128) as u32;
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(*network) & mask == u128::from(*ip) & mask
+        }
+        _ => false,
+    }
 }
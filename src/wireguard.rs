@@ -1,26 +1,87 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
-use boringtun::noise::Tunn;
-use dashmap::DashMap;
-use rand::RngCore;
-use route_manager::{Route, RouteManager};
-use tokio::net::UdpSocket;
+use boringtun::x25519::PublicKey;
+use route_manager::{AsyncRouteListener, AsyncRouteManager, Route, RouteChange, RouteManager};
+use tokio::sync::{broadcast, Notify};
 
-use crate::{interface::Interface, peer::Peer, utils::if_index_to_addr};
+use crate::{
+    buffer::BufferPool,
+    control::Control,
+    device::Device,
+    forward::{Forward, ForwardKind},
+    interface::Interface,
+    limiter::{HandshakeLimiter, HandshakeStats, HANDSHAKE_RATE_LIMIT, HANDSHAKE_RESPONSE},
+    netstack::Netstack,
+    obfuscation::ObfuscatedTransport,
+    peer::{Peer, PeerEvent, ENDPOINT_RACE_DELAY},
+    proxy::ProxyTransport,
+    quota::{StateFile, STATE_SAVE_INTERVAL},
+    rendezvous::{self, REGISTER_INTERVAL},
+    resolver::{Resolver, SystemResolver},
+    runtime::{Context, RouteTarget, Runtime},
+    shaper::TokenBucket,
+    socks5::Socks5,
+    stun::{StunClient, StunStatus},
+    tcp::TcpTransport,
+    transport::{self, Transport},
+    tun::{TunBatch, TunDevice, TunReader},
+    udp::{OuterSocket, RecvBatch, SendBatch},
+    worker::{default_workers, Job, WorkerPool},
+};
 
-pub(crate) struct WireGuard {
+const BUFFER_POOL_SIZE: usize = 1024;
+/// Seconds between lookups of peer endpoint hostnames.
+const DEFAULT_RESOLVE_INTERVAL: u64 = 120;
+/// Look a peer endpoint up again early after this many unanswered handshakes.
+const RESOLVE_AFTER_FAILED_HANDSHAKES: u32 = 3;
+/// Pause after a failed receive, so a broken socket does not spin.
+const RECV_ERROR_DELAY: Duration = Duration::from_millis(100);
+/// Route changes arriving within this window are handled together.
+const NETWORK_CHANGE_SETTLE: Duration = Duration::from_secs(1);
+/// Peer events a slow subscriber may fall behind by before missing some.
+const PEER_EVENT_CAPACITY: usize = 256;
+
+pub struct WireGuard {
     pub interface: Option<Interface>,
     pub peers: Option<Vec<Peer>>,
-    route_stack: Vec<Route>,
+    pub socks5: Option<Socks5>,
+    pub forwards: Vec<Forward>,
+    transport: Option<Arc<dyn Transport>>,
+    device: Option<Arc<dyn Device>>,
+    workers: usize,
+    resolver: Arc<dyn Resolver>,
+    handshake_rate_limit: u64,
+    handshake_stats: Arc<HandshakeStats>,
+    stun_status: Arc<StunStatus>,
+    control: Arc<Control>,
+    peer_events: broadcast::Sender<PeerEvent>,
 }
 
 impl WireGuard {
     pub fn new() -> Result<Self> {
+        let handshake_stats = Arc::new(HandshakeStats::default());
+        let stun_status = Arc::new(StunStatus::default());
         let wg = Self {
             interface: None,
             peers: Some(vec![]),
-            route_stack: vec![],
+            socks5: None,
+            forwards: vec![],
+            transport: None,
+            device: None,
+            workers: default_workers(),
+            resolver: Arc::new(SystemResolver),
+            handshake_rate_limit: HANDSHAKE_RATE_LIMIT,
+            handshake_stats: handshake_stats.clone(),
+            stun_status: stun_status.clone(),
+            control: Arc::new(Control::new(handshake_stats, stun_status)),
+            peer_events: broadcast::channel(PEER_EVENT_CAPACITY).0,
         };
         Ok(wg)
     }
@@ -56,6 +117,23 @@ impl WireGuard {
                         current_peer = Some(Peer::new()?);
                         current_section = Section::Peer;
                     }
+                    Section::Socks5 => {
+                        if Section::None == current_section {
+                            Err(anyhow!("Setup [Interface] before [Socks5]"))?
+                        }
+                        if wg.socks5.is_some() {
+                            Err(anyhow!("Duplicated [Socks5]"))?
+                        }
+                        wg.set_socks5(Socks5::new()?)?;
+                        current_section = Section::Socks5;
+                    }
+                    Section::Forward(kind) => {
+                        if Section::None == current_section {
+                            Err(anyhow!("Setup [Interface] before port forwarding"))?
+                        }
+                        wg.add_forward(Forward::new(kind)?)?;
+                        current_section = Section::Forward(kind);
+                    }
                     _ => Err(anyhow!("Unexpected session"))?,
                 }
                 continue;
@@ -66,12 +144,12 @@ impl WireGuard {
                     Section::Interface => match key.as_str() {
                         "PrivateKey" => {
                             if let Some(private_key) = values.first() {
-                                interface.set_private_key(&private_key)?;
+                                interface.set_private_key(private_key)?;
                             }
                         }
                         "Address" => {
                             if let Some(address) = values.first() {
-                                interface.set_address(&address)?;
+                                interface.set_address(address)?;
                             }
                         }
                         "ListenPort" => {
@@ -79,44 +157,123 @@ impl WireGuard {
                                 interface.set_listen_port(listen_port.parse::<u16>()?)?;
                             }
                         }
+                        "ListenAddress" => {
+                            if let Some(listen_address) = values.first() {
+                                interface.set_listen_address(listen_address)?;
+                            }
+                        }
+                        "BindInterface" => {
+                            if let Some(bind_interface) = values.first() {
+                                interface.set_bind_interface(bind_interface)?;
+                            }
+                        }
                         "DNS" => {
                             let dns: Vec<&str> = values.iter().map(|i| i.as_str()).collect();
                             interface.set_dns(&dns)?;
                         }
+                        "Offload" => {
+                            if let Some(offload) = values.first() {
+                                interface.set_offload(offload.parse::<bool>()?)?;
+                            }
+                        }
+                        "ResolveInterval" => {
+                            if let Some(resolve_interval) = values.first() {
+                                interface.set_resolve_interval(resolve_interval.parse::<u64>()?)?;
+                            }
+                        }
+                        "TcpListenPort" => {
+                            if let Some(tcp_listen_port) = values.first() {
+                                interface.set_tcp_listen_port(tcp_listen_port.parse::<u16>()?)?;
+                            }
+                        }
+                        "Proxy" => {
+                            if let Some(proxy) = values.first() {
+                                interface.set_proxy(proxy)?;
+                            }
+                        }
+                        "Relay" => {
+                            if let Some(relay) = values.first() {
+                                interface.set_relay(relay)?;
+                            }
+                        }
+                        "Rendezvous" => {
+                            if let Some(rendezvous) = values.first() {
+                                interface.set_rendezvous(rendezvous)?;
+                            }
+                        }
+                        "StunServer" => {
+                            let stun_servers: Vec<&str> =
+                                values.iter().map(|i| i.as_str()).collect();
+                            interface.set_stun_servers(&stun_servers)?;
+                        }
+                        "SendRate" => {
+                            if let Some(send_rate) = values.first() {
+                                interface.set_send_rate(send_rate)?;
+                            }
+                        }
+                        "ReceiveRate" => {
+                            if let Some(receive_rate) = values.first() {
+                                interface.set_receive_rate(receive_rate)?;
+                            }
+                        }
+                        "StateFile" => {
+                            if let Some(state_file) = values.first() {
+                                interface.set_state_file(state_file)?;
+                            }
+                        }
+                        "Jc" | "Jmin" | "Jmax" | "S1" | "S2" | "H1" | "H2" | "H3" | "H4" => {
+                            if let Some(value) = values.first() {
+                                interface.set_obfuscation(&key, value)?;
+                            }
+                        }
                         other => {
                             Err(anyhow!("Unexpected Interface Key: {other}"))?;
                         }
                     },
-                    Section::Peer => match key.as_str() {
-                        "PublicKey" => {
-                            if let Some(public_key) = values.first() {
-                                current_peer.as_mut().unwrap().set_public_key(public_key)?;
+                    Section::Peer => set_peer_key(current_peer.as_mut().unwrap(), &key, &values)?,
+                    Section::Socks5 => match key.as_str() {
+                        "BindAddress" => {
+                            if let Some(bind_address) = values.first() {
+                                wg.socks5.as_mut().unwrap().set_bind_address(bind_address)?;
                             }
                         }
-                        "AllowedIPs" => {
-                            let allowed_ips: Vec<&str> =
-                                values.iter().map(|i| i.as_str()).collect();
-                            current_peer
-                                .as_mut()
-                                .unwrap()
-                                .set_allowed_ips(&allowed_ips)?;
-                        }
-                        "Endpoint" => {
-                            if let Some(endpoint) = values.first() {
-                                current_peer.as_mut().unwrap().set_endpoint(endpoint)?;
+                        "Username" => {
+                            if let Some(username) = values.first() {
+                                wg.socks5.as_mut().unwrap().set_username(username)?;
                             }
                         }
-                        "PersistentKeepalive" => {
-                            if let Some(persistent_keepalive) = values.first() {
-                                current_peer.as_mut().unwrap().set_persistent_keepalive(
-                                    persistent_keepalive.parse::<u16>()?,
-                                )?;
+                        "Password" => {
+                            if let Some(password) = values.first() {
+                                wg.socks5.as_mut().unwrap().set_password(password)?;
                             }
                         }
                         other => {
-                            Err(anyhow!("Unexpected Peer Key: {other}"))?;
+                            Err(anyhow!("Unexpected Socks5 Key: {other}"))?;
                         }
                     },
+                    Section::Forward(_) => {
+                        let forward = wg.forwards.last_mut().unwrap();
+                        match key.as_str() {
+                            "BindAddress" => {
+                                if let Some(bind_address) = values.first() {
+                                    forward.set_bind_address(bind_address)?;
+                                }
+                            }
+                            "ListenPort" => {
+                                if let Some(listen_port) = values.first() {
+                                    forward.set_listen_port(listen_port)?;
+                                }
+                            }
+                            "Target" => {
+                                if let Some(target) = values.first() {
+                                    forward.set_target(target)?;
+                                }
+                            }
+                            other => {
+                                Err(anyhow!("Unexpected {forward} Key: {other}"))?;
+                            }
+                        }
+                    }
                     _ => Err(anyhow!("Unexpected session"))?,
                 }
             }
@@ -132,6 +289,10 @@ impl WireGuard {
     }
 
     pub fn set_interface(&mut self, interface: Interface) -> Result<()> {
+        interface
+            .obfuscation
+            .validate()
+            .map_err(|e| anyhow!("Invalid obfuscation settings: {e}"))?;
         self.interface = Some(interface);
         Ok(())
     }
@@ -148,162 +309,744 @@ impl WireGuard {
         Ok(())
     }
 
+    pub fn set_socks5(&mut self, socks5: Socks5) -> Result<()> {
+        self.socks5 = Some(socks5);
+        Ok(())
+    }
+
+    pub fn add_forward(&mut self, forward: Forward) -> Result<()> {
+        self.forwards.push(forward);
+        Ok(())
+    }
+
+    /// Carry the encrypted traffic over `transport` instead of binding the
+    /// outer UDP socket.
+    pub fn set_transport(&mut self, transport: Arc<dyn Transport>) -> Result<()> {
+        self.transport = Some(transport);
+        Ok(())
+    }
+
+    /// Use `device` as the inside of the tunnel instead of creating a tun
+    /// device. No routes are added for it.
+    pub fn set_device(&mut self, device: Arc<dyn Device>) -> Result<()> {
+        self.device = Some(device);
+        Ok(())
+    }
+
+    /// Handshakes per second, across all peers, before cookies are required.
+    pub fn set_handshake_rate_limit(&mut self, limit: u64) -> Result<()> {
+        self.handshake_rate_limit = limit;
+        Ok(())
+    }
+
+    /// Counters of cookie replies and rejected handshakes.
+    pub fn handshake_stats(&self) -> Arc<HandshakeStats> {
+        self.handshake_stats.clone()
+    }
+
+    /// The public address and NAT type the STUN servers last reported.
+    pub fn stun_status(&self) -> Arc<StunStatus> {
+        self.stun_status.clone()
+    }
+
+    /// Query and steer the instance while it runs, see [`crate::control`].
+    pub fn control(&self) -> Arc<Control> {
+        self.control.clone()
+    }
+
+    /// Every peer state change from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.peer_events.subscribe()
+    }
+
+    pub fn set_workers(&mut self, workers: usize) -> Result<()> {
+        if workers == 0 {
+            return Err(anyhow!("Workers must be greater than 0"));
+        }
+        self.workers = workers;
+        Ok(())
+    }
+
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolver>) -> Result<()> {
+        self.resolver = resolver;
+        Ok(())
+    }
+
     pub async fn run(&mut self) -> Result<()> {
         let interface = self
             .interface
             .as_ref()
             .ok_or(anyhow!("Missing interface"))?;
 
-        let mut route_manager =
+        let route_manager =
             RouteManager::new().map_err(|e| anyhow!("Create route manager failed: {e}"))?;
 
-        let udp_socket = {
-            let bind_addr = {
-                let ip = "0.0.0.0"
-                    .parse()
-                    .map_err(|e| anyhow!("Parse 0.0.0.0 failed: {e}"))?;
-
-                let if_index = route_manager
-                    .find_route(&ip)
-                    .map_err(|e| anyhow!("Find default route failed: {e}"))?
-                    .ok_or(anyhow!("Default route not found"))?
-                    .if_index()
-                    .ok_or(anyhow!("Get bind ip failed"))?;
-
-                let addr = if_index_to_addr(if_index)?;
-                addr
-            };
-            Arc::new(
-                UdpSocket::bind(SocketAddr::from((
-                    bind_addr,
+        let transport: Arc<dyn Transport> = match &self.transport {
+            Some(transport) => transport.clone(),
+            None => Arc::new(
+                OuterSocket::listen(
+                    interface.listen_address,
                     interface.listen_port.unwrap_or(51820),
-                )))
-                .await
+                    interface.bind_interface.clone(),
+                )
                 .map_err(|e| anyhow!("UdpSocket bind failed: {e}"))?,
-            )
+            ),
+        };
+        // Only datagrams go through the proxy, TCP peers are connected directly
+        let transport: Arc<dyn Transport> = match &interface.proxy {
+            Some(proxy) => Arc::new(
+                ProxyTransport::connect(transport, proxy.clone())
+                    .await
+                    .map_err(|e| anyhow!("Proxy failed: {e}"))?,
+            ),
+            None => transport,
+        };
+        let tcp = if interface.tcp_listen_port.is_some()
+            || self.peers.iter().flatten().any(|peer| peer.over_tcp)
+        {
+            let tcp = Arc::new(TcpTransport::new(transport.clone()));
+            if let Some(port) = interface.tcp_listen_port {
+                let address = interface
+                    .listen_address
+                    .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+                let local_addr = tcp
+                    .listen(SocketAddr::new(address, port))
+                    .await
+                    .map_err(|e| anyhow!("TCP listen failed: {e}"))?;
+                println!("Listening for peers over TCP on {local_addr}");
+            }
+            Some(tcp)
+        } else {
+            None
+        };
+        let transport: Arc<dyn Transport> = match &tcp {
+            Some(tcp) => tcp.clone(),
+            None => transport,
+        };
+        // Wraps any transport, both sides of a tunnel need the same settings
+        let transport: Arc<dyn Transport> = if interface.obfuscation.is_enabled() {
+            Arc::new(ObfuscatedTransport::new(
+                transport,
+                interface.obfuscation.clone(),
+            )?)
+        } else {
+            transport
         };
 
         let (interface_address, interface_mask) = interface
             .address
             .ok_or(anyhow!("Interface missing address"))?;
 
-        let tun_dev = Arc::new({
-            tun_rs::DeviceBuilder::new()
-                .ipv4(interface_address, interface_mask, None)
-                .mtu(1500)
-                .build_async()
-                .map_err(|e| anyhow!("Create tun device failed: {}", e))?
-        });
-
-        let endpoint_peer_map = DashMap::new();
-        let mut allowed_ips_peer_map = vec![];
-        let mut routine_peers = vec![];
-
-        for mut peer in self.peers.take().unwrap() {
-            peer.set_send_socket(udp_socket.clone())?;
-            peer.set_send_tun(tun_dev.clone())?;
-
-            let tunn = Tunn::new(
-                interface
-                    .private_key
-                    .clone()
-                    .ok_or(anyhow!("Missing interface private key"))?,
-                peer.public_key.ok_or(anyhow!("Missing peer public key"))?,
-                None,
-                peer.persistent_keepalive,
-                rand::rng().next_u32(),
-                None,
-            )
-            .map_err(|e| anyhow!("Create tunn failed: {e}"))?;
-            peer.set_tunn(tunn)?;
-
-            let allowed_ips = peer.allowed_ips.take().unwrap();
-
-            let peer = Arc::new(peer);
-            if let Some(endpoint) = peer.endpoint {
-                endpoint_peer_map.insert(endpoint, peer.clone());
-            }
-            let if_index = tun_dev
-                .if_index()
-                .map_err(|e| anyhow!("Get tun dev interface index failed: {e}"))?;
-            let name = tun_dev
-                .name()
-                .map_err(|e| anyhow!("Get tun dev interface name failed: {e}"))?;
-
-            for allowed_ip in allowed_ips {
-                let (destination, prefix) = allowed_ip;
-                let route = Route::new(destination, prefix)
-                    .with_if_index(if_index)
-                    .with_if_name(name.clone())
-                    .with_gateway(interface_address);
-                route_manager
-                    .add(&route)
-                    .map_err(|e| anyhow!("Add route failed: {route}: {e}"))?;
-                self.route_stack.push(route);
-                allowed_ips_peer_map.push((allowed_ip, peer.clone()));
+        // Without a tun device the userspace stack carries the traffic, which
+        // needs no privileges
+        let netstack = if self.socks5.is_some() || !self.forwards.is_empty() {
+            Some(Arc::new(Netstack::new(
+                interface_address,
+                interface_mask,
+                interface.dns.as_deref().unwrap_or_default(),
+            )?))
+        } else {
+            None
+        };
+        let device: Arc<dyn Device> = match (&self.device, &netstack) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "A custom device can't be used with [Socks5] or port forwarding"
+                ))
             }
-            routine_peers.push(peer.clone());
+            (Some(device), None) => device.clone(),
+            (None, Some(netstack)) => netstack.clone(),
+            (None, None) => Arc::new(TunDevice::new(
+                interface_address,
+                interface_mask,
+                interface.offload,
+            )?),
+        };
+        if interface.offload && device.if_index().is_none() {
+            return Err(anyhow!("Offload requires a tun device"));
         }
+        let if_index = device.if_index();
+
+        let private_key = interface
+            .private_key
+            .clone()
+            .ok_or(anyhow!("Missing interface private key"))?;
+        let limiter = Arc::new(HandshakeLimiter::new(
+            &private_key,
+            self.handshake_rate_limit,
+            self.handshake_stats.clone(),
+        ));
 
-        let mut tasks = vec![];
+        // Woken when a shaper queues packets
+        let shaped = Arc::new(Notify::new());
+        let runtime = Arc::new(Runtime::new(
+            Context {
+                private_key: private_key.clone(),
+                rate_limiter: limiter.session_limiter(),
+                transport: transport.clone(),
+                device: device.clone(),
+                tcp: tcp.clone(),
+                relay: interface.relay,
+                resolver: self.resolver.clone(),
+                handshake_stats: self.handshake_stats.clone(),
+                events: self.peer_events.clone(),
+                route_target: if_index.map(|if_index| RouteTarget {
+                    if_index,
+                    if_name: device.name(),
+                    gateway: interface_address,
+                }),
+                state_file: StateFile::load(interface.state_file.clone())?,
+                send_bucket: interface
+                    .send_rate
+                    .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate, Instant::now())))),
+                receive_bucket: interface
+                    .receive_rate
+                    .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate, Instant::now())))),
+                shaped: shaped.clone(),
+            },
+            route_manager,
+        ));
+        runtime
+            .change(&[], self.peers.take().unwrap_or_default())
+            .await?;
+        self.control.start(runtime.clone());
+
+        let (worker_pool, mut tasks) = WorkerPool::new(
+            self.workers,
+            transport.clone(),
+            device.clone(),
+            runtime.endpoints.clone(),
+        )?;
+        let worker_pool = Arc::new(worker_pool);
+        let buffer_pool = BufferPool::new(BUFFER_POOL_SIZE);
 
         tasks.push(tokio::spawn(async {
             let _ = tokio::signal::ctrl_c().await;
         }));
 
-        let recv_socket = udp_socket.clone();
+        let mut events = self.peer_events.subscribe();
+        let record_control = self.control.clone();
+        tasks.push(tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => record_control.record(&event),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }));
+
+        let resolve_interval = Duration::from_secs(
+            interface
+                .resolve_interval
+                .unwrap_or(DEFAULT_RESOLVE_INTERVAL),
+        );
+        let resolve_runtime = runtime.clone();
+        tasks.push(tokio::spawn(async move {
+            // Peers were resolved when they were added
+            let mut resolved_at = HashMap::new();
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let peers = resolve_runtime.peers();
+                resolved_at.retain(|public_key, _| peers.get(public_key).is_some());
+                for peer in peers.peers() {
+                    let (Some(public_key), false) =
+                        (peer.public_key, peer.endpoint_hosts().is_empty())
+                    else {
+                        continue;
+                    };
+                    let resolved_at = resolved_at
+                        .entry(public_key.to_bytes())
+                        .or_insert_with(tokio::time::Instant::now);
+                    if resolved_at.elapsed() < resolve_interval
+                        && peer.failed_handshakes() < RESOLVE_AFTER_FAILED_HANDSHAKES
+                    {
+                        continue;
+                    }
+                    *resolved_at = tokio::time::Instant::now();
+                    peer.reset_failed_handshakes();
+                    match resolve_runtime.refresh_endpoint(peer).await {
+                        Ok(true) => {
+                            println!(
+                                "Peer endpoints {} changed to {:?}",
+                                peer.endpoint_hosts().join(", "),
+                                peer.endpoints()
+                            );
+                            peer.reset_backoff();
+                        }
+                        Ok(false) => {}
+                        Err(e) => println!("Resolve peer endpoint failed: {e}"),
+                    }
+                }
+            }
+        }));
+
+        // Looked up again before every registration
+        let rendezvous_server = Arc::new(RwLock::new(None));
+        if let Some(server) = interface.rendezvous.clone() {
+            let public_key = PublicKey::from(&private_key);
+            let register_runtime = runtime.clone();
+            let resolver = self.resolver.clone();
+            let register_server = rendezvous_server.clone();
+            let register_transport = transport.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut batch = SendBatch::new();
+                loop {
+                    let lookup_resolver = resolver.clone();
+                    let lookup_server = server.clone();
+                    let lookup = tokio::task::spawn_blocking(move || {
+                        lookup_resolver.resolve(&lookup_server)
+                    })
+                    .await;
+                    match lookup {
+                        Ok(Ok(addrs)) if !addrs.is_empty() => {
+                            *register_server.write().unwrap_or_else(|e| e.into_inner()) =
+                                Some(addrs[0]);
+                            let wanted = register_runtime
+                                .peers()
+                                .peers()
+                                .filter_map(|peer| peer.public_key)
+                                .collect::<Vec<_>>();
+                            for message in rendezvous::register(&public_key, &wanted) {
+                                if batch.is_full() {
+                                    send_registration(&register_transport, &mut batch).await;
+                                }
+                                batch.slot()[..message.len()].copy_from_slice(&message);
+                                batch.commit(message.len(), addrs[0], None);
+                            }
+                            send_registration(&register_transport, &mut batch).await;
+                        }
+                        Ok(Err(e)) => println!("Resolve rendezvous server {server} failed: {e}"),
+                        _ => println!("Resolve rendezvous server {server} failed"),
+                    }
+                    tokio::time::sleep(REGISTER_INTERVAL).await;
+                }
+            }));
+        }
+
+        let stun = (!interface.stun_servers.is_empty()).then(|| {
+            Arc::new(StunClient::new(
+                interface.stun_servers.clone(),
+                self.stun_status.clone(),
+            ))
+        });
+        if let Some(stun) = stun.clone() {
+            let resolver = self.resolver.clone();
+            let stun_transport = transport.clone();
+            tasks.push(tokio::spawn(async move {
+                stun.run(stun_transport, resolver).await
+            }));
+        }
+
+        let recv_runtime = runtime.clone();
+        let recv_transport = transport.clone();
+        let socket_worker_pool = worker_pool.clone();
+        let socket_buffer_pool = buffer_pool.clone();
+        let recv_limiter = limiter.clone();
         tasks.push(tokio::spawn(async move {
-            let mut buf = vec![0; 2048];
-            while let Ok((len, endpoint)) = recv_socket.recv_from(&mut buf).await {
-                if let Some(peer) = endpoint_peer_map.get(&endpoint) {
-                    if let Err(e) = peer.handle_socket_packet(&mut buf[..len]).await {
-                        println!("Handle socket packet failed: {e}")
+            let mut batch = RecvBatch::new(socket_buffer_pool);
+            let mut replies = SendBatch::new();
+            // Addresses punched towards, whose handshake responses go to the
+            // peer without mapping them first
+            let mut punched: HashMap<SocketAddr, Arc<Peer>> = HashMap::new();
+            'recv: loop {
+                if let Err(e) = recv_transport.recv_batch(&mut batch).await {
+                    println!("Receive from network failed: {e}");
+                    tokio::time::sleep(RECV_ERROR_DELAY).await;
+                    continue;
+                }
+                let peers = recv_runtime.peers();
+                for (buf, endpoint, local) in batch.drain() {
+                    let from_rendezvous =
+                        *rendezvous_server.read().unwrap_or_else(|e| e.into_inner())
+                            == Some(endpoint);
+                    if from_rendezvous && rendezvous::is_message(&buf) {
+                        let Some((public_key, address)) = rendezvous::parse_endpoint(&buf) else {
+                            continue;
+                        };
+                        // Only the source address vouches for the message, the
+                        // endpoint is mapped once the peer's handshake arrives
+                        if let Some(peer) = peers.get(&public_key.to_bytes()) {
+                            punched.retain(|_, punched| !Arc::ptr_eq(punched, peer));
+                            punched.insert(address, peer.clone());
+                            if let Err(e) = peer.punch(address, &mut replies).await {
+                                println!("Punch through to {address} failed: {e}");
+                            }
+                        }
+                        continue;
+                    }
+                    if stun
+                        .as_ref()
+                        .is_some_and(|stun| stun.handle(&buf, endpoint))
+                    {
+                        continue;
+                    }
+                    // A peer removed meanwhile may still be mapped
+                    let mapped = recv_runtime
+                        .endpoints
+                        .get(&endpoint)
+                        .map(|peer| peer.clone())
+                        .filter(|peer| !peer.is_removed());
+                    if replies.is_full() {
+                        send_replies(&recv_transport, &mut replies).await;
+                    }
+                    // Handshakes are counted here, not again by the sessions
+                    let job = match mapped {
+                        Some(peer) if recv_limiter.check(&buf, endpoint, local, &mut replies) => {
+                            Job::Socket(peer, buf, endpoint, local)
+                        }
+                        Some(_) => continue,
+                        None if buf.first() == Some(&HANDSHAKE_RESPONSE)
+                            && punched.contains_key(&endpoint) =>
+                        {
+                            let peer = punched.remove(&endpoint).filter(|peer| !peer.is_removed());
+                            match peer {
+                                Some(peer)
+                                    if recv_limiter.check(&buf, endpoint, local, &mut replies) =>
+                                {
+                                    Job::Handshake(peer, buf, endpoint, local)
+                                }
+                                _ => continue,
+                            }
+                        }
+                        None => {
+                            // A peer may initiate from anywhere, its session
+                            // decides whether the endpoint is really its own
+                            let found = recv_limiter.handle_unknown(
+                                &buf,
+                                endpoint,
+                                local,
+                                &mut replies,
+                                |public_key| peers.get(&public_key.to_bytes()).cloned(),
+                            );
+                            match found {
+                                Some(peer) => Job::Handshake(peer, buf, endpoint, local),
+                                None => continue,
+                            }
+                        }
+                    };
+                    if let Err(e) = socket_worker_pool.dispatch(job).await {
+                        println!("Dispatch socket packet failed: {e}");
+                        break 'recv;
                     }
                 }
+                if !replies.is_empty() {
+                    send_replies(&recv_transport, &mut replies).await;
+                }
             }
         }));
 
-        let recv_tun = tun_dev.clone();
+        match AsyncRouteManager::listener() {
+            Ok(listener) => {
+                tasks.push(tokio::spawn(watch_network(
+                    listener,
+                    if_index,
+                    transport.clone(),
+                    runtime.clone(),
+                )));
+            }
+            Err(e) => println!("Watch network changes failed: {e}"),
+        }
+
+        let route_runtime = runtime.clone();
+        let recv_device = device.clone();
+        let device_worker_pool = worker_pool.clone();
+        let mut reader = TunReader::new(buffer_pool.clone(), interface.offload);
         tasks.push(tokio::spawn(async move {
-            let mut buf = vec![0; 2048];
-            while let Ok(len) = recv_tun.recv(&mut buf).await {
-                // TODO: get peer by route, now we just fetch the first peer
-                if let Some(peer) = allowed_ips_peer_map.first() {
-                    let (_, peer) = &peer;
-                    if let Err(e) = peer.handle_tun_packet(&mut buf[..len]).await {
-                        println!("Handle tun packet failed: {e}")
+            'recv: loop {
+                if let Err(e) = recv_device.recv(&mut reader).await {
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        println!("Read tun packet failed: {e}");
+                        continue;
+                    }
+                    break;
+                }
+                let peers = route_runtime.peers();
+                for buf in reader.drain() {
+                    let peer = match peers.route(&buf) {
+                        Some(peer) => peer,
+                        None => continue,
+                    };
+                    let job = Job::Tun(peer, buf);
+                    if let Err(e) = device_worker_pool.dispatch(job).await {
+                        println!("Dispatch tun packet failed: {e}");
+                        break 'recv;
                     }
                 }
             }
         }));
 
+        if let Some(netstack) = &netstack {
+            let run_netstack = netstack.clone();
+            tasks.push(tokio::spawn(async move { run_netstack.run().await }));
+
+            if let Some(socks5) = self.socks5.take() {
+                let listener = socks5.bind().await?;
+                println!(
+                    "Socks5 listening on {}",
+                    listener
+                        .local_addr()
+                        .map_err(|e| anyhow!("Get socks5 address failed: {e}"))?
+                );
+                tasks.push(tokio::spawn(
+                    Arc::new(socks5).serve(listener, netstack.clone()),
+                ));
+            }
+
+            for forward in self.forwards.drain(..) {
+                let listener = forward.bind(netstack).await?;
+                if let Some((host, port)) = &forward.target {
+                    println!("{forward} forwarding to {host}:{port}");
+                }
+                tasks.push(tokio::spawn(
+                    Arc::new(forward).serve(listener, netstack.clone()),
+                ));
+            }
+        }
+
+        let routine_runtime = runtime.clone();
+        let routine_transport = transport.clone();
         tasks.push(tokio::spawn(async move {
+            let mut batch = SendBatch::new();
             loop {
                 tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                for peer in &routine_peers {
-                    if let Err(e) = peer.handle_routine_task().await {
+                limiter.reset();
+                for peer in routine_runtime.peers().peers() {
+                    if let Err(e) = peer.handle_routine_task(&mut batch).await {
                         println!("Handle routine task failed: {e}")
                     }
                 }
+                if let Err(e) = routine_transport.send_batch(&mut batch).await {
+                    println!("Send routine packets failed: {e}")
+                }
             }
         }));
 
-        let _ = futures::future::select_all(tasks).await;
+        let race_runtime = runtime.clone();
+        let race_transport = transport.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut batch = SendBatch::new();
+            loop {
+                tokio::time::sleep(ENDPOINT_RACE_DELAY).await;
+                for peer in race_runtime.peers().peers() {
+                    if let Err(e) = peer.race_endpoints(&mut batch).await {
+                        println!("Race endpoints failed: {e}")
+                    }
+                }
+                if !batch.is_empty() {
+                    if let Err(e) = race_transport.send_batch(&mut batch).await {
+                        println!("Send raced initiations failed: {e}")
+                    }
+                }
+            }
+        }));
+
+        let shaper_runtime = runtime.clone();
+        let shaper_transport = transport.clone();
+        let shaper_device = device.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut wait = None;
+            let mut batches = None;
+            loop {
+                match wait {
+                    Some(wait) => {
+                        let _ = tokio::time::timeout(wait, shaped.notified()).await;
+                    }
+                    None => shaped.notified().await,
+                }
+                // Only allocated once shaping is used
+                let (batch, tun_batch) =
+                    batches.get_or_insert_with(|| (SendBatch::new(), TunBatch::new()));
+                wait = None;
+                for peer in shaper_runtime.peers().peers() {
+                    match peer.drain_shaped(batch, tun_batch).await {
+                        Ok(Some(until)) => {
+                            wait = Some(wait.map_or(until, |wait: Duration| wait.min(until)))
+                        }
+                        Ok(None) => {}
+                        Err(e) => println!("Send shaped packets failed: {e}"),
+                    }
+                }
+                if let Err(e) = shaper_transport.send_batch(batch).await {
+                    println!("Send to network failed: {e}")
+                }
+                if let Err(e) = shaper_device.send(tun_batch).await {
+                    println!("Send to tun dev failed: {e}")
+                }
+            }
+        }));
 
-        while let Some(route) = self.route_stack.pop() {
-            if let Err(e) = route_manager.delete(&route) {
-                println!("Delete route failed: {e}");
+        let save_runtime = runtime.clone();
+        tasks.push(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(STATE_SAVE_INTERVAL).await;
+                if let Err(e) = save_runtime.save_usage() {
+                    println!("{e}");
+                }
             }
+        }));
+
+        let _ = futures::future::select_all(tasks).await;
+        self.control.stop();
+        runtime.delete_all_routes();
+        if let Err(e) = runtime.save_usage() {
+            println!("{e}");
         }
 
         Ok(())
     }
 }
 
+/// Send what the receive loop queued, cookie replies to endpoints that
+/// belong to no peer and initiations punching through NATs.
+async fn send_replies(transport: &Arc<dyn Transport>, replies: &mut SendBatch) {
+    if let Err(e) = transport::send(&**transport, replies).await {
+        println!("Send replies failed: {e}");
+        replies.clear();
+    }
+}
+
+/// Send the registration messages queued for the rendezvous server.
+async fn send_registration(transport: &Arc<dyn Transport>, batch: &mut SendBatch) {
+    if let Err(e) = transport.send_batch(batch).await {
+        println!("Register with rendezvous server failed: {e}");
+        batch.clear();
+    }
+}
+
+/// Rebind the outer socket whenever routes outside the tun device change,
+/// e.g. when switching between Wi-Fi and Ethernet, then handshake again with
+/// every peer. The tun device and sessions are kept.
+async fn watch_network(
+    mut listener: AsyncRouteListener,
+    tun_index: Option<u32>,
+    transport: Arc<dyn Transport>,
+    runtime: Arc<Runtime>,
+) {
+    let mut batch = SendBatch::new();
+    loop {
+        match listener.listen().await {
+            Ok(change) if tun_index.is_none() || route_of(&change).if_index() != tun_index => {}
+            Ok(_) => continue,
+            Err(e) => {
+                println!("Receive route change failed: {e}");
+                tokio::time::sleep(NETWORK_CHANGE_SETTLE).await;
+                continue;
+            }
+        }
+        // Wait for the burst of changes to settle
+        while let Ok(Ok(_)) = tokio::time::timeout(NETWORK_CHANGE_SETTLE, listener.listen()).await {
+        }
+
+        println!("Network changed, rebinding socket");
+        while let Err(e) = transport.rebind().await {
+            println!("Rebind socket failed: {e}");
+            tokio::time::sleep(NETWORK_CHANGE_SETTLE).await;
+        }
+        for peer in runtime.peers().peers() {
+            if let Err(e) = peer.rehandshake(&mut batch).await {
+                println!("Handshake after network change failed: {e}")
+            }
+        }
+        if let Err(e) = transport.send_batch(&mut batch).await {
+            println!("Send handshake packets failed: {e}")
+        }
+    }
+}
+
+fn route_of(change: &RouteChange) -> &Route {
+    match change {
+        RouteChange::Add(route) | RouteChange::Delete(route) | RouteChange::Change(route) => route,
+    }
+}
+
+/// Apply `key` of a `[Peer]` section.
+fn set_peer_key(peer: &mut Peer, key: &str, values: &[String]) -> Result<()> {
+    match key {
+        "PublicKey" => {
+            if let Some(public_key) = values.first() {
+                peer.set_public_key(public_key)?;
+            }
+        }
+        "AllowedIPs" => {
+            let allowed_ips: Vec<&str> = values.iter().map(|i| i.as_str()).collect();
+            peer.set_allowed_ips(&allowed_ips)?;
+        }
+        "Endpoint" => {
+            for endpoint in values {
+                peer.add_endpoint(endpoint)?;
+            }
+        }
+        "HandshakeAttempts" => {
+            if let Some(handshake_attempts) = values.first() {
+                peer.set_handshake_attempts(handshake_attempts.parse::<u32>()?)?;
+            }
+        }
+        "Transport" => {
+            if let Some(transport) = values.first() {
+                peer.set_transport(transport)?;
+            }
+        }
+        "RelayGroups" => {
+            let relay_groups: Vec<&str> = values.iter().map(|i| i.as_str()).collect();
+            peer.set_relay_groups(&relay_groups)?;
+        }
+        "PersistentKeepalive" => {
+            if let Some(persistent_keepalive) = values.first() {
+                peer.set_persistent_keepalive(persistent_keepalive.parse::<u16>()?)?;
+            }
+        }
+        "DataLimit" => {
+            if let Some(data_limit) = values.first() {
+                peer.set_data_limit(data_limit)?;
+            }
+        }
+        "SendRate" => {
+            if let Some(send_rate) = values.first() {
+                peer.set_send_rate(send_rate)?;
+            }
+        }
+        "ReceiveRate" => {
+            if let Some(receive_rate) = values.first() {
+                peer.set_receive_rate(receive_rate)?;
+            }
+        }
+        "ExpiresAt" => {
+            if let Some(expires_at) = values.first() {
+                peer.set_expires_at(expires_at)?;
+            }
+        }
+        other => {
+            Err(anyhow!("Unexpected Peer Key: {other}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// A peer from the keys of a `[Peer]` section, with or without the header.
+pub fn parse_peer(content: &str) -> Result<Peer> {
+    let mut peer = Peer::new()?;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || parse_section_header(line) == Some(Section::Peer)
+        {
+            continue;
+        }
+        let (key, values) =
+            parse_key_value(line).ok_or_else(|| anyhow!("Expected key = value: {line}"))?;
+        set_peer_key(&mut peer, &key, &values)?;
+    }
+    Ok(peer)
+}
+
 #[derive(Debug, PartialEq)]
 enum Section {
     Interface,
     Peer,
+    Socks5,
+    Forward(ForwardKind),
     None,
 }
 
@@ -313,6 +1056,10 @@ fn parse_section_header(line: &str) -> Option<Section> {
         match section.as_str() {
             "interface" => Some(Section::Interface),
             "peer" => Some(Section::Peer),
+            "socks5" => Some(Section::Socks5),
+            "tcpclienttunnel" => Some(Section::Forward(ForwardKind::TcpClient)),
+            "tcpservertunnel" => Some(Section::Forward(ForwardKind::TcpServer)),
+            "udpservertunnel" => Some(Section::Forward(ForwardKind::UdpServer)),
             _ => Some(Section::None),
         }
     } else {
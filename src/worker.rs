@@ -0,0 +1,151 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+    buffer::PacketBuffer,
+    device::Device,
+    peer::Peer,
+    transport::{self, Transport},
+    tun::TunBatch,
+    udp::{SendBatch, BATCH_SIZE},
+};
+
+const WORKER_QUEUE_DEPTH: usize = 1024;
+
+/// Endpoints packets are known to come from, with their peer.
+pub type Endpoints = DashMap<SocketAddr, Arc<Peer>>;
+
+/// A packet for a worker, with the peer it belongs to.
+pub enum Job {
+    Socket(Arc<Peer>, PacketBuffer, SocketAddr, Option<IpAddr>),
+    /// A handshake from an endpoint that belongs to no peer yet. The
+    /// endpoint is mapped to the peer once its session accepts it.
+    Handshake(Arc<Peer>, PacketBuffer, SocketAddr, Option<IpAddr>),
+    Tun(Arc<Peer>, PacketBuffer),
+}
+
+impl Job {
+    fn peer(&self) -> &Arc<Peer> {
+        match self {
+            Job::Socket(peer, _, _, _) | Job::Handshake(peer, _, _, _) | Job::Tun(peer, _) => peer,
+        }
+    }
+}
+
+/// Packets of one peer are always handled by the same worker, so they keep
+/// their order, while different peers are encrypted and decrypted in parallel.
+pub struct WorkerPool {
+    senders: Vec<mpsc::Sender<Job>>,
+}
+
+impl WorkerPool {
+    pub fn new(
+        workers: usize,
+        socket: Arc<dyn Transport>,
+        device: Arc<dyn Device>,
+        endpoints: Arc<Endpoints>,
+    ) -> Result<(Self, Vec<JoinHandle<()>>)> {
+        if workers == 0 {
+            return Err(anyhow!("Workers must be greater than 0"));
+        }
+
+        let mut senders = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            let (sender, receiver) = mpsc::channel(WORKER_QUEUE_DEPTH);
+            senders.push(sender);
+            handles.push(tokio::spawn(run_worker(
+                receiver,
+                socket.clone(),
+                device.clone(),
+                endpoints.clone(),
+            )));
+        }
+
+        Ok((Self { senders }, handles))
+    }
+
+    pub async fn dispatch(&self, job: Job) -> Result<()> {
+        let index = job.peer().worker_index() % self.senders.len();
+        self.senders[index]
+            .send(job)
+            .await
+            .map_err(|_| anyhow!("Worker {index} stopped"))?;
+        Ok(())
+    }
+}
+
+async fn run_worker(
+    mut receiver: mpsc::Receiver<Job>,
+    socket: Arc<dyn Transport>,
+    device: Arc<dyn Device>,
+    endpoints: Arc<Endpoints>,
+) {
+    // Output of up to BATCH_SIZE jobs is sent and written together
+    let mut batch = SendBatch::new();
+    let mut tun_batch = TunBatch::new();
+    while let Some(job) = receiver.recv().await {
+        handle_job(job, &endpoints, &mut batch, &mut tun_batch).await;
+        for _ in 1..BATCH_SIZE {
+            match receiver.try_recv() {
+                Ok(job) => handle_job(job, &endpoints, &mut batch, &mut tun_batch).await,
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = transport::send(&*socket, &mut batch).await {
+            println!("Send to network failed: {e}")
+        }
+        if let Err(e) = device.send(&mut tun_batch).await {
+            println!("Send to tun dev failed: {e}")
+        }
+    }
+}
+
+async fn handle_job(
+    job: Job,
+    endpoints: &Endpoints,
+    batch: &mut SendBatch,
+    tun_batch: &mut TunBatch,
+) {
+    match job {
+        Job::Socket(peer, packet, endpoint, local) => {
+            if let Err(e) = peer
+                .handle_socket_packet(&packet, endpoint, local, batch, tun_batch)
+                .await
+            {
+                println!("Handle socket packet failed: {e}")
+            }
+        }
+        Job::Handshake(peer, packet, endpoint, local) => {
+            match peer
+                .handle_socket_packet(&packet, endpoint, local, batch, tun_batch)
+                .await
+            {
+                // Not before, or anyone could fill the map
+                Ok(true) if !peer.is_removed() => {
+                    endpoints.insert(endpoint, peer);
+                }
+                Ok(_) => {}
+                Err(e) => println!("Handle socket packet failed: {e}"),
+            }
+        }
+        Job::Tun(peer, packet) => {
+            if let Err(e) = peer.handle_tun_packet(&packet, batch).await {
+                println!("Handle tun packet failed: {e}")
+            }
+        }
+    }
+}
+
+pub fn default_workers() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
@@ -0,0 +1,131 @@
+//! The packet path must not allocate once warmed up.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use base64::{engine::general_purpose, Engine};
+use boringtun::{
+    noise::{Tunn, TunnResult},
+    x25519::{PublicKey, StaticSecret},
+};
+use tokio::{net::UdpSocket, runtime::Runtime};
+use wireguard::{
+    buffer::{BufferPool, MAX_PACKET_SIZE},
+    peer::Peer,
+    udp::{OuterSocket, SendBatch},
+};
+
+/// Counts the allocations of the thread running the packet path, not those
+/// of the test harness.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const PACKET_SIZE: usize = 1400;
+const ITERATIONS: usize = 1_000;
+
+/// A peer with an established session, sending to a loopback socket.
+fn established_peer(runtime: &Runtime) -> (Peer, UdpSocket) {
+    let secret_a = StaticSecret::from([1u8; 32]);
+    let secret_b = StaticSecret::from([2u8; 32]);
+    let public_a = PublicKey::from(&secret_a);
+    let public_b = PublicKey::from(&secret_b);
+
+    let mut tunn_a = Tunn::new(secret_a, public_b, None, None, 1, None).unwrap();
+    let mut tunn_b = Tunn::new(secret_b, public_a, None, None, 2, None).unwrap();
+
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    let mut out = vec![0u8; MAX_PACKET_SIZE];
+    let init = match tunn_a.format_handshake_initiation(&mut buf, false) {
+        TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+        other => panic!("Unexpected handshake initiation: {other:?}"),
+    };
+    let response = match tunn_b.decapsulate(None, &init, &mut out) {
+        TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+        other => panic!("Unexpected handshake response: {other:?}"),
+    };
+    match tunn_a.decapsulate(None, &response, &mut out) {
+        TunnResult::WriteToNetwork(_) => {}
+        other => panic!("Unexpected keepalive: {other:?}"),
+    }
+
+    let (send_socket, sink) = runtime.block_on(async {
+        let send_socket = OuterSocket::listen(Some("127.0.0.1".parse().unwrap()), 0, None).unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        (send_socket, sink)
+    });
+    let sink_addr: SocketAddr = sink.local_addr().unwrap();
+
+    let mut peer = Peer::new().unwrap();
+    peer.set_public_key(&general_purpose::STANDARD.encode(public_b.as_bytes()))
+        .unwrap();
+    peer.set_endpoint(&sink_addr.to_string()).unwrap();
+    peer.set_send_socket(Arc::new(send_socket)).unwrap();
+    peer.set_tunn(tunn_a).unwrap();
+    (peer, sink)
+}
+
+/// A minimal IPv4 header followed by zeroes.
+fn ip_packet() -> Vec<u8> {
+    let mut packet = vec![0u8; PACKET_SIZE];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(PACKET_SIZE as u16).to_be_bytes());
+    packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+    packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+    packet
+}
+
+/// Encrypt a packet from the tun device into `batch` and send it.
+async fn tun_to_network(pool: &Arc<BufferPool>, peer: &Peer, packet: &[u8], batch: &mut SendBatch) {
+    let mut buf = pool.get();
+    buf.as_full_mut()[..packet.len()].copy_from_slice(packet);
+    buf.set_len(packet.len());
+    peer.handle_tun_packet(&buf, batch).await.unwrap();
+    peer.flush(batch).await.unwrap();
+}
+
+#[test]
+fn tun_to_network_does_not_allocate() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (peer, _sink) = established_peer(&runtime);
+    let pool = BufferPool::new(16);
+    let packet = ip_packet();
+    let mut batch = SendBatch::new();
+
+    let allocations = runtime.block_on(async {
+        // Warm up the pool, the socket and the list tokio keeps of tasks
+        // that used up their budget
+        for _ in 0..ITERATIONS {
+            tun_to_network(&pool, &peer, &packet, &mut batch).await;
+        }
+        let before = ALLOCATIONS.get();
+        for _ in 0..ITERATIONS {
+            tun_to_network(&pool, &peer, &packet, &mut batch).await;
+        }
+        ALLOCATIONS.get() - before
+    });
+    assert_eq!(allocations, 0);
+}